@@ -1,6 +1,8 @@
 use crate::utl;
 use colored::*;
+use std::error::Error;
 use std::io::Write;
+use std::path::Path;
 
 // note: just a quick direct translation of my C code from the early 90s ;)
 
@@ -13,6 +15,21 @@ pub struct C12nResults {
     // TODO eventually remove some to the above
     y_true: Vec<String>,
     y_pred: Vec<String>,
+
+    /// Cases added with `add_named_case`.
+    cases: Vec<CaseScores>,
+
+    /// How the scores were computed, if recorded (see `set_scoring`).
+    scoring: Option<String>,
+}
+
+struct CaseScores {
+    name: String,
+    class_id: usize,
+    predicted_id: usize,
+    /// 1-based rank of the true class.
+    rank: usize,
+    probs: Vec<f64>,
 }
 
 impl C12nResults {
@@ -30,7 +47,76 @@ impl C12nResults {
             confusion,
             y_true,
             y_pred,
+            cases: Vec::new(),
+            scoring: None,
+        }
+    }
+
+    /// Records how the scores were computed (e.g., the precision),
+    /// as the `scoring` column of `save_cases_csv` and the `scoring`
+    /// field of the classification summary.
+    pub fn set_scoring(&mut self, scoring: &str) {
+        self.scoring = Some(scoring.to_string());
+    }
+
+    /// Like `add_case`, also recording the scores of the case, identified
+    /// by `name` (typically the filename), for `save_cases_csv`.
+    pub fn add_named_case(
+        &mut self,
+        name: &str,
+        class_id: usize,
+        seq_classname: &str,
+        probs: Vec<f64>,
+        show_ranked: bool,
+    ) {
+        let true_prob = probs[class_id];
+        let rank = 1 + probs.iter().filter(|&&p| p > true_prob).count();
+        // (as in `add_case`, the last one in case of ties)
+        let predicted_id = probs
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())
+            .unwrap()
+            .0;
+        self.cases.push(CaseScores {
+            name: name.to_string(),
+            class_id,
+            predicted_id,
+            rank,
+            probs: probs.clone(),
+        });
+        self.add_case(class_id, seq_classname, probs, show_ranked, || {
+            format!("\n{}: '{}'", name, seq_classname)
+        });
+    }
+
+    /// Saves a row per case added with `add_named_case`: name, true class,
+    /// predicted class, rank of the true class, and the score for each model.
+    pub fn save_cases_csv(&self, filename: &Path) -> Result<(), Box<dyn Error>> {
+        let mut wrt = csv::Writer::from_path(filename)?;
+        let mut header: Vec<String> = ["filename", "true", "predicted", "rank"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        if self.scoring.is_some() {
+            header.push("scoring".to_string());
+        }
+        header.extend(self.model_class_names.iter().cloned());
+        wrt.write_record(&header)?;
+        for case in &self.cases {
+            let mut record = vec![
+                case.name.clone(),
+                self.model_class_names[case.class_id].clone(),
+                self.model_class_names[case.predicted_id].clone(),
+                case.rank.to_string(),
+            ];
+            record.extend(self.scoring.iter().cloned());
+            record.extend(case.probs.iter().map(|p| p.to_string()));
+            wrt.write_record(&record)?;
         }
+        wrt.flush()?;
+        println!("{} saved", filename.display());
+        Ok(())
     }
 
     pub fn add_case<F>(
@@ -167,6 +253,7 @@ impl C12nResults {
         let mut summary = Summary {
             accuracy: 0_f32,
             avg_accuracy: 0_f32,
+            scoring: self.scoring.clone(),
         };
 
         for (class_id, class_name) in class_names.iter().enumerate().take(num_models + 1) {
@@ -227,6 +314,8 @@ impl C12nResults {
 struct Summary {
     accuracy: f32,
     avg_accuracy: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scoring: Option<String>,
 }
 
 // TODO instead, generate a c12n CSV as the HMM C code does.
@@ -235,3 +324,31 @@ struct TruePred {
     y_true: Vec<String>,
     y_pred: Vec<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_named_cases() {
+        let names = vec!["A".to_string(), "B".to_string(), "C".to_string()];
+        let mut c12n = C12nResults::new(names);
+        c12n.set_scoring("scaled");
+        // true class ranked first, tied with another, and last
+        c12n.add_named_case("a.seq", 0, "A", vec![-1.0, -2.0, -3.0], false);
+        c12n.add_named_case("b.seq", 1, "B", vec![-1.0, -1.0, -3.0], false);
+        c12n.add_named_case("c.seq", 2, "C", vec![-1.0, -2.0, -3.0], false);
+        let ranks: Vec<usize> = c12n.cases.iter().map(|c| c.rank).collect();
+        assert_eq!(ranks, vec![1, 1, 3]);
+
+        let path = std::env::temp_dir().join("ecoz2_test_named_cases.csv");
+        c12n.save_cases_csv(&path).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines[0], "filename,true,predicted,rank,scoring,A,B,C");
+        assert_eq!(lines[1], "a.seq,A,A,1,scaled,-1,-2,-3");
+        assert_eq!(lines[3], "c.seq,C,A,3,scaled,-1,-2,-3");
+        assert_eq!(lines.len(), 4);
+    }
+}
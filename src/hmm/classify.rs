@@ -0,0 +1,172 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::c12n;
+use crate::sequence;
+
+use super::model::{self, Hmm, Precision};
+
+/// Classification of the given sequences or, if `soft` is given, the given
+/// soft sequences using the `soft` value as the temperature for the observations.
+///
+/// The log probabilities are computed with the given `precision` or, by default,
+/// the one recorded in the models if they all agree, or otherwise `Scaled`.
+///
+/// Results are reported with `C12nResults` under the base name `hmm_<M>`,
+/// and the log probability of each sequence under each model is saved to
+/// `scores_filename` (by default `hmm_<M>_scores.csv`),
+/// with the precision recorded in both.
+pub fn classify(
+    hmm_filenames: Vec<PathBuf>,
+    seq_filenames: Vec<PathBuf>,
+    show_ranked: bool,
+    soft: Option<f64>,
+    precision: Option<Precision>,
+    scores_filename: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    println!("Loading HMM models");
+    let models = hmm_filenames
+        .iter()
+        .map(|n| model::load(n.to_str().unwrap()))
+        .collect::<Result<Vec<Hmm>, _>>()?;
+    if models.is_empty() {
+        return Err("No HMM models given".into());
+    }
+    let codebook_size = models[0].m;
+    for (hmm, filename) in models.iter().zip(&hmm_filenames) {
+        if hmm.m != codebook_size {
+            return Err(format!(
+                "conformity error: {}: codebook size {} (expecting {})",
+                filename.display(),
+                hmm.m,
+                codebook_size
+            )
+            .into());
+        }
+    }
+
+    let recorded = models[0].precision;
+    let precision = precision.unwrap_or(match recorded {
+        Some(p) if models.iter().all(|m| m.precision == recorded) => p,
+        _ => Precision::Scaled,
+    });
+    println!("precision: {}", precision);
+
+    let model_class_names = models.iter().map(|m| m.class_name.clone()).collect();
+    let mut c12n = c12n::C12nResults::new(model_class_names);
+    c12n.set_scoring(&precision.to_string());
+
+    println!("Classifying sequences");
+    for seq_filename in &seq_filenames {
+        let filename = seq_filename.to_str().unwrap();
+        let conformity = |seq_codebook_size: u32| -> Result<(), Box<dyn Error>> {
+            if seq_codebook_size as usize != codebook_size {
+                return Err(format!(
+                    "{}: conformity error: codebook size: {} != {}",
+                    filename, seq_codebook_size, codebook_size
+                )
+                .into());
+            }
+            Ok(())
+        };
+        let (class_name, probs) = match soft {
+            Some(temperature) => {
+                let seq = sequence::load_soft(filename)?;
+                conformity(seq.codebook_size)?;
+                let observations = seq.observations(temperature);
+                let probs: Vec<f64> = models
+                    .iter()
+                    .map(|m| m.log_prob_soft(&observations, precision))
+                    .collect();
+                (seq.class_name, probs)
+            }
+            None => {
+                let seq = sequence::load(filename)?;
+                conformity(seq.codebook_size)?;
+                let probs: Vec<f64> = models
+                    .iter()
+                    .map(|m| m.log_prob_with(&seq.symbols, precision))
+                    .collect();
+                (seq.class_name, probs)
+            }
+        };
+
+        let class_id_opt = models.iter().position(|m| m.class_name == class_name);
+        if let Some(class_id) = class_id_opt {
+            c12n.add_named_case(filename, class_id, &class_name, probs, show_ranked);
+        }
+    }
+
+    println!();
+
+    let class_names: Vec<&String> = models.iter().map(|m| &m.class_name).collect();
+    let out_base_name = format!("hmm_{}", codebook_size);
+    let scores_filename =
+        scores_filename.unwrap_or_else(|| PathBuf::from(format!("{}_scores.csv", out_base_name)));
+    c12n.report_results(class_names, out_base_name);
+    c12n.save_cases_csv(&scores_filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequence::{Sequence, SoftSequence};
+    use ndarray::prelude::*;
+
+    #[test]
+    fn test_classify_conformity() {
+        let dir = std::env::temp_dir();
+        let hmm = Hmm {
+            class_name: "c".to_string(),
+            n: 2,
+            m: 3,
+            pi: array![1.0, 0.0],
+            a: array![[0.9, 0.1], [0.1, 0.9]],
+            b: array![[0.5, 0.3, 0.2], [0.2, 0.3, 0.5]],
+            precision: None,
+        };
+        let hmm_filename = dir.join("ecoz2_test_classify_conformity.hmm");
+        hmm.save(hmm_filename.to_str().unwrap()).unwrap();
+
+        // sequences from a codebook of size 4, with symbol 3
+        let seq_filename = dir.join("ecoz2_test_classify_conformity.seq");
+        Sequence {
+            class_name: "c".to_string(),
+            codebook_size: 4,
+            symbols: vec![0, 3, 1],
+        }
+        .save(seq_filename.to_str().unwrap())
+        .unwrap();
+        let sseq_filename = dir.join("ecoz2_test_classify_conformity.sseq");
+        SoftSequence {
+            class_name: "c".to_string(),
+            codebook_size: 4,
+            k: 2,
+            frames: vec![vec![(3, 1.0), (0, 2.0)]],
+        }
+        .save(sseq_filename.to_str().unwrap())
+        .unwrap();
+
+        let hard = classify(
+            vec![hmm_filename.clone()],
+            vec![seq_filename.clone()],
+            false,
+            None,
+            None,
+            None,
+        );
+        let soft = classify(
+            vec![hmm_filename.clone()],
+            vec![sseq_filename.clone()],
+            false,
+            Some(1.0),
+            None,
+            None,
+        );
+        for filename in [hmm_filename, seq_filename, sseq_filename] {
+            std::fs::remove_file(filename).unwrap();
+        }
+        assert!(hard.unwrap_err().to_string().contains("conformity error"));
+        assert!(soft.unwrap_err().to_string().contains("conformity error"));
+    }
+}
@@ -0,0 +1,232 @@
+use std::error::Error;
+use std::thread;
+
+use ndarray::prelude::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::sequence::generate::Length;
+
+use super::model::Hmm;
+
+/// Symmetrized Juang-Rabiner distances between all pairs of the given models:
+///
+///   `D(λi, λj) = (log P(O_i | λi) - log P(O_i | λj)) / T_i`
+///
+/// with `O_i` sequences generated from `λi` (`num_sequences` of them,
+/// total length `T_i`), and `Ds(λi, λj) = (D(λi, λj) + D(λj, λi)) / 2`.
+/// The distance is infinite if `λj` gives zero probability to the sequences.
+pub fn distances(models: &[Hmm], num_sequences: usize, length: &Length, seed: u64) -> Array2<f64> {
+    let k = models.len();
+    // per model i: average log probability per symbol of O_i under each model
+    let log_probs: Vec<Vec<f64>> = thread::scope(|s| {
+        let handles: Vec<_> = models
+            .iter()
+            .enumerate()
+            .map(|(i, model)| {
+                s.spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(seed + i as u64);
+                    let sequences: Vec<Vec<u16>> = (0..num_sequences)
+                        .map(|_| model.generate(&mut rng, length))
+                        .collect();
+                    let total_length: usize = sequences.iter().map(|s| s.len()).sum();
+                    models
+                        .iter()
+                        .map(|other| {
+                            let sum: f64 = sequences.iter().map(|s| other.log_prob(s)).sum();
+                            sum / total_length as f64
+                        })
+                        .collect()
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    Array2::from_shape_fn((k, k), |(i, j)| {
+        if i == j {
+            return 0.0;
+        }
+        let d_ij = log_probs[i][i] - log_probs[i][j];
+        let d_ji = log_probs[j][j] - log_probs[j][i];
+        (d_ij + d_ji) / 2.0
+    })
+}
+
+/// Saves the distance matrix with the model names as header and first column.
+pub fn save_csv(
+    names: &[String],
+    distances: &Array2<f64>,
+    filename: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut wrt = csv::Writer::from_path(filename)?;
+    let mut header = vec!["model".to_string()];
+    header.extend(names.iter().cloned());
+    wrt.write_record(&header)?;
+    for (name, row) in names.iter().zip(distances.axis_iter(Axis(0))) {
+        let mut record = vec![name.clone()];
+        record.extend(row.iter().map(|d| d.to_string()));
+        wrt.write_record(&record)?;
+    }
+    wrt.flush()?;
+    Ok(())
+}
+
+/// Copy of the distances with the infinite ones (a model giving zero probability
+/// to the sequences of another) replaced by twice the largest finite distance
+/// (1 if none), so the clustering remains defined.
+/// Also returns the number of pairs replaced.
+pub fn clamp_infinite(distances: &Array2<f64>) -> (Array2<f64>, usize) {
+    let max = distances
+        .iter()
+        .copied()
+        .filter(|d| d.is_finite())
+        .fold(0.0, f64::max);
+    let replacement = if max > 0.0 { 2.0 * max } else { 1.0 };
+    let mut replaced = 0;
+    let clamped = distances.mapv(|d| {
+        if d.is_finite() {
+            d
+        } else {
+            replaced += 1;
+            replacement
+        }
+    });
+    // (each pair appears twice in the symmetric matrix)
+    (clamped, replaced / 2)
+}
+
+/// Node of the clustering tree.
+enum Node {
+    Leaf(usize),
+    /// Children with their branch lengths.
+    Internal(Vec<(Node, f64)>),
+}
+
+struct Cluster {
+    node: Node,
+    size: usize,
+    /// Half the merge distance (so, an ultrametric tree).
+    height: f64,
+}
+
+/// Hierarchical clustering (UPGMA, i.e., average linkage) of the given
+/// distance matrix, as a Newick tree with branch lengths.
+/// Infinite distances are first replaced as in `clamp_infinite`.
+pub fn newick(names: &[String], distances: &Array2<f64>) -> String {
+    let mut clusters: Vec<Cluster> = (0..names.len())
+        .map(|i| Cluster {
+            node: Node::Leaf(i),
+            size: 1,
+            height: 0.0,
+        })
+        .collect();
+    let (mut dist, _) = clamp_infinite(distances);
+
+    while clusters.len() > 1 {
+        let k = clusters.len();
+        let (mut a, mut b) = (0, 1);
+        for i in 0..k {
+            for j in i + 1..k {
+                if dist[[i, j]] < dist[[a, b]] {
+                    (a, b) = (i, j);
+                }
+            }
+        }
+        let height = dist[[a, b]] / 2.0;
+
+        // the merged cluster takes the place of `a`
+        let (size_a, size_b) = (clusters[a].size as f64, clusters[b].size as f64);
+        for i in 0..k {
+            let d = (size_a * dist[[a, i]] + size_b * dist[[b, i]]) / (size_a + size_b);
+            dist[[a, i]] = d;
+            dist[[i, a]] = d;
+        }
+        dist[[a, a]] = 0.0;
+        let keep: Vec<usize> = (0..k).filter(|&i| i != b).collect();
+        dist = dist.select(Axis(0), &keep).select(Axis(1), &keep);
+
+        let cluster_b = clusters.remove(b);
+        let cluster_a = clusters.remove(a);
+        let merged = Cluster {
+            size: cluster_a.size + cluster_b.size,
+            node: Node::Internal(vec![
+                (cluster_a.node, height - cluster_a.height),
+                (cluster_b.node, height - cluster_b.height),
+            ]),
+            height,
+        };
+        clusters.insert(a, merged);
+    }
+
+    match clusters.pop() {
+        Some(root) => format!("{};", to_newick(&root.node, names)),
+        None => ";".to_string(),
+    }
+}
+
+fn to_newick(node: &Node, names: &[String]) -> String {
+    match node {
+        Node::Leaf(i) => quote(&names[*i]),
+        Node::Internal(children) => {
+            let children: Vec<String> = children
+                .iter()
+                .map(|(child, length)| format!("{}:{:.6}", to_newick(child, names), length))
+                .collect();
+            format!("({})", children.join(","))
+        }
+    }
+}
+
+/// Newick label, quoted if needed.
+fn quote(name: &str) -> String {
+    if name
+        .chars()
+        .any(|c| c.is_whitespace() || "(),:;'[]".contains(c))
+    {
+        format!("'{}'", name.replace('\'', "''"))
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_newick() {
+        let names: Vec<String> = ["A", "B", "C d"].iter().map(|s| s.to_string()).collect();
+        let distances = array![[0.0, 2.0, 6.0], [2.0, 0.0, 8.0], [6.0, 8.0, 0.0]];
+        assert_eq!(
+            newick(&names, &distances),
+            "((A:1.000000,B:1.000000):2.500000,'C d':3.500000);"
+        );
+
+        let inf = f64::INFINITY;
+        let distances = array![[0.0, 2.0, inf], [2.0, 0.0, inf], [inf, inf, 0.0]];
+        assert_eq!(clamp_infinite(&distances).1, 2);
+        assert_eq!(
+            newick(&names, &distances),
+            "((A:1.000000,B:1.000000):1.000000,'C d':2.000000);"
+        );
+    }
+
+    #[test]
+    fn test_distances() {
+        let hmm = |b0: f64| Hmm {
+            class_name: format!("{}", b0),
+            n: 2,
+            m: 2,
+            pi: array![1.0, 0.0],
+            a: array![[0.9, 0.1], [0.1, 0.9]],
+            b: array![[b0, 1.0 - b0], [1.0 - b0, b0]],
+            precision: None,
+        };
+        let models = vec![hmm(0.9), hmm(0.85), hmm(0.3)];
+        let d = distances(&models, 10, &Length::Fixed(100), 1);
+        assert_eq!(d.diag(), array![0.0, 0.0, 0.0]);
+        assert_eq!(d, d.t());
+        assert!(0.0 < d[[0, 1]] && d[[0, 1]] < d[[0, 2]]);
+    }
+}
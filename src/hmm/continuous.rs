@@ -0,0 +1,600 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::thread;
+
+use ndarray::prelude::*;
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
+
+use crate::c12n;
+use crate::prd;
+
+use super::curve::LearningCurve;
+use super::learn::{init_transitions, train_em, ExpectedCounts, HmmLearnParams};
+use super::model::{backward_scaled, forward_scaled, Precision};
+
+/// Extension of the continuous HMM files.
+pub const EXTENSION: &str = ".chmm";
+
+/// Variances are kept above this fraction of the global variance
+/// of the training vectors in each dimension.
+const VARIANCE_FLOOR: f64 = 1e-3;
+
+/// Lloyd iterations for the initial mixtures.
+const KMEANS_ITERATIONS: usize = 10;
+
+/// A continuous-density HMM whose emissions are mixtures of Gaussians
+/// with diagonal covariances, on cepstral vectors.
+/// Saved in CBOR format, as with the MM and NB models.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct GmmHmm {
+    pub class_name: String,
+
+    /// The cepstrum is computed up to this order; coefficients `1 ..`
+    /// (so, without the gain term) make up the feature vectors.
+    pub cepstrum_order: usize,
+
+    /// Number of states.
+    pub n: usize,
+
+    /// Number of mixture components per state.
+    pub num_mixtures: usize,
+
+    pub pi: Array1<f64>,
+    pub a: Array2<f64>,
+
+    /// Mixture weights (N x K).
+    pub weights: Array2<f64>,
+
+    /// Means (N x K x D).
+    pub means: Array3<f64>,
+
+    /// Diagonal variances (N x K x D).
+    pub variances: Array3<f64>,
+}
+
+/// Cepstral feature vectors of the given predictor.
+pub fn features(
+    predictor: &prd::Predictor,
+    cepstrum_order: usize,
+) -> Result<Vec<Vec<f64>>, Box<dyn Error>> {
+    if predictor.prediction_order >= cepstrum_order {
+        return Err(format!(
+            "cepstrum order ({}) must be greater than the prediction order ({})",
+            cepstrum_order, predictor.prediction_order
+        )
+        .into());
+    }
+    Ok(predictor
+        .get_cepstrum(cepstrum_order)
+        .into_iter()
+        .map(|c| c[1..].to_vec())
+        .collect())
+}
+
+impl GmmHmm {
+    fn dim(&self) -> usize {
+        self.means.dim().2
+    }
+
+    pub fn show(&self) {
+        println!(
+            "class_name='{}', N={}, K={}, D={} (cepstrum order {})",
+            self.class_name,
+            self.n,
+            self.num_mixtures,
+            self.dim(),
+            self.cepstrum_order
+        );
+        println!("pi = {}", self.pi);
+        println!("A =");
+        for (i, a_row) in self.a.axis_iter(Axis(0)).enumerate() {
+            println!(" [{}]: {}", i, a_row);
+        }
+        println!("mixture weights =");
+        for (i, w_row) in self.weights.axis_iter(Axis(0)).enumerate() {
+            println!(" [{}]: {}", i, w_row);
+        }
+    }
+
+    /// `log(w_jk) + log N(x; mu_jk, var_jk)` for each state and component (N x K).
+    fn log_densities(&self, x: &[f64]) -> Array2<f64> {
+        let d = self.dim();
+        let log_2pi = (2.0 * std::f64::consts::PI).ln();
+        Array2::from_shape_fn((self.n, self.num_mixtures), |(j, k)| {
+            let mut sum = 0.0;
+            for (i, &xi) in x.iter().enumerate().take(d) {
+                let var = self.variances[[j, k, i]];
+                let diff = xi - self.means[[j, k, i]];
+                sum += var.ln() + diff * diff / var;
+            }
+            self.weights[[j, k]].ln() - 0.5 * (d as f64 * log_2pi + sum)
+        })
+    }
+
+    /// Emission probabilities of each frame, scaled by `exp(-m_t)` to avoid
+    /// underflow (T x N), the corresponding `m_t`, and the component
+    /// log densities (T x N x K).
+    fn emissions(&self, frames: &[Vec<f64>]) -> (Array2<f64>, Vec<f64>, Array3<f64>) {
+        let t_len = frames.len();
+        let mut emissions = Array2::zeros((t_len, self.n));
+        let mut log_max = Vec::with_capacity(t_len);
+        let mut log_densities = Array3::zeros((t_len, self.n, self.num_mixtures));
+        for (t, x) in frames.iter().enumerate() {
+            let ld = self.log_densities(x);
+            let m = ld.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            for j in 0..self.n {
+                emissions[[t, j]] = ld.row(j).iter().map(|&l| (l - m).exp()).sum();
+            }
+            log_densities.slice_mut(s![t, .., ..]).assign(&ld);
+            log_max.push(m);
+        }
+        (emissions, log_max, log_densities)
+    }
+
+    /// Log probability (natural log) of the given feature vectors,
+    /// `-inf` if zero.
+    pub fn log_prob(&self, frames: &[Vec<f64>]) -> f64 {
+        if frames.is_empty() {
+            return f64::NEG_INFINITY;
+        }
+        let (emissions, log_max, _) = self.emissions(frames);
+        match forward_scaled(&self.pi, &self.a, &emissions) {
+            Some((_, scales)) => {
+                log_max.iter().sum::<f64>() - scales.iter().map(|c| c.ln()).sum::<f64>()
+            }
+            None => f64::NEG_INFINITY,
+        }
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        crate::utl::save_ser(self, filename)
+    }
+}
+
+pub fn load(filename: &str) -> Result<GmmHmm, Box<dyn Error>> {
+    let f = File::open(filename)?;
+    let br = BufReader::new(f);
+    let model = serde_cbor::from_reader(br)?;
+    Ok(model)
+}
+
+/// Expected counts accumulated over a set of training sequences.
+struct Counts {
+    pi: Array1<f64>,
+    a_num: Array2<f64>,
+    a_den: Array1<f64>,
+    /// Occupancy of each component (N x K).
+    occupancy: Array2<f64>,
+    /// Weighted sums of x and x^2 for each component (N x K x D).
+    sum_x: Array3<f64>,
+    sum_x2: Array3<f64>,
+    log_prob: f64,
+    zero_prob: usize,
+}
+
+impl Counts {
+    fn new(n: usize, k: usize, d: usize) -> Counts {
+        Counts {
+            pi: Array1::zeros(n),
+            a_num: Array2::zeros((n, n)),
+            a_den: Array1::zeros(n),
+            occupancy: Array2::zeros((n, k)),
+            sum_x: Array3::zeros((n, k, d)),
+            sum_x2: Array3::zeros((n, k, d)),
+            log_prob: 0.0,
+            zero_prob: 0,
+        }
+    }
+
+    fn add(&mut self, other: &Counts) {
+        self.pi += &other.pi;
+        self.a_num += &other.a_num;
+        self.a_den += &other.a_den;
+        self.occupancy += &other.occupancy;
+        self.sum_x += &other.sum_x;
+        self.sum_x2 += &other.sum_x2;
+        self.log_prob += other.log_prob;
+        self.zero_prob += other.zero_prob;
+    }
+
+    fn accumulate(&mut self, model: &GmmHmm, frames: &[Vec<f64>]) {
+        let (emissions, log_max, log_densities) = model.emissions(frames);
+        let (alpha, scales) = match forward_scaled(&model.pi, &model.a, &emissions) {
+            Some(res) => res,
+            None => {
+                self.zero_prob += 1;
+                return;
+            }
+        };
+        let beta = backward_scaled(&model.a, &emissions, &scales);
+        let (n, t_len) = (model.n, frames.len());
+
+        self.log_prob += log_max.iter().sum::<f64>() - scales.iter().map(|c| c.ln()).sum::<f64>();
+
+        for (t, x) in frames.iter().enumerate() {
+            for j in 0..n {
+                let gamma = alpha[[t, j]] * beta[[t, j]] / scales[t];
+                if t == 0 {
+                    self.pi[j] += gamma;
+                }
+                if t + 1 < t_len {
+                    self.a_den[j] += gamma;
+                    for jj in 0..n {
+                        self.a_num[[j, jj]] += alpha[[t, j]]
+                            * model.a[[j, jj]]
+                            * emissions[[t + 1, jj]]
+                            * beta[[t + 1, jj]];
+                    }
+                }
+                if emissions[[t, j]] <= 0.0 {
+                    continue;
+                }
+                for k in 0..model.num_mixtures {
+                    let share = (log_densities[[t, j, k]] - log_max[t]).exp() / emissions[[t, j]];
+                    let g = gamma * share;
+                    self.occupancy[[j, k]] += g;
+                    for (i, &xi) in x.iter().enumerate() {
+                        self.sum_x[[j, k, i]] += g * xi;
+                        self.sum_x2[[j, k, i]] += g * xi * xi;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Baum-Welch training of a continuous-density HMM with `num_mixtures`
+/// diagonal Gaussians per state on the given feature vector sequences.
+///
+/// Transitions are initialized as with the discrete HMMs (`params.type_`,
+/// `params.mask`); the mixtures, by k-means on a uniform segmentation of the
+/// sequences into the states. `params.epsilon` is not used.
+/// Returns the model along with its final total log probability of the
+/// training sequences and the learning curve.
+pub fn learn(
+    class_name: String,
+    cepstrum_order: usize,
+    num_mixtures: usize,
+    sequences: &[Vec<Vec<f64>>],
+    params: &HmmLearnParams,
+) -> Result<(GmmHmm, f64, LearningCurve), Box<dyn Error>> {
+    if num_mixtures == 0 {
+        return Err("Number of mixtures must be positive".into());
+    }
+    let sequences: Vec<&[Vec<f64>]> = sequences
+        .iter()
+        .filter(|s| !s.is_empty())
+        .map(|s| s.as_slice())
+        .collect();
+    if sequences.is_empty() {
+        return Err("No training vectors".into());
+    }
+
+    let mut rng = StdRng::seed_from_u64(params.seed);
+    let (pi, a) = init_transitions(params, &mut rng)?;
+    let mut model = init_mixtures(
+        class_name,
+        cepstrum_order,
+        num_mixtures,
+        pi,
+        a,
+        &sequences,
+        &mut rng,
+    );
+    let var_floor = variance_floor(&sequences);
+
+    let mut curve = LearningCurve::default();
+    let log_prob = train_em(
+        &mut model,
+        sequences.len(),
+        params,
+        &mut curve,
+        |model| expected_counts(model, &sequences, params.parallel),
+        |model, counts| {
+            reestimate(model, counts, &var_floor);
+            if let Some(mask) = &params.mask {
+                mask.apply(&mut model.a);
+            }
+        },
+        |_, _, _| Ok(()),
+    )?;
+    Ok((model, log_prob, curve))
+}
+
+impl ExpectedCounts for Counts {
+    fn log_prob(&self) -> f64 {
+        self.log_prob
+    }
+
+    fn zero_prob(&self) -> usize {
+        self.zero_prob
+    }
+}
+
+fn expected_counts(model: &GmmHmm, sequences: &[&[Vec<f64>]], parallel: bool) -> Counts {
+    let cores = if parallel { num_cpus::get() } else { 1 };
+    let chunk_size = sequences.len().div_ceil(cores).max(1);
+    let (n, k, d) = model.means.dim();
+
+    thread::scope(|s| {
+        let handles: Vec<_> = sequences
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    let mut counts = Counts::new(n, k, d);
+                    chunk.iter().for_each(|seq| counts.accumulate(model, seq));
+                    counts
+                })
+            })
+            .collect();
+
+        let mut counts = Counts::new(n, k, d);
+        for handle in handles {
+            counts.add(&handle.join().unwrap());
+        }
+        counts
+    })
+}
+
+fn reestimate(model: &mut GmmHmm, counts: &Counts, var_floor: &[f64]) {
+    let pi_sum = counts.pi.sum();
+    if pi_sum > 0.0 {
+        model.pi = &counts.pi / pi_sum;
+    }
+    let (n, num_mixtures, d) = model.means.dim();
+    for j in 0..n {
+        // states or components never visited keep their previous values
+        if counts.a_den[j] > 0.0 {
+            let row = counts.a_num.row(j).to_owned() / counts.a_den[j];
+            model.a.row_mut(j).assign(&row);
+        }
+        let state_occupancy = counts.occupancy.row(j).sum();
+        if state_occupancy <= 0.0 {
+            continue;
+        }
+        for k in 0..num_mixtures {
+            let occupancy = counts.occupancy[[j, k]];
+            model.weights[[j, k]] = (occupancy / state_occupancy).max(f64::MIN_POSITIVE);
+            if occupancy <= 0.0 {
+                continue;
+            }
+            for (i, &floor) in var_floor.iter().enumerate().take(d) {
+                let mean = counts.sum_x[[j, k, i]] / occupancy;
+                let var = counts.sum_x2[[j, k, i]] / occupancy - mean * mean;
+                model.means[[j, k, i]] = mean;
+                model.variances[[j, k, i]] = var.max(floor);
+            }
+        }
+        let sum = model.weights.row(j).sum();
+        model.weights.row_mut(j).mapv_inplace(|w| w / sum);
+    }
+}
+
+fn variance_floor(sequences: &[&[Vec<f64>]]) -> Vec<f64> {
+    let frames: Vec<&Vec<f64>> = sequences.iter().flat_map(|s| s.iter()).collect();
+    let (_, var) = mean_variance(&frames);
+    var.iter()
+        .map(|v| (v * VARIANCE_FLOOR).max(1e-12))
+        .collect()
+}
+
+fn mean_variance(frames: &[&Vec<f64>]) -> (Vec<f64>, Vec<f64>) {
+    let d = frames[0].len();
+    let count = frames.len() as f64;
+    let mut mean = vec![0f64; d];
+    for x in frames {
+        for (m, xi) in mean.iter_mut().zip(x.iter()) {
+            *m += xi / count;
+        }
+    }
+    let mut var = vec![0f64; d];
+    for x in frames {
+        for ((v, xi), m) in var.iter_mut().zip(x.iter()).zip(&mean) {
+            *v += (xi - m) * (xi - m) / count;
+        }
+    }
+    (mean, var)
+}
+
+/// Mixtures obtained by k-means on the frames assigned to each state
+/// by a uniform segmentation of each sequence.
+fn init_mixtures(
+    class_name: String,
+    cepstrum_order: usize,
+    num_mixtures: usize,
+    pi: Array1<f64>,
+    a: Array2<f64>,
+    sequences: &[&[Vec<f64>]],
+    rng: &mut StdRng,
+) -> GmmHmm {
+    let n = a.nrows();
+    let d = sequences[0][0].len();
+    let all: Vec<&Vec<f64>> = sequences.iter().flat_map(|s| s.iter()).collect();
+    let var_floor = variance_floor(sequences);
+
+    let mut state_frames: Vec<Vec<&Vec<f64>>> = vec![Vec::new(); n];
+    for seq in sequences {
+        for (t, x) in seq.iter().enumerate() {
+            state_frames[t * n / seq.len()].push(x);
+        }
+    }
+
+    let mut weights = Array2::zeros((n, num_mixtures));
+    let mut means = Array3::zeros((n, num_mixtures, d));
+    let mut variances = Array3::zeros((n, num_mixtures, d));
+    for (j, frames) in state_frames.iter().enumerate() {
+        // states without frames (sequences shorter than N) take all of them
+        let frames = if frames.is_empty() { &all } else { frames };
+        let clusters = kmeans(frames, num_mixtures, rng);
+        for (k, cluster) in clusters.iter().enumerate() {
+            let cluster = if cluster.is_empty() { frames } else { cluster };
+            let (mean, var) = mean_variance(cluster);
+            weights[[j, k]] = cluster.len().max(1) as f64;
+            means.slice_mut(s![j, k, ..]).assign(&Array1::from(mean));
+            for (i, (v, floor)) in var.iter().zip(&var_floor).enumerate() {
+                variances[[j, k, i]] = v.max(*floor);
+            }
+        }
+        let sum = weights.row(j).sum();
+        weights.row_mut(j).mapv_inplace(|w| w / sum);
+    }
+
+    GmmHmm {
+        class_name,
+        cepstrum_order,
+        n,
+        num_mixtures,
+        pi,
+        a,
+        weights,
+        means,
+        variances,
+    }
+}
+
+/// Plain k-means (euclidean) with centroids initialized from random frames.
+fn kmeans<'a>(frames: &[&'a Vec<f64>], k: usize, rng: &mut StdRng) -> Vec<Vec<&'a Vec<f64>>> {
+    let mut centroids: Vec<Vec<f64>> = (0..k)
+        .map(|_| frames[rng.random_range(0..frames.len())].clone())
+        .collect();
+    let mut clusters: Vec<Vec<&Vec<f64>>> = vec![Vec::new(); k];
+    for _ in 0..KMEANS_ITERATIONS {
+        clusters = vec![Vec::new(); k];
+        for &x in frames {
+            let nearest = (0..k)
+                .map(|c| {
+                    let d: f64 = x
+                        .iter()
+                        .zip(&centroids[c])
+                        .map(|(a, b)| (a - b) * (a - b))
+                        .sum();
+                    (c, d)
+                })
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .unwrap()
+                .0;
+            clusters[nearest].push(x);
+        }
+        for (centroid, cluster) in centroids.iter_mut().zip(&clusters) {
+            if !cluster.is_empty() {
+                *centroid = mean_variance(cluster).0;
+            }
+        }
+    }
+    clusters
+}
+
+/// Classification of predictor files with continuous HMMs (no codebook).
+///
+/// Each predictor file is scored by its log probability under each model.
+/// The class of a predictor file is that of the predictor or, if not set,
+/// the name of the directory containing the file.
+/// Results are reported with `C12nResults` under the base name `chmm_N<N>_K<K>`,
+/// with the (always scaled) precision recorded.
+pub fn classify(
+    model_filenames: Vec<PathBuf>,
+    prd_filenames: Vec<PathBuf>,
+    show_ranked: bool,
+) -> Result<(), Box<dyn Error>> {
+    println!("Loading models");
+    let models = model_filenames
+        .iter()
+        .map(|n| load(n.to_str().unwrap()))
+        .collect::<Result<Vec<GmmHmm>, _>>()?;
+
+    let cepstrum_order = models[0].cepstrum_order;
+    for (model, filename) in models.iter().zip(&model_filenames) {
+        if model.cepstrum_order != cepstrum_order {
+            return Err(format!(
+                "conformity error: {}: cepstrum order {} (expecting {})",
+                filename.display(),
+                model.cepstrum_order,
+                cepstrum_order
+            )
+            .into());
+        }
+    }
+
+    let model_class_names = models.iter().map(|m| m.class_name.clone()).collect();
+    let mut c12n = c12n::C12nResults::new(model_class_names);
+    c12n.set_scoring(&Precision::Scaled.to_string());
+
+    println!("Classifying predictors");
+    for prd_filename in &prd_filenames {
+        let filename = prd_filename.to_str().unwrap();
+        let predictor = prd::load(filename)?;
+        if predictor.vectors.is_empty() {
+            continue;
+        }
+        let class_name = predictor.resolved_class_name(prd_filename);
+
+        let class_id_opt = models.iter().position(|m| m.class_name == class_name);
+        if let Some(class_id) = class_id_opt {
+            let frames = features(&predictor, cepstrum_order)?;
+            let probs: Vec<f64> = models.iter().map(|m| m.log_prob(&frames)).collect();
+            c12n.add_case(class_id, &class_name, probs, show_ranked, || {
+                format!("\n{}: '{}'", filename, class_name)
+            });
+        }
+    }
+
+    println!();
+
+    let class_names: Vec<&String> = models.iter().map(|m| &m.class_name).collect();
+    let out_base_name = format!("chmm_N{}_K{}", models[0].n, models[0].num_mixtures);
+    c12n.report_results(class_names, out_base_name);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> HmmLearnParams {
+        HmmLearnParams {
+            num_states: 2,
+            type_: 2,
+            epsilon: 0.0,
+            val_auto: 0.5,
+            max_iterations: 20,
+            seed: 3,
+            parallel: false,
+            verbose: false,
+            precision: Precision::Scaled,
+            mask: None,
+        }
+    }
+
+    #[test]
+    fn test_learn() {
+        // two regimes around (0, 0) and (5, 5)
+        let mut rng = StdRng::seed_from_u64(7);
+        let sequences: Vec<Vec<Vec<f64>>> = (0..10)
+            .map(|_| {
+                (0..30)
+                    .map(|t| {
+                        let c = if t < 15 { 0.0 } else { 5.0 };
+                        vec![c + rng.random::<f64>() - 0.5, c + rng.random::<f64>() - 0.5]
+                    })
+                    .collect()
+            })
+            .collect();
+        let (model, log_prob, curve) = learn("c".to_string(), 3, 2, &sequences, &params()).unwrap();
+
+        assert!(curve.points.len() > 1);
+        assert!(log_prob >= curve.points[0].log_prob);
+        assert!(model.means[[0, 0, 0]].abs() < 1.0 && model.means[[0, 1, 0]].abs() < 1.0);
+        assert!((model.means[[1, 0, 0]] - 5.0).abs() < 1.0);
+
+        let log_prob_sum: f64 = sequences.iter().map(|s| model.log_prob(s)).sum();
+        assert!((log_prob_sum - log_prob).abs() < 1e-6 * log_prob.abs().max(1.0));
+
+        // reversed sequences are much less likely under this left-right model
+        let reversed: Vec<Vec<f64>> = sequences[0].iter().rev().cloned().collect();
+        assert!(model.log_prob(&reversed) < model.log_prob(&sequences[0]));
+    }
+}
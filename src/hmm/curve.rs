@@ -0,0 +1,152 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::utl;
+
+/// Values at one iteration of the training.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct CurvePoint {
+    pub iteration: i32,
+
+    /// Total log probability of the training sequences (natural log).
+    pub log_prob: f64,
+
+    /// Change of `log_prob` with respect to the previous iteration (none for the first one).
+    pub delta: Option<f64>,
+
+    /// Seconds since the start of the training (including resumed runs).
+    pub elapsed_secs: f64,
+}
+
+/// Learning curve of an HMM training, saved as `<prefix>.json` and `<prefix>.csv`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+pub struct LearningCurve {
+    pub points: Vec<CurvePoint>,
+}
+
+impl LearningCurve {
+    pub fn save(&self, prefix: &str) -> Result<(), Box<dyn Error>> {
+        utl::save_json(self, &format!("{}.json", prefix))?;
+
+        let header: Vec<String> = ["iteration", "log_prob", "delta", "elapsed_secs"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let rows: Vec<Vec<f64>> = self
+            .points
+            .iter()
+            .map(|p| {
+                vec![
+                    p.iteration as f64,
+                    p.log_prob,
+                    p.delta.unwrap_or(f64::NAN),
+                    p.elapsed_secs,
+                ]
+            })
+            .collect();
+        utl::save_csv(&header, &rows, &PathBuf::from(format!("{}.csv", prefix)))
+    }
+
+    /// Curve from the values reported by the C implementation: a point for each
+    /// `LOG_PROB_VARIABLE` record, in the order reported.
+    pub fn from_callback_records(records: &[CallbackRecord]) -> LearningCurve {
+        let mut points: Vec<CurvePoint> = Vec::new();
+        for r in records.iter().filter(|r| r.variable == LOG_PROB_VARIABLE) {
+            let delta = points
+                .last()
+                .map(|prev| r.value - prev.log_prob)
+                .filter(|d| d.is_finite());
+            points.push(CurvePoint {
+                iteration: points.len() as i32,
+                log_prob: r.value,
+                delta,
+                elapsed_secs: r.elapsed_secs,
+            });
+        }
+        LearningCurve { points }
+    }
+
+    /// Loads `<prefix>.json`, or returns an empty curve if it does not exist.
+    pub fn load(prefix: &str) -> Result<LearningCurve, Box<dyn Error>> {
+        let filename = format!("{}.json", prefix);
+        if !Path::new(&filename).exists() {
+            return Ok(LearningCurve::default());
+        }
+        let br = BufReader::new(File::open(&filename)?);
+        Ok(serde_json::from_reader(br)?)
+    }
+}
+
+/// Variable with which the C implementation reports, at each iteration,
+/// the total log probability of the training sequences.
+pub const LOG_PROB_VARIABLE: &str = "P";
+
+/// Value reported by the C implementation via its callback.
+#[derive(Debug)]
+pub struct CallbackRecord {
+    pub variable: String,
+    pub value: f64,
+    pub elapsed_secs: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let curve = LearningCurve {
+            points: vec![CurvePoint {
+                iteration: 0,
+                log_prob: -123.5,
+                delta: None,
+                elapsed_secs: 0.25,
+            }],
+        };
+        let path = std::env::temp_dir().join("ecoz2_test_curve");
+        let prefix = path.to_str().unwrap();
+        curve.save(prefix).unwrap();
+        let loaded = LearningCurve::load(prefix).unwrap();
+        std::fs::remove_file(format!("{}.json", prefix)).unwrap();
+        std::fs::remove_file(format!("{}.csv", prefix)).unwrap();
+
+        assert_eq!(loaded.points.len(), 1);
+        assert_eq!(loaded.points[0].log_prob, -123.5);
+        assert_eq!(loaded.points, curve.points);
+    }
+
+    #[test]
+    fn test_from_callback_records() {
+        let record = |variable: &str, value: f64, elapsed_secs: f64| CallbackRecord {
+            variable: variable.to_string(),
+            value,
+            elapsed_secs,
+        };
+        let records = vec![
+            record("EPSILON", 1e-5, 0.0),
+            record(LOG_PROB_VARIABLE, -300.0, 0.5),
+            record("r", 1.0, 0.5),
+            record(LOG_PROB_VARIABLE, -250.0, 1.0),
+        ];
+        let curve = LearningCurve::from_callback_records(&records);
+        assert_eq!(
+            curve.points,
+            vec![
+                CurvePoint {
+                    iteration: 0,
+                    log_prob: -300.0,
+                    delta: None,
+                    elapsed_secs: 0.5,
+                },
+                CurvePoint {
+                    iteration: 1,
+                    log_prob: -250.0,
+                    delta: Some(50.0),
+                    elapsed_secs: 1.0,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+use ndarray::prelude::*;
+
+use crate::sequence;
+use crate::utl;
+
+use super::model::Hmm;
+
+/// Writes the state posteriors `P(q_t = i | O, λ)` of each sequence along
+/// with the per-frame entropy (in bits) of that distribution.
+///
+/// For each sequence, with `<dir>` being `<out_dir>/<class>`:
+/// - CSV: `<dir>/<name>_gamma.csv` with columns `s0, .., s<N-1>, entropy`;
+/// - NPY: `<dir>/<name>_gamma.npy` (T x N) and `<dir>/<name>_entropy.npy` (T x 1).
+///
+/// A summary with the mean and max entropy, and the number of frames with
+/// entropy above `uncertain_threshold` bits, is printed for each sequence.
+pub fn decode(
+    hmm: &Hmm,
+    seq_filenames: &[PathBuf],
+    out_dir: &str,
+    npy: bool,
+    uncertain_threshold: f64,
+) -> Result<(), Box<dyn Error>> {
+    println!(
+        "\n{:>7} {:>12} {:>12} {:>10}  sequence",
+        "T", "mean_H", "max_H", "uncertain"
+    );
+    for seq_filename in seq_filenames {
+        let filename = seq_filename.to_str().unwrap();
+        let seq = sequence::load(filename)?;
+        if seq.codebook_size as usize != hmm.m {
+            return Err(format!(
+                "{}: conformity error: codebook size: {} != {}",
+                filename, seq.codebook_size, hmm.m
+            )
+            .into());
+        }
+        if seq.symbols.is_empty() {
+            println!("{:>7} {:>12}  {}", 0, "(empty)", filename);
+            continue;
+        }
+        let gamma = match hmm.posteriors(&seq.symbols) {
+            Some(gamma) => gamma,
+            None => {
+                println!(
+                    "{:>7} {:>12}  {}",
+                    seq.symbols.len(),
+                    "(zero prob)",
+                    filename
+                );
+                continue;
+            }
+        };
+        let entropy: Vec<f64> = gamma.axis_iter(Axis(0)).map(entropy_bits).collect();
+
+        let dir = format!("{}/{}", out_dir, seq.class_name);
+        std::fs::create_dir_all(&dir)?;
+        let stem = seq_filename.file_stem().unwrap().to_str().unwrap();
+        let rows: Vec<Vec<f64>> = gamma.axis_iter(Axis(0)).map(|row| row.to_vec()).collect();
+        if npy {
+            utl::save_npy(&rows, &PathBuf::from(format!("{}/{}_gamma.npy", dir, stem)))?;
+            let entropy_rows: Vec<Vec<f64>> = entropy.iter().map(|&h| vec![h]).collect();
+            utl::save_npy(
+                &entropy_rows,
+                &PathBuf::from(format!("{}/{}_entropy.npy", dir, stem)),
+            )?;
+        } else {
+            let mut header: Vec<String> = (0..hmm.n).map(|i| format!("s{}", i)).collect();
+            header.push("entropy".to_string());
+            let rows: Vec<Vec<f64>> = rows
+                .into_iter()
+                .zip(&entropy)
+                .map(|(mut row, &h)| {
+                    row.push(h);
+                    row
+                })
+                .collect();
+            utl::save_csv(
+                &header,
+                &rows,
+                &PathBuf::from(format!("{}/{}_gamma.csv", dir, stem)),
+            )?;
+        }
+
+        let mean = entropy.iter().sum::<f64>() / entropy.len() as f64;
+        let max = entropy.iter().copied().fold(0f64, f64::max);
+        let uncertain = entropy.iter().filter(|&&h| h > uncertain_threshold).count();
+        println!(
+            "{:>7} {:>12.4} {:>12.4} {:>10}  {}",
+            entropy.len(),
+            mean,
+            max,
+            uncertain,
+            filename
+        );
+    }
+    println!("\nposteriors saved under {}/", out_dir);
+    Ok(())
+}
+
+fn entropy_bits(p: ArrayView1<f64>) -> f64 {
+    let h = -p
+        .iter()
+        .filter(|&&p| p > 0.0)
+        .map(|&p| p * p.log2())
+        .sum::<f64>();
+    // avoid tiny negative values due to rounding
+    h.max(0.0)
+}
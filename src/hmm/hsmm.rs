@@ -0,0 +1,747 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+
+use ndarray::prelude::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::c12n;
+use crate::sequence::generate::{sample_index, Length};
+use crate::sequence::{self, Sequence};
+
+use super::curve::LearningCurve;
+use super::learn::{
+    init_emissions, init_transitions, load_sequences, train_em, ExpectedCounts, HmmLearnParams,
+};
+use super::model::{apply_epsilon, log_sum_exp, Precision};
+
+/// Extension of the HSMM files.
+pub const EXTENSION: &str = ".hsmm";
+
+/// Non-parametric duration probabilities are kept above this value
+/// so no duration up to the maximum becomes impossible.
+const DURATION_FLOOR: f64 = 1e-6;
+
+/// Family of the state duration distributions.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DurationKind {
+    /// `1 + Poisson(λ)`.
+    Poisson,
+
+    /// Discretized Gamma density, fitted by the method of moments.
+    Gamma,
+
+    /// Arbitrary distribution over `1 ..= D`.
+    NonParametric,
+}
+
+impl FromStr for DurationKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "poisson" => Ok(DurationKind::Poisson),
+            "gamma" => Ok(DurationKind::Gamma),
+            "nonparametric" => Ok(DurationKind::NonParametric),
+            _ => Err(format!(
+                "invalid duration kind: {} (expecting poisson, gamma or nonparametric)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DurationKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DurationKind::Poisson => "poisson",
+            DurationKind::Gamma => "gamma",
+            DurationKind::NonParametric => "nonparametric",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl DurationKind {
+    /// Distribution over `1 ..= max_duration` with (approximately) the given
+    /// mean and variance; for `NonParametric`, the Poisson one.
+    fn with_moments(&self, mean: f64, var: f64, max_duration: usize) -> Array1<f64> {
+        let log_probs: Vec<f64> = match self {
+            DurationKind::Gamma => {
+                let var = var.max(1e-3);
+                let shape = mean * mean / var;
+                let scale = var / mean;
+                (1..=max_duration)
+                    .map(|d| (shape - 1.0) * (d as f64).ln() - d as f64 / scale)
+                    .collect()
+            }
+            DurationKind::Poisson | DurationKind::NonParametric => {
+                let lambda = (mean - 1.0).max(1e-6);
+                let mut log_fact = 0.0;
+                (0..max_duration)
+                    .map(|x| {
+                        if x > 0 {
+                            log_fact += (x as f64).ln();
+                        }
+                        x as f64 * lambda.ln() - log_fact
+                    })
+                    .collect()
+            }
+        };
+        let max = log_probs.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let probs = Array1::from_iter(log_probs.iter().map(|l| (l - max).exp()));
+        let sum = probs.sum();
+        probs / sum
+    }
+
+    /// Distribution fitted to the given expected counts of each duration,
+    /// or `None` if there are no counts.
+    fn fit(&self, counts: ArrayView1<f64>) -> Option<Array1<f64>> {
+        let total = counts.sum();
+        if total <= 0.0 {
+            return None;
+        }
+        if *self == DurationKind::NonParametric {
+            let probs = counts.mapv(|c| (c / total).max(DURATION_FLOOR));
+            let sum = probs.sum();
+            return Some(probs / sum);
+        }
+        let (mean, var) = moments(counts);
+        Some(self.with_moments(mean, var, counts.len()))
+    }
+}
+
+/// Mean and variance of the durations `1 ..= D` with the given weights.
+fn moments(weights: ArrayView1<f64>) -> (f64, f64) {
+    let total = weights.sum();
+    let mean = weights
+        .iter()
+        .enumerate()
+        .map(|(i, w)| (i + 1) as f64 * w)
+        .sum::<f64>()
+        / total;
+    let var = weights
+        .iter()
+        .enumerate()
+        .map(|(i, w)| ((i + 1) as f64 - mean).powi(2) * w)
+        .sum::<f64>()
+        / total;
+    (mean, var)
+}
+
+/// A hidden semi-Markov model (explicit-duration HMM) on discrete symbols.
+///
+/// Each visit to a state emits a number of symbols given by the state's
+/// duration distribution, then moves to another state according to A
+/// (which has no self-transitions). The last segment of a sequence may be
+/// incomplete (right-censored). Saved in CBOR format.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Hsmm {
+    pub class_name: String,
+    pub n: usize,
+    pub m: usize,
+    pub duration_kind: DurationKind,
+    pub pi: Array1<f64>,
+
+    /// Transitions between different states (N x N, zero diagonal).
+    /// A state with no transitions only ends a sequence.
+    pub a: Array2<f64>,
+
+    pub b: Array2<f64>,
+
+    /// `durations[[j, d - 1]]`: probability of staying `d` frames in state `j`,
+    /// for `d` in `1 ..= D` (N x D).
+    pub durations: Array2<f64>,
+}
+
+/// Model parameters in the log domain.
+struct LogParams {
+    pi: Array1<f64>,
+    a: Array2<f64>,
+    b: Array2<f64>,
+    durations: Array2<f64>,
+    /// `survival[[j, d - 1]]`: log probability of a duration of at least `d`.
+    survival: Array2<f64>,
+}
+
+/// Forward and backward variables of a sequence (all T x N, log domain).
+struct Lattice {
+    /// A segment of the state starts at t (and the observations before t).
+    start: Array2<f64>,
+    /// A complete segment of the state ends at t (and the observations up to t).
+    end: Array2<f64>,
+    /// Observations from t given a segment of the state starts at t.
+    start_back: Array2<f64>,
+    /// Observations after t given a complete segment of the state ends at t.
+    end_back: Array2<f64>,
+    log_prob: f64,
+}
+
+impl Hsmm {
+    pub fn max_duration(&self) -> usize {
+        self.durations.ncols()
+    }
+
+    pub fn show(&self) {
+        println!(
+            "class_name='{}', N={}, M={}, durations: {} (D={})",
+            self.class_name,
+            self.n,
+            self.m,
+            self.duration_kind,
+            self.max_duration()
+        );
+        println!("pi = {}", self.pi);
+        println!("A =");
+        for (i, a_row) in self.a.axis_iter(Axis(0)).enumerate() {
+            println!(" [{}]: {}", i, a_row);
+        }
+        println!("durations:");
+        for (i, d_row) in self.durations.axis_iter(Axis(0)).enumerate() {
+            let (mean, var) = moments(d_row);
+            println!(" [{}]: mean = {:.2}  sd = {:.2}", i, mean, var.sqrt());
+        }
+    }
+
+    fn log_params(&self) -> LogParams {
+        let mut survival = self.durations.clone();
+        for mut row in survival.axis_iter_mut(Axis(0)) {
+            let mut acc = 0.0;
+            for p in row.iter_mut().rev() {
+                acc += *p;
+                *p = acc;
+            }
+        }
+        LogParams {
+            pi: self.pi.mapv(f64::ln),
+            a: self.a.mapv(f64::ln),
+            b: self.b.mapv(f64::ln),
+            durations: self.durations.mapv(f64::ln),
+            survival: survival.mapv(f64::ln),
+        }
+    }
+
+    fn lattice(&self, lp: &LogParams, symbols: &[u16]) -> Lattice {
+        let (n, t_len, max_d) = (self.n, symbols.len(), self.max_duration());
+        let log_b = |j: usize, t: usize| lp.b[[j, symbols[t] as usize]];
+
+        let mut start = Array2::from_elem((t_len, n), f64::NEG_INFINITY);
+        let mut end = Array2::from_elem((t_len, n), f64::NEG_INFINITY);
+        for t in 0..t_len {
+            for j in 0..n {
+                start[[t, j]] = if t == 0 {
+                    lp.pi[j]
+                } else {
+                    log_sum_exp((0..n).map(|i| end[[t - 1, i]] + lp.a[[i, j]]))
+                };
+            }
+            // complete segments never end at the last frame (see `log_prob`)
+            if t + 1 == t_len {
+                break;
+            }
+            for j in 0..n {
+                let mut e = 0.0;
+                end[[t, j]] = log_sum_exp((1..=max_d.min(t + 1)).map(|d| {
+                    let s = t + 1 - d;
+                    e += log_b(j, s);
+                    start[[s, j]] + lp.durations[[j, d - 1]] + e
+                }));
+            }
+        }
+
+        let mut start_back = Array2::from_elem((t_len, n), f64::NEG_INFINITY);
+        let mut end_back = Array2::from_elem((t_len, n), f64::NEG_INFINITY);
+        for t in (0..t_len).rev() {
+            if t + 1 < t_len {
+                for j in 0..n {
+                    end_back[[t, j]] =
+                        log_sum_exp((0..n).map(|k| lp.a[[j, k]] + start_back[[t + 1, k]]));
+                }
+            }
+            for k in 0..n {
+                let mut e = 0.0;
+                start_back[[t, k]] = log_sum_exp((1..=max_d.min(t_len - t)).map(|d| {
+                    let last = t + d - 1;
+                    e += log_b(k, last);
+                    if last + 1 < t_len {
+                        lp.durations[[k, d - 1]] + e + end_back[[last, k]]
+                    } else {
+                        lp.survival[[k, d - 1]] + e
+                    }
+                }));
+            }
+        }
+
+        let log_prob = log_sum_exp((0..n).map(|j| lp.pi[j] + start_back[[0, j]]));
+        Lattice {
+            start,
+            end,
+            start_back,
+            end_back,
+            log_prob,
+        }
+    }
+
+    /// Log probability (natural log) of the given sequence, `-inf` if zero.
+    pub fn log_prob(&self, symbols: &[u16]) -> f64 {
+        if symbols.is_empty() {
+            return f64::NEG_INFINITY;
+        }
+        self.lattice(&self.log_params(), symbols).log_prob
+    }
+
+    /// Generates a sequence with the length sampled from `length`.
+    pub fn generate(&self, rng: &mut StdRng, length: &Length) -> Vec<u16> {
+        let len = length.sample(rng);
+        let mut symbols = Vec::with_capacity(len);
+        let mut state = sample_index(rng, self.pi.iter().copied());
+        while symbols.len() < len {
+            let d = 1 + sample_index(rng, self.durations.row(state).iter().copied());
+            for _ in 0..d.min(len - symbols.len()) {
+                symbols.push(sample_index(rng, self.b.row(state).iter().copied()) as u16);
+            }
+            // a state without transitions remains until the end
+            if self.a.row(state).sum() > 0.0 {
+                state = sample_index(rng, self.a.row(state).iter().copied());
+            }
+        }
+        symbols
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        crate::utl::save_ser(self, filename)
+    }
+}
+
+pub fn load(filename: &str) -> Result<Hsmm, Box<dyn Error>> {
+    let f = File::open(filename)?;
+    let br = BufReader::new(f);
+    let model = serde_cbor::from_reader(br)?;
+    Ok(model)
+}
+
+/// Expected counts accumulated over a set of training sequences.
+struct Counts {
+    pi: Array1<f64>,
+    a_num: Array2<f64>,
+    b_num: Array2<f64>,
+    durations: Array2<f64>,
+    log_prob: f64,
+    zero_prob: usize,
+}
+
+impl Counts {
+    fn new(n: usize, m: usize, max_d: usize) -> Counts {
+        Counts {
+            pi: Array1::zeros(n),
+            a_num: Array2::zeros((n, n)),
+            b_num: Array2::zeros((n, m)),
+            durations: Array2::zeros((n, max_d)),
+            log_prob: 0.0,
+            zero_prob: 0,
+        }
+    }
+
+    fn add(&mut self, other: &Counts) {
+        self.pi += &other.pi;
+        self.a_num += &other.a_num;
+        self.b_num += &other.b_num;
+        self.durations += &other.durations;
+        self.log_prob += other.log_prob;
+        self.zero_prob += other.zero_prob;
+    }
+
+    fn accumulate(&mut self, hsmm: &Hsmm, lp: &LogParams, symbols: &[u16]) {
+        let lattice = hsmm.lattice(lp, symbols);
+        let log_prob = lattice.log_prob;
+        if log_prob == f64::NEG_INFINITY {
+            self.zero_prob += 1;
+            return;
+        }
+        self.log_prob += log_prob;
+        let (n, t_len, max_d) = (hsmm.n, symbols.len(), hsmm.max_duration());
+
+        for j in 0..n {
+            self.pi[j] += (lp.pi[j] + lattice.start_back[[0, j]] - log_prob).exp();
+        }
+        for t in 0..t_len - 1 {
+            for j in 0..n {
+                for k in 0..n {
+                    self.a_num[[j, k]] +=
+                        (lattice.end[[t, j]] + lp.a[[j, k]] + lattice.start_back[[t + 1, k]]
+                            - log_prob)
+                            .exp();
+                }
+            }
+        }
+
+        // segment posteriors; state occupancies via differences
+        let mut occupancy_diff = Array2::<f64>::zeros((t_len + 1, n));
+        for s in 0..t_len {
+            for j in 0..n {
+                let base = lattice.start[[s, j]] - log_prob;
+                if base == f64::NEG_INFINITY {
+                    continue;
+                }
+                let mut e = 0.0;
+                for d in 1..=max_d.min(t_len - s) {
+                    let last = s + d - 1;
+                    e += lp.b[[j, symbols[last] as usize]];
+                    if last + 1 < t_len {
+                        let p = (base + lp.durations[[j, d - 1]] + e + lattice.end_back[[last, j]])
+                            .exp();
+                        self.durations[[j, d - 1]] += p;
+                        occupancy_diff[[s, j]] += p;
+                        occupancy_diff[[last + 1, j]] -= p;
+                    } else {
+                        // censored: distributed over the durations of at least d
+                        let p = (base + lp.survival[[j, d - 1]] + e).exp();
+                        if p > 0.0 {
+                            let survival = lp.survival[[j, d - 1]].exp();
+                            for dd in d..=max_d {
+                                self.durations[[j, dd - 1]] +=
+                                    p * hsmm.durations[[j, dd - 1]] / survival;
+                            }
+                        }
+                        occupancy_diff[[s, j]] += p;
+                    }
+                }
+            }
+        }
+        let mut occupancy = Array1::<f64>::zeros(n);
+        for (t, &symbol) in symbols.iter().enumerate() {
+            occupancy += &occupancy_diff.row(t);
+            for j in 0..n {
+                self.b_num[[j, symbol as usize]] += occupancy[j].max(0.0);
+            }
+        }
+    }
+}
+
+/// Training of an HSMM with the given duration distribution family
+/// over `1 ..= max_duration` (EM on the explicit-duration lattice).
+///
+/// Pi, A and B are initialized as with the discrete HMMs (`params.type_`,
+/// `params.mask`), but without self-transitions; the durations, as broad
+/// distributions around the average sequence length divided by N
+/// (at most D/2).
+/// Returns the model along with its final total log probability of the
+/// training sequences and the learning curve.
+pub fn learn(
+    codebook_size: usize,
+    seq_filenames: &[PathBuf],
+    params: &HmmLearnParams,
+    duration_kind: DurationKind,
+    max_duration: usize,
+) -> Result<(Hsmm, f64, LearningCurve), Box<dyn Error>> {
+    let sequences = load_sequences(codebook_size, seq_filenames)?;
+    learn_sequences(&sequences, params, duration_kind, max_duration)
+}
+
+fn learn_sequences(
+    sequences: &[Sequence],
+    params: &HmmLearnParams,
+    duration_kind: DurationKind,
+    max_duration: usize,
+) -> Result<(Hsmm, f64, LearningCurve), Box<dyn Error>> {
+    if max_duration == 0 {
+        return Err("Maximum duration must be positive".into());
+    }
+    let symbols: Vec<&[u16]> = sequences.iter().map(|s| s.symbols.as_slice()).collect();
+    let mut hsmm = init(
+        sequences[0].class_name.clone(),
+        sequences[0].codebook_size as usize,
+        &symbols,
+        params,
+        duration_kind,
+        max_duration,
+    )?;
+
+    let mut curve = LearningCurve::default();
+    let log_prob = train_em(
+        &mut hsmm,
+        symbols.len(),
+        params,
+        &mut curve,
+        |hsmm| expected_counts(hsmm, &symbols, params.parallel),
+        |hsmm, counts| reestimate(hsmm, counts, params.epsilon),
+        |_, _, _| Ok(()),
+    )
+    .map_err(|e| {
+        format!(
+            "{} (note: with left-right topologies, at most N x D = {} frames are possible)",
+            e,
+            params.num_states * max_duration
+        )
+    })?;
+    Ok((hsmm, log_prob, curve))
+}
+
+impl ExpectedCounts for Counts {
+    fn log_prob(&self) -> f64 {
+        self.log_prob
+    }
+
+    fn zero_prob(&self) -> usize {
+        self.zero_prob
+    }
+}
+
+fn expected_counts(hsmm: &Hsmm, symbols: &[&[u16]], parallel: bool) -> Counts {
+    let cores = if parallel { num_cpus::get() } else { 1 };
+    let chunk_size = symbols.len().div_ceil(cores).max(1);
+    let (n, m, max_d) = (hsmm.n, hsmm.m, hsmm.max_duration());
+    let lp = hsmm.log_params();
+    let lp = &lp;
+
+    thread::scope(|s| {
+        let handles: Vec<_> = symbols
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    let mut counts = Counts::new(n, m, max_d);
+                    chunk
+                        .iter()
+                        .for_each(|symbols| counts.accumulate(hsmm, lp, symbols));
+                    counts
+                })
+            })
+            .collect();
+
+        let mut counts = Counts::new(n, m, max_d);
+        for handle in handles {
+            counts.add(&handle.join().unwrap());
+        }
+        counts
+    })
+}
+
+fn reestimate(hsmm: &mut Hsmm, counts: &Counts, epsilon: f64) {
+    let pi_sum = counts.pi.sum();
+    if pi_sum > 0.0 {
+        hsmm.pi = &counts.pi / pi_sum;
+    }
+    // states never visited keep their previous values
+    for j in 0..hsmm.n {
+        let a_sum = counts.a_num.row(j).sum();
+        if a_sum > 0.0 {
+            let row = counts.a_num.row(j).to_owned() / a_sum;
+            hsmm.a.row_mut(j).assign(&row);
+        }
+        let b_sum = counts.b_num.row(j).sum();
+        if b_sum > 0.0 {
+            let row = counts.b_num.row(j).to_owned() / b_sum;
+            hsmm.b.row_mut(j).assign(&row);
+        }
+        if let Some(row) = hsmm.duration_kind.fit(counts.durations.row(j)) {
+            hsmm.durations.row_mut(j).assign(&row);
+        }
+    }
+    apply_epsilon(&mut hsmm.b, epsilon);
+}
+
+fn init(
+    class_name: String,
+    m: usize,
+    symbols: &[&[u16]],
+    params: &HmmLearnParams,
+    duration_kind: DurationKind,
+    max_duration: usize,
+) -> Result<Hsmm, Box<dyn Error>> {
+    let mut rng = StdRng::seed_from_u64(params.seed);
+    let (pi, mut a) = init_transitions(params, &mut rng)?;
+    let n = params.num_states;
+    for (j, mut row) in a.axis_iter_mut(Axis(0)).enumerate() {
+        row[j] = 0.0;
+        let sum = row.sum();
+        if sum > 0.0 {
+            row /= sum;
+        }
+    }
+    let b = init_emissions(m, params, &mut rng);
+
+    let avg_len = symbols.iter().map(|s| s.len()).sum::<usize>() as f64 / symbols.len() as f64;
+    let mean = (avg_len / n as f64).clamp(1.0, (max_duration as f64 / 2.0).max(1.0));
+    let row = duration_kind.with_moments(mean, mean * mean, max_duration);
+    let durations = Array2::from_shape_fn((n, max_duration), |(_, d)| row[d]);
+
+    Ok(Hsmm {
+        class_name,
+        n,
+        m,
+        duration_kind,
+        pi,
+        a,
+        b,
+        durations,
+    })
+}
+
+/// Classification of the given sequences with HSMMs.
+/// Results are reported with `C12nResults` under the base name
+/// `hsmm_N<N>_M<M>_<duration kind>`, with the (always log-space) precision recorded.
+pub fn classify(
+    model_filenames: Vec<PathBuf>,
+    seq_filenames: Vec<PathBuf>,
+    show_ranked: bool,
+) -> Result<(), Box<dyn Error>> {
+    println!("Loading HSMM models");
+    let models = model_filenames
+        .iter()
+        .map(|n| load(n.to_str().unwrap()))
+        .collect::<Result<Vec<Hsmm>, _>>()?;
+    if models.is_empty() {
+        return Err("No HSMM models given".into());
+    }
+    let codebook_size = models[0].m;
+    for (model, filename) in models.iter().zip(&model_filenames) {
+        if model.m != codebook_size {
+            return Err(format!(
+                "conformity error: {}: codebook size {} (expecting {})",
+                filename.display(),
+                model.m,
+                codebook_size
+            )
+            .into());
+        }
+    }
+
+    let model_class_names = models.iter().map(|m| m.class_name.clone()).collect();
+    let mut c12n = c12n::C12nResults::new(model_class_names);
+    c12n.set_scoring(&Precision::LogSpace.to_string());
+
+    println!("Classifying sequences");
+    for seq_filename in seq_filenames {
+        let filename = seq_filename.to_str().unwrap();
+        let seq = sequence::load(filename)?;
+        if seq.codebook_size as usize != codebook_size {
+            return Err(format!(
+                "{}: conformity error: codebook size: {} != {}",
+                filename, seq.codebook_size, codebook_size
+            )
+            .into());
+        }
+
+        let class_id_opt = models.iter().position(|m| m.class_name == seq.class_name);
+        if let Some(class_id) = class_id_opt {
+            let probs: Vec<f64> = models.iter().map(|m| m.log_prob(&seq.symbols)).collect();
+            c12n.add_case(class_id, &seq.class_name, probs, show_ranked, || {
+                format!("\n{}: '{}'", filename, seq.class_name)
+            });
+        }
+    }
+
+    println!();
+
+    let class_names: Vec<&String> = models.iter().map(|m| &m.class_name).collect();
+    let out_base_name = format!(
+        "hsmm_N{}_M{}_{}",
+        models[0].n, models[0].m, models[0].duration_kind
+    );
+    c12n.report_results(class_names, out_base_name);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> Hsmm {
+        let kind = DurationKind::Poisson;
+        Hsmm {
+            class_name: "c".to_string(),
+            n: 2,
+            m: 3,
+            duration_kind: kind,
+            pi: array![0.5, 0.5],
+            a: array![[0.0, 1.0], [1.0, 0.0]],
+            b: array![[0.8, 0.1, 0.1], [0.1, 0.1, 0.8]],
+            durations: ndarray::stack(
+                Axis(0),
+                &[
+                    kind.with_moments(8.0, 8.0, 20).view(),
+                    kind.with_moments(3.0, 3.0, 20).view(),
+                ],
+            )
+            .unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_learn() {
+        let true_model = model();
+        let mut rng = StdRng::seed_from_u64(11);
+        let sequences: Vec<Sequence> = (0..40)
+            .map(|_| Sequence {
+                class_name: "c".to_string(),
+                codebook_size: 3,
+                symbols: true_model.generate(&mut rng, &Length::Fixed(60)),
+            })
+            .collect();
+        let true_log_prob: f64 = sequences
+            .iter()
+            .map(|s| true_model.log_prob(&s.symbols))
+            .sum();
+
+        let params = HmmLearnParams {
+            num_states: 2,
+            type_: 0,
+            epsilon: 0.0,
+            val_auto: 0.3,
+            max_iterations: 200,
+            seed: 5,
+            parallel: false,
+            verbose: false,
+            precision: Precision::Scaled,
+            mask: None,
+        };
+        for kind in [
+            DurationKind::Poisson,
+            DurationKind::Gamma,
+            DurationKind::NonParametric,
+        ] {
+            let (hsmm, log_prob, curve) = learn_sequences(&sequences, &params, kind, 20).unwrap();
+            if kind == DurationKind::NonParametric {
+                // exact EM (the parametric fits on truncated durations are approximate)
+                let increasing = curve
+                    .points
+                    .windows(2)
+                    .all(|w| w[1].log_prob >= w[0].log_prob - 1e-6);
+                assert!(increasing);
+            }
+            assert!(
+                log_prob > true_log_prob - 0.02 * true_log_prob.abs(),
+                "{}",
+                kind
+            );
+
+            let total: f64 = sequences.iter().map(|s| hsmm.log_prob(&s.symbols)).sum();
+            assert!((total - log_prob).abs() < 1e-6 * log_prob.abs());
+
+            // the state emitting mostly symbol 0 has the longer durations
+            let long = if hsmm.b[[0, 0]] > hsmm.b[[1, 0]] {
+                0
+            } else {
+                1
+            };
+            let mean_long = moments(hsmm.durations.row(long)).0;
+            let mean_short = moments(hsmm.durations.row(1 - long)).0;
+            assert!((mean_long - 8.0).abs() < 1.5, "{}: {}", kind, mean_long);
+            assert!((mean_short - 3.0).abs() < 1.0, "{}: {}", kind, mean_short);
+        }
+    }
+
+    #[test]
+    fn test_gamma() {
+        let probs = DurationKind::Gamma.with_moments(10.0, 4.0, 40);
+        let (mean, var) = moments(probs.view());
+        assert!((probs.sum() - 1.0).abs() < 1e-12);
+        assert!((mean - 10.0).abs() < 0.5 && (var - 4.0).abs() < 1.0);
+    }
+}
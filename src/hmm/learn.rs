@@ -0,0 +1,887 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Instant;
+
+use ndarray::prelude::*;
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
+
+use crate::sequence::{self, Sequence};
+use crate::utl;
+
+use super::curve::{CurvePoint, LearningCurve};
+use super::model::{self, backward_log, forward_log, Hmm, Precision};
+use super::topology::TransitionMask;
+
+/// Training stops when the relative change of the total log probability
+/// of the training sequences is less than this value.
+pub const CONVERGENCE_THRESHOLD: f64 = 1e-5;
+
+/// Parameters for the HMM training.
+#[derive(Debug, Clone)]
+pub struct HmmLearnParams {
+    /// Number of states.
+    pub num_states: usize,
+
+    /// Type of initial model:
+    ///    0: random values for pi, A, and B
+    ///    1: uniform distributions
+    ///    2: cascade-2; random B
+    ///    3: cascade-3; random B
+    pub type_: usize,
+
+    /// Minimum value for the entries of B. 0 means no restriction.
+    pub epsilon: f64,
+
+    /// Self-transition probability in the cascade models.
+    pub val_auto: f64,
+
+    /// Maximum number of iterations; negative means no limit.
+    pub max_iterations: i32,
+
+    pub seed: u64,
+
+    /// Distribute the re-estimation over the available cores.
+    pub parallel: bool,
+
+    /// Report each iteration.
+    pub verbose: bool,
+
+    /// Precision of the forward-backward procedure, recorded in the model.
+    pub precision: Precision,
+
+    /// Allowed transitions. For the cascade types, this replaces their
+    /// structure, with `val_auto` for the self-transitions.
+    pub mask: Option<TransitionMask>,
+}
+
+/// Files associated to a training run, for a model to be saved under `dir`.
+pub struct TrainingOutput {
+    pub dir: String,
+
+    /// Prefix for the learning curve files; `<dir>/<class>_curve` by default.
+    pub curve_prefix: Option<String>,
+
+    /// Write a checkpoint every this number of iterations (0: never).
+    pub checkpoint_every: usize,
+
+    /// Continue from the checkpoint (and learning curve) of a previous run.
+    pub resume: bool,
+}
+
+impl TrainingOutput {
+    pub fn curve_prefix(&self, class_name: &str) -> String {
+        match &self.curve_prefix {
+            Some(prefix) => prefix.clone(),
+            None => format!("{}/{}_curve", self.dir, class_name),
+        }
+    }
+
+    /// The checkpoint has the same format as the final model,
+    /// but not the `.hmm` extension so it is not taken as a model.
+    pub fn checkpoint(&self, class_name: &str) -> String {
+        format!("{}/{}.hmm.ckpt", self.dir, class_name)
+    }
+}
+
+struct Checkpointing {
+    filename: String,
+    every: usize,
+    curve_prefix: String,
+}
+
+/// Totals of the expected counts computed in each EM iteration (see `train_em`).
+pub trait ExpectedCounts {
+    /// Total log probability of the training sequences under the model.
+    fn log_prob(&self) -> f64;
+
+    /// Number of training sequences with zero probability under the model.
+    fn zero_prob(&self) -> usize;
+}
+
+/// EM iterations on the given model until the relative change of the total
+/// log probability of the training sequences is less than `CONVERGENCE_THRESHOLD`,
+/// or `params.max_iterations` is reached.
+///
+/// Each iteration computes the expected counts under the current model
+/// (`e_step`) and adds a point to the learning curve (continuing from those
+/// already in it); unless done, the model is then re-estimated (`m_step`), and
+/// `after_m_step` is called with the learning curve and the next iteration.
+/// Returns the final total log probability.
+pub fn train_em<M, C: ExpectedCounts>(
+    model: &mut M,
+    num_sequences: usize,
+    params: &HmmLearnParams,
+    curve: &mut LearningCurve,
+    e_step: impl Fn(&M) -> C,
+    mut m_step: impl FnMut(&mut M, &C),
+    mut after_m_step: impl FnMut(&M, &LearningCurve, i32) -> Result<(), Box<dyn Error>>,
+) -> Result<f64, Box<dyn Error>> {
+    let (mut iteration, mut prev_log_prob, elapsed_before) = match curve.points.last() {
+        Some(last) => (last.iteration + 1, last.log_prob, last.elapsed_secs),
+        None => (0, f64::NEG_INFINITY, 0.0),
+    };
+    let before = Instant::now();
+    loop {
+        let counts = e_step(model);
+        if counts.zero_prob() == num_sequences {
+            return Err("All training sequences have zero probability".into());
+        }
+        let log_prob = counts.log_prob();
+        let change = (log_prob - prev_log_prob) / prev_log_prob.abs();
+        let elapsed_secs = elapsed_before + before.elapsed().as_secs_f64();
+        if params.verbose {
+            println!(
+                "{:>5}: log P = {:<20e} change = {:<14e} ({} zero-prob sequences) {:.2}s",
+                iteration,
+                log_prob,
+                change,
+                counts.zero_prob(),
+                elapsed_secs
+            );
+        }
+        curve.points.push(CurvePoint {
+            iteration,
+            log_prob,
+            delta: Some(log_prob - prev_log_prob).filter(|d| d.is_finite()),
+            elapsed_secs,
+        });
+
+        if change.abs() < CONVERGENCE_THRESHOLD
+            || (params.max_iterations >= 0 && iteration >= params.max_iterations)
+        {
+            return Ok(log_prob);
+        }
+
+        m_step(model, &counts);
+        prev_log_prob = log_prob;
+        iteration += 1;
+        after_m_step(model, curve, iteration)?;
+    }
+}
+
+/// Expected counts accumulated over a set of training sequences.
+struct Counts {
+    pi: Array1<f64>,
+    a_num: Array2<f64>,
+    a_den: Array1<f64>,
+    b_num: Array2<f64>,
+    b_den: Array1<f64>,
+    log_prob: f64,
+    /// Sequences with zero probability under the model (not accumulated).
+    zero_prob: usize,
+}
+
+impl Counts {
+    fn new(n: usize, m: usize) -> Counts {
+        Counts {
+            pi: Array1::zeros(n),
+            a_num: Array2::zeros((n, n)),
+            a_den: Array1::zeros(n),
+            b_num: Array2::zeros((n, m)),
+            b_den: Array1::zeros(n),
+            log_prob: 0.0,
+            zero_prob: 0,
+        }
+    }
+
+    fn add(&mut self, other: &Counts) {
+        self.pi += &other.pi;
+        self.a_num += &other.a_num;
+        self.a_den += &other.a_den;
+        self.b_num += &other.b_num;
+        self.b_den += &other.b_den;
+        self.log_prob += other.log_prob;
+        self.zero_prob += other.zero_prob;
+    }
+
+    /// Accumulates the expected counts for the given sequence
+    /// using the forward-backward procedure with the given precision.
+    fn accumulate(&mut self, hmm: &Hmm, symbols: &[u16], precision: Precision) {
+        match precision {
+            Precision::Scaled => self.accumulate_scaled(hmm, symbols),
+            Precision::LogSpace => self.accumulate_log(hmm, symbols),
+        }
+    }
+
+    fn accumulate_scaled(&mut self, hmm: &Hmm, symbols: &[u16]) {
+        let (alpha, scales) = match hmm.forward(symbols) {
+            Some(res) => res,
+            None => {
+                self.zero_prob += 1;
+                return;
+            }
+        };
+        let beta = hmm.backward(symbols, &scales);
+        let (n, t_len) = (hmm.n, symbols.len());
+
+        self.log_prob -= scales.iter().map(|c| c.ln()).sum::<f64>();
+
+        for t in 0..t_len {
+            let o = symbols[t] as usize;
+            for i in 0..n {
+                let gamma = alpha[[t, i]] * beta[[t, i]] / scales[t];
+                if t == 0 {
+                    self.pi[i] += gamma;
+                }
+                self.b_num[[i, o]] += gamma;
+                self.b_den[i] += gamma;
+                if t + 1 < t_len {
+                    self.a_den[i] += gamma;
+                    let o_next = symbols[t + 1] as usize;
+                    for j in 0..n {
+                        self.a_num[[i, j]] +=
+                            alpha[[t, i]] * hmm.a[[i, j]] * hmm.b[[j, o_next]] * beta[[t + 1, j]];
+                    }
+                }
+            }
+        }
+    }
+
+    fn accumulate_log(&mut self, hmm: &Hmm, symbols: &[u16]) {
+        let log_emissions = hmm.emissions(symbols).mapv(f64::ln);
+        let (log_alpha, log_prob) = match forward_log(&hmm.pi, &hmm.a, &log_emissions) {
+            Some(res) => res,
+            None => {
+                self.zero_prob += 1;
+                return;
+            }
+        };
+        let log_beta = backward_log(&hmm.a, &log_emissions);
+        let log_a = hmm.a.mapv(f64::ln);
+        let (n, t_len) = (hmm.n, symbols.len());
+
+        self.log_prob += log_prob;
+
+        for t in 0..t_len {
+            let o = symbols[t] as usize;
+            for i in 0..n {
+                let gamma = (log_alpha[[t, i]] + log_beta[[t, i]] - log_prob).exp();
+                if t == 0 {
+                    self.pi[i] += gamma;
+                }
+                self.b_num[[i, o]] += gamma;
+                self.b_den[i] += gamma;
+                if t + 1 < t_len {
+                    self.a_den[i] += gamma;
+                    for j in 0..n {
+                        self.a_num[[i, j]] += (log_alpha[[t, i]]
+                            + log_a[[i, j]]
+                            + log_emissions[[t + 1, j]]
+                            + log_beta[[t + 1, j]]
+                            - log_prob)
+                            .exp();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rust implementation of the Baum-Welch training of a discrete HMM.
+///
+/// Unlike the C implementation, there is no global state involved,
+/// so several models can be trained concurrently in the same process.
+/// Returns the trained model along with its final total log probability
+/// of the training sequences.
+///
+/// With an `output`, the learning curve is saved at the end and, along with
+/// the current model, at every checkpoint.
+pub fn learn(
+    codebook_size: usize,
+    seq_filenames: &[PathBuf],
+    params: &HmmLearnParams,
+    output: Option<&TrainingOutput>,
+) -> Result<(Hmm, f64), Box<dyn Error>> {
+    let sequences = load_sequences(codebook_size, seq_filenames)?;
+    let class_name = sequences[0].class_name.clone();
+    let symbols: Vec<&[u16]> = sequences.iter().map(|s| s.symbols.as_slice()).collect();
+
+    let output = match output {
+        Some(output) => output,
+        None => {
+            let hmm = init(class_name, codebook_size, params)?;
+            return train(hmm, &symbols, params, &mut LearningCurve::default(), None);
+        }
+    };
+
+    let checkpointing = Checkpointing {
+        filename: output.checkpoint(&class_name),
+        every: output.checkpoint_every,
+        curve_prefix: output.curve_prefix(&class_name),
+    };
+
+    let (hmm, mut curve) = if output.resume {
+        let hmm = model::load(&checkpointing.filename)?;
+        if (hmm.n, hmm.m) != (params.num_states, codebook_size)
+            || hmm.class_name != class_name
+            || hmm.precision != Some(params.precision)
+        {
+            return Err(format!(
+                "{}: checkpoint not conforming to the training",
+                checkpointing.filename
+            )
+            .into());
+        }
+        let curve = LearningCurve::load(&checkpointing.curve_prefix)?;
+        println!(
+            "resuming from {} after {} iteration(s)",
+            checkpointing.filename,
+            curve.points.len()
+        );
+        (hmm, curve)
+    } else {
+        let hmm = init(class_name, codebook_size, params)?;
+        (hmm, LearningCurve::default())
+    };
+
+    let res = train(hmm, &symbols, params, &mut curve, Some(&checkpointing))?;
+    curve.save(&checkpointing.curve_prefix)?;
+    println!(
+        "learning curve saved: {}.{{json,csv}}",
+        checkpointing.curve_prefix
+    );
+    Ok(res)
+}
+
+/// Final values of one of the trainings in `learn_restarts`.
+struct Restart {
+    seed: u64,
+    iterations: usize,
+    log_prob: f64,
+    heldout_log_prob: Option<f64>,
+    hmm: Hmm,
+    curve: LearningCurve,
+}
+
+/// Trains `restarts` models, initialized with seeds `params.seed`,
+/// `params.seed + 1`, .., and keeps the one with the highest log probability
+/// of the held-out sequences, if any, or otherwise of the training sequences.
+///
+/// Trainings run in parallel. The learning curve of each restart is saved as
+/// `<dir>/<class>_curve_r<restart>.{json,csv}`, the one of the selected restart
+/// also as the learning curve of `output` (see `TrainingOutput::curve_prefix`),
+/// and the final log probabilities of all of them as `<dir>/<class>_restarts.csv`.
+pub fn learn_restarts(
+    codebook_size: usize,
+    seq_filenames: &[PathBuf],
+    heldout_filenames: &[PathBuf],
+    params: &HmmLearnParams,
+    restarts: usize,
+    output: &TrainingOutput,
+) -> Result<(Hmm, f64), Box<dyn Error>> {
+    let dir = output.dir.as_str();
+    let sequences = load_sequences(codebook_size, seq_filenames)?;
+    let class_name = sequences[0].class_name.clone();
+    let symbols: Vec<&[u16]> = sequences.iter().map(|s| s.symbols.as_slice()).collect();
+    let heldout = if heldout_filenames.is_empty() {
+        vec![]
+    } else {
+        load_sequences(codebook_size, heldout_filenames)?
+    };
+
+    // each training on a single core unless fewer restarts than cores
+    let params = HmmLearnParams {
+        parallel: params.parallel && restarts < num_cpus::get(),
+        verbose: false,
+        ..params.clone()
+    };
+
+    let results: Vec<Result<Restart, String>> = thread::scope(|s| {
+        let handles: Vec<_> = (0..restarts)
+            .map(|r| {
+                let (class_name, symbols, heldout) = (&class_name, &symbols, &heldout);
+                let params = HmmLearnParams {
+                    seed: params.seed.wrapping_add(r as u64),
+                    ..params.clone()
+                };
+                s.spawn(move || -> Result<Restart, String> {
+                    let hmm = init(class_name.clone(), codebook_size, &params)
+                        .map_err(|e| e.to_string())?;
+                    let mut curve = LearningCurve::default();
+                    let (hmm, log_prob) = train(hmm, symbols, &params, &mut curve, None)
+                        .map_err(|e| format!("restart {}: {}", r, e))?;
+                    curve
+                        .save(&format!("{}/{}_curve_r{}", dir, class_name, r))
+                        .map_err(|e| e.to_string())?;
+                    let heldout_log_prob = if heldout.is_empty() {
+                        None
+                    } else {
+                        Some(
+                            heldout
+                                .iter()
+                                .map(|seq| hmm.log_prob_with(&seq.symbols, params.precision))
+                                .sum(),
+                        )
+                    };
+                    println!(
+                        "restart {:>3} (seed {}): {} iterations, log P = {}{}",
+                        r,
+                        params.seed,
+                        curve.points.len(),
+                        log_prob,
+                        heldout_log_prob
+                            .map_or(String::new(), |p| format!(", held-out log P = {}", p))
+                    );
+                    Ok(Restart {
+                        seed: params.seed,
+                        iterations: curve.points.len(),
+                        log_prob,
+                        heldout_log_prob,
+                        hmm,
+                        curve,
+                    })
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    let results = results
+        .into_iter()
+        .collect::<Result<Vec<Restart>, String>>()?;
+
+    let score = |r: &Restart| r.heldout_log_prob.unwrap_or(r.log_prob);
+    let best = (0..results.len())
+        .max_by(|&i, &j| score(&results[i]).total_cmp(&score(&results[j])))
+        .unwrap();
+
+    println!(
+        "\n{:>7} {:>20} {:>10} {:>22} {:>22}",
+        "restart", "seed", "iterations", "log_prob", "heldout_log_prob"
+    );
+    let mut rows = Vec::new();
+    for (r, restart) in results.iter().enumerate() {
+        let heldout = restart.heldout_log_prob.unwrap_or(f64::NAN);
+        println!(
+            "{:>7} {:>20} {:>10} {:>22.6} {:>22.6}{}",
+            r,
+            restart.seed,
+            restart.iterations,
+            restart.log_prob,
+            heldout,
+            if r == best { "  *" } else { "" }
+        );
+        rows.push(vec![
+            r as f64,
+            restart.seed as f64,
+            restart.iterations as f64,
+            restart.log_prob,
+            heldout,
+            if r == best { 1.0 } else { 0.0 },
+        ]);
+    }
+    let header: Vec<String> = [
+        "restart",
+        "seed",
+        "iterations",
+        "log_prob",
+        "heldout_log_prob",
+        "selected",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    let restarts_filename = PathBuf::from(format!("{}/{}_restarts.csv", dir, class_name));
+    utl::save_csv(&header, &rows, &restarts_filename)?;
+    println!(
+        "selected restart {} by {} log P; summary saved to {:?}",
+        best,
+        if heldout.is_empty() {
+            "training"
+        } else {
+            "held-out"
+        },
+        restarts_filename
+    );
+
+    let best = results.into_iter().nth(best).unwrap();
+    let curve_prefix = output.curve_prefix(&class_name);
+    best.curve.save(&curve_prefix)?;
+    println!("learning curve saved: {}.{{json,csv}}", curve_prefix);
+    Ok((best.hmm, best.log_prob))
+}
+
+/// Loads the given sequences (at least one), checking they conform to the codebook size.
+pub fn load_sequences(
+    codebook_size: usize,
+    seq_filenames: &[PathBuf],
+) -> Result<Vec<Sequence>, Box<dyn Error>> {
+    let mut sequences = Vec::with_capacity(seq_filenames.len());
+    for seq_filename in seq_filenames {
+        let filename = seq_filename.to_str().unwrap();
+        let seq = sequence::load(filename)?;
+        if codebook_size != seq.codebook_size as usize {
+            return Err(format!(
+                "{}: conformity error: codebook size: {} != {}",
+                filename, codebook_size, seq.codebook_size
+            )
+            .into());
+        }
+        if seq.symbols.is_empty() {
+            return Err(format!("{}: empty sequence", filename).into());
+        }
+        sequences.push(seq);
+    }
+    if sequences.is_empty() {
+        return Err("No training sequences".into());
+    }
+    Ok(sequences)
+}
+
+/// Baum-Welch re-estimation of the given model until convergence
+/// or the maximum number of iterations.
+/// Iterations continue from those already in the given learning curve.
+fn train(
+    mut hmm: Hmm,
+    symbols: &[&[u16]],
+    params: &HmmLearnParams,
+    curve: &mut LearningCurve,
+    checkpointing: Option<&Checkpointing>,
+) -> Result<(Hmm, f64), Box<dyn Error>> {
+    let constrain = |hmm: &mut Hmm| {
+        hmm.apply_epsilon(params.epsilon);
+        if let Some(mask) = &params.mask {
+            mask.apply(&mut hmm.a);
+        }
+    };
+    constrain(&mut hmm);
+    let log_prob = train_em(
+        &mut hmm,
+        symbols.len(),
+        params,
+        curve,
+        |hmm| expected_counts(hmm, symbols, params.parallel, params.precision),
+        |hmm, counts| {
+            reestimate(hmm, counts);
+            constrain(hmm);
+        },
+        |hmm, curve, iteration| {
+            if let Some(checkpointing) = checkpointing {
+                if checkpointing.every > 0
+                    && (iteration as usize).is_multiple_of(checkpointing.every)
+                {
+                    // the model to be evaluated at `iteration`, and the curve up to the previous one
+                    hmm.save(&checkpointing.filename)?;
+                    curve.save(&checkpointing.curve_prefix)?;
+                    println!("       checkpoint saved: {}", checkpointing.filename);
+                }
+            }
+            Ok(())
+        },
+    )?;
+    Ok((hmm, log_prob))
+}
+
+impl ExpectedCounts for Counts {
+    fn log_prob(&self) -> f64 {
+        self.log_prob
+    }
+
+    fn zero_prob(&self) -> usize {
+        self.zero_prob
+    }
+}
+
+fn expected_counts(hmm: &Hmm, symbols: &[&[u16]], parallel: bool, precision: Precision) -> Counts {
+    let cores = if parallel { num_cpus::get() } else { 1 };
+    let chunk_size = symbols.len().div_ceil(cores).max(1);
+
+    thread::scope(|s| {
+        let handles: Vec<_> = symbols
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    let mut counts = Counts::new(hmm.n, hmm.m);
+                    chunk
+                        .iter()
+                        .for_each(|seq| counts.accumulate(hmm, seq, precision));
+                    counts
+                })
+            })
+            .collect();
+
+        let mut counts = Counts::new(hmm.n, hmm.m);
+        for handle in handles {
+            counts.add(&handle.join().unwrap());
+        }
+        counts
+    })
+}
+
+fn reestimate(hmm: &mut Hmm, counts: &Counts) {
+    let pi_sum = counts.pi.sum();
+    if pi_sum > 0.0 {
+        hmm.pi = &counts.pi / pi_sum;
+    }
+    for i in 0..hmm.n {
+        // states never visited keep their previous distributions
+        if counts.a_den[i] > 0.0 {
+            let row = counts.a_num.row(i).to_owned() / counts.a_den[i];
+            hmm.a.row_mut(i).assign(&row);
+        }
+        if counts.b_den[i] > 0.0 {
+            let row = counts.b_num.row(i).to_owned() / counts.b_den[i];
+            hmm.b.row_mut(i).assign(&row);
+        }
+    }
+}
+
+/// Initial model according to `params.type_`.
+fn init(class_name: String, m: usize, params: &HmmLearnParams) -> Result<Hmm, Box<dyn Error>> {
+    if m == 0 {
+        return Err("Number of symbols must be positive".into());
+    }
+    let mut rng = StdRng::seed_from_u64(params.seed);
+    let (pi, a) = init_transitions(params, &mut rng)?;
+    let b = init_emissions(m, params, &mut rng);
+
+    Ok(Hmm {
+        class_name,
+        n: params.num_states,
+        m,
+        pi,
+        a,
+        b,
+        precision: Some(params.precision),
+    })
+}
+
+/// Initial B according to `params.type_`: uniform for type 1, random otherwise.
+pub fn init_emissions(m: usize, params: &HmmLearnParams, rng: &mut StdRng) -> Array2<f64> {
+    let n = params.num_states;
+    if params.type_ == 1 {
+        Array2::from_elem((n, m), 1.0 / m as f64)
+    } else {
+        let mut b = Array2::zeros((n, m));
+        for i in 0..n {
+            b.row_mut(i).assign(&random_row(rng, m));
+        }
+        b
+    }
+}
+
+/// Initial pi and A according to `params.type_` and `params.mask`.
+pub fn init_transitions(
+    params: &HmmLearnParams,
+    rng: &mut StdRng,
+) -> Result<(Array1<f64>, Array2<f64>), Box<dyn Error>> {
+    let n = params.num_states;
+    if n == 0 {
+        return Err("Number of states must be positive".into());
+    }
+    let val_auto = params.val_auto;
+    if params.type_ >= 2 && !(0.0..=1.0).contains(&val_auto) {
+        return Err(format!("val_auto must be in [0, 1]: {}", val_auto).into());
+    }
+    if let Some(mask) = &params.mask {
+        if mask.allowed.dim() != (n, n) {
+            return Err("Transition mask not conforming to the number of states".into());
+        }
+    }
+
+    let (pi, mut a) = match params.type_ {
+        0 => {
+            let pi = random_row(rng, n);
+            let mut a = Array2::zeros((n, n));
+            for i in 0..n {
+                a.row_mut(i).assign(&random_row(rng, n));
+            }
+            (pi, a)
+        }
+        1 => (
+            Array1::from_elem(n, 1.0 / n as f64),
+            Array2::from_elem((n, n), 1.0 / n as f64),
+        ),
+        2 | 3 => {
+            let mut pi = Array1::zeros(n);
+            pi[0] = 1.0;
+            let cascade = if params.type_ == 2 {
+                "left-right"
+            } else {
+                "left-right-skip"
+            };
+            let a = match &params.mask {
+                Some(mask) => mask.cascade(val_auto),
+                None => TransitionMask::parse(cascade, n)?.cascade(val_auto),
+            };
+            (pi, a)
+        }
+        _ => return Err(format!("Unrecognized model type: {}", params.type_).into()),
+    };
+    if let Some(mask) = &params.mask {
+        mask.apply(&mut a);
+    }
+    Ok((pi, a))
+}
+
+fn random_row(rng: &mut StdRng, len: usize) -> Array1<f64> {
+    let row = Array1::from_shape_fn(len, |_| rng.random::<f64>() + f64::EPSILON);
+    let sum = row.sum();
+    row / sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(type_: usize, seed: u64) -> HmmLearnParams {
+        HmmLearnParams {
+            num_states: 3,
+            type_,
+            epsilon: 1e-5,
+            val_auto: 0.3,
+            max_iterations: 30,
+            seed,
+            parallel: true,
+            verbose: false,
+            precision: Precision::Scaled,
+            mask: None,
+        }
+    }
+
+    #[test]
+    fn test_retrain_on_generated() {
+        let truth = Hmm {
+            class_name: "c".to_string(),
+            n: 2,
+            m: 3,
+            pi: array![1.0, 0.0],
+            a: array![[0.8, 0.2], [0.1, 0.9]],
+            b: array![[0.7, 0.2, 0.1], [0.1, 0.1, 0.8]],
+            precision: None,
+        };
+        let mut rng = StdRng::seed_from_u64(1);
+        let length = crate::sequence::generate::Length::Fixed(100);
+        let seqs: Vec<Vec<u16>> = (0..40).map(|_| truth.generate(&mut rng, &length)).collect();
+        let symbols: Vec<&[u16]> = seqs.iter().map(|s| s.as_slice()).collect();
+
+        // best of a few random initializations
+        let (trained, log_prob) = (0..5)
+            .map(|seed| {
+                let params = HmmLearnParams {
+                    num_states: 2,
+                    max_iterations: 100,
+                    ..params(0, seed)
+                };
+                let hmm = init("c".to_string(), 3, &params).unwrap();
+                train(hmm, &symbols, &params, &mut LearningCurve::default(), None).unwrap()
+            })
+            .max_by(|x, y| x.1.total_cmp(&y.1))
+            .unwrap();
+
+        // the trained model fits the data at least as well as the true one
+        let truth_log_prob: f64 = symbols.iter().map(|s| truth.log_prob(s)).sum();
+        assert!(log_prob > truth_log_prob - 1.0);
+
+        // and recovers the emissions, up to a permutation of the states
+        let k = if trained.b[[0, 0]] > trained.b[[1, 0]] {
+            0
+        } else {
+            1
+        };
+        assert!((trained.b[[k, 0]] - 0.7).abs() < 0.1);
+        assert!((trained.b[[1 - k, 2]] - 0.8).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_mask_enforced() {
+        let seqs: Vec<Vec<u16>> = vec![vec![0, 0, 1, 1, 2, 2, 0, 0, 1, 2], vec![2, 2, 0, 1, 1]];
+        let symbols: Vec<&[u16]> = seqs.iter().map(|s| s.as_slice()).collect();
+        for type_ in 0..4 {
+            let mask = TransitionMask::parse("cyclic", 3).unwrap();
+            let params = HmmLearnParams {
+                mask: Some(mask.clone()),
+                ..params(type_, 11)
+            };
+            let hmm = init("c".to_string(), 3, &params).unwrap();
+            let (hmm, _) =
+                train(hmm, &symbols, &params, &mut LearningCurve::default(), None).unwrap();
+            hmm.validate();
+            for ((i, j), &ok) in mask.allowed.indexed_iter() {
+                if !ok {
+                    assert_eq!(hmm.a[[i, j]], 0.0, "type {}", type_);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_train_concurrently() {
+        let seqs: Vec<Vec<u16>> = vec![
+            vec![0, 0, 0, 1, 1, 1, 2, 2, 2, 2],
+            vec![0, 0, 1, 1, 1, 2, 2],
+            vec![0, 1, 1, 2, 2, 2, 2, 2],
+        ];
+        let symbols: Vec<&[u16]> = seqs.iter().map(|s| s.as_slice()).collect();
+
+        let results: Vec<(Hmm, f64, f64)> = thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|type_| {
+                    let symbols = &symbols;
+                    s.spawn(move || {
+                        let params = params(type_, 17);
+                        let mut hmm = init("c".to_string(), 3, &params).unwrap();
+                        hmm.apply_epsilon(params.epsilon);
+                        let initial =
+                            expected_counts(&hmm, symbols, false, params.precision).log_prob;
+                        let mut curve = LearningCurve::default();
+                        let (hmm, log_prob) =
+                            train(hmm, symbols, &params, &mut curve, None).unwrap();
+                        (hmm, initial, log_prob)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        for (type_, (hmm, initial, log_prob)) in results.iter().enumerate() {
+            hmm.validate();
+            assert!(hmm.b.iter().all(|&p| p >= 1e-5 - 1e-12));
+            if type_ >= 2 {
+                // cascade structure preserved
+                assert_eq!(hmm.a[[2, 0]], 0.0);
+                assert_eq!(hmm.a[[1, 0]], 0.0);
+            }
+            if type_ != 1 {
+                assert!(
+                    log_prob > initial,
+                    "type {}: {} <= {}",
+                    type_,
+                    log_prob,
+                    initial
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_precisions() {
+        let seqs: Vec<Vec<u16>> = vec![
+            vec![0, 0, 0, 1, 1, 1, 2, 2, 2, 2],
+            vec![0, 0, 1, 1, 1, 2, 2],
+            vec![0, 1, 1, 2, 2, 2, 2, 2],
+        ];
+        let symbols: Vec<&[u16]> = seqs.iter().map(|s| s.as_slice()).collect();
+
+        let trained: Vec<(Hmm, f64)> = [Precision::Scaled, Precision::LogSpace]
+            .into_iter()
+            .map(|precision| {
+                let params = HmmLearnParams {
+                    precision,
+                    ..params(3, 5)
+                };
+                let hmm = init("c".to_string(), 3, &params).unwrap();
+                train(hmm, &symbols, &params, &mut LearningCurve::default(), None).unwrap()
+            })
+            .collect();
+
+        let (scaled, log_space) = (&trained[0], &trained[1]);
+        assert_eq!(scaled.0.precision, Some(Precision::Scaled));
+        assert_eq!(log_space.0.precision, Some(Precision::LogSpace));
+        assert_approx_eq!(scaled.1, log_space.1, 1e-8);
+        for (x, y) in scaled.0.b.iter().zip(log_space.0.b.iter()) {
+            assert_approx_eq!(x, y, 1e-8);
+        }
+    }
+}
@@ -3,9 +3,13 @@ extern crate libc;
 
 use std::error::Error;
 use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Instant;
 
 use clap::StructOpt;
 use colored::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 
 use crate::ecoz2_lib::hmm_classify_predictors;
 use crate::ecoz2_lib::hmm_classify_sequences;
@@ -13,9 +17,23 @@ use crate::ecoz2_lib::hmm_learn;
 use crate::ecoz2_lib::hmm_show;
 use crate::ecoz2_lib::set_random_seed;
 use crate::ecoz2_lib::version;
+use crate::prd;
+use crate::sequence::generate::{self, Length};
 use crate::utl;
+use crate::utl::learn_all;
 
-use self::EcozHmmCommand::{Classify, Learn, Show};
+use self::model::Precision;
+use self::EcozHmmCommand::{Classify, Compare, Decode, Generate, Learn, LearnAll, Show};
+
+mod classify;
+mod compare;
+mod continuous;
+mod curve;
+mod decode;
+mod hsmm;
+mod learn;
+pub mod model;
+mod topology;
 
 #[derive(StructOpt, Debug)]
 pub struct HmmMainOpts {
@@ -29,11 +47,23 @@ enum EcozHmmCommand {
     #[structopt(about = "HMM training")]
     Learn(HmmLearnOpts),
 
+    #[structopt(about = "HMM training of all classes (Rust implementation)")]
+    LearnAll(HmmLearnAllOpts),
+
     #[structopt(about = "HMM based classification")]
     Classify(HmmClassifyOpts),
 
     #[structopt(about = "Show HMM model")]
     Show(HmmShowOpts),
+
+    #[structopt(about = "Posterior state decoding")]
+    Decode(HmmDecodeOpts),
+
+    #[structopt(about = "Generate synthetic sequences from HMM model")]
+    Generate(HmmGenerateOpts),
+
+    #[structopt(about = "Distances between HMM models")]
+    Compare(HmmCompareOpts),
 }
 
 #[derive(StructOpt, Debug)]
@@ -43,8 +73,8 @@ pub struct HmmLearnOpts {
     num_states: usize,
 
     /// Number of symbols (codebook size)
-    #[structopt(short = 'M', long, name = "M", required = true)]
-    codebook_size: usize,
+    #[structopt(short = 'M', long, name = "M", required_unless("continuous"))]
+    codebook_size: Option<usize>,
 
     /// Type of model to generate:
     ///    0: random values for pi, A, and B
@@ -76,6 +106,93 @@ pub struct HmmLearnOpts {
     #[structopt(long)]
     ser: bool,
 
+    /// Use Rust implementation.
+    /// The model is saved as `data/hmms/N<N>__M<M>_t<type>__a<val_auto>[_I<I>]/<class>.hmm`.
+    #[structopt(long)]
+    zrs: bool,
+
+    /// Save the learning curve (iteration, log probability, delta, elapsed time)
+    /// to `<prefix>.json` and `<prefix>.csv`.
+    /// With `--zrs`, by default `<class>_curve` next to the model (with `--restarts`,
+    /// the curve of the selected restart).
+    /// With the C implementation, from the log probabilities reported via its callback.
+    #[structopt(long, name = "prefix")]
+    curve: Option<String>,
+
+    /// With `--zrs`, precision of the forward-backward procedure:
+    /// scaled (default) or log-space. Recorded in the model.
+    #[structopt(long)]
+    precision: Option<Precision>,
+
+    /// With `--zrs`, save a checkpoint `<class>.hmm.ckpt` next to the model
+    /// every this number of iterations (0 to disable).
+    #[structopt(long, default_value = "10")]
+    checkpoint_every: usize,
+
+    /// With `--zrs`, continue the training from the checkpoint left by
+    /// a previous run with the same options.
+    #[structopt(long)]
+    resume: bool,
+
+    /// With `--zrs`, number of trainings from different seeds (in parallel)
+    /// keeping the model with the highest training, or held-out, log probability.
+    #[structopt(long, default_value = "1")]
+    restarts: usize,
+
+    /// With `--zrs`, allowed state transitions, enforced throughout the training:
+    /// ergodic, left-right, left-right-skip, cyclic, or a file with N rows of
+    /// N 0/1 values. With the cascade types, this replaces their structure.
+    #[structopt(long, name = "topology")]
+    topology: Option<String>,
+
+    /// Train a continuous-density HMM with a mixture of diagonal Gaussians
+    /// per state on the cepstral vectors of the `--predictors`, instead of
+    /// the discrete HMM on sequences. Transitions are initialized according to
+    /// `-t`, `-a` and `--topology`.
+    /// The model is saved as `data/chmms/N<N>__K<K>__Q<Q>[_I<I>]/<class>.chmm`.
+    #[structopt(long)]
+    continuous: bool,
+
+    /// With `--continuous`, number of mixture components per state.
+    #[structopt(short = 'K', long, default_value = "2")]
+    mixtures: usize,
+
+    /// With `--continuous`, order Q of the cepstrum (coefficients 1..Q-1 are used).
+    /// 0 means P + 1, with P the prediction order.
+    #[structopt(short = 'Q', long, default_value = "0")]
+    cepstrum_order: usize,
+
+    /// With `--continuous`, training predictor files.
+    /// If a single `.csv` file is given, then the "TRAIN" files indicated there will be used,
+    /// and only the ones corresponding to a class name if `--class-name` is given.
+    /// Otherwise, if directories are included, then all `.prd` under them will be used.
+    #[structopt(long, parse(from_os_str))]
+    predictors: Vec<PathBuf>,
+
+    #[structopt(long, default_value = "data/predictors")]
+    predictors_dir_template: String,
+
+    /// Train a hidden semi-Markov model with explicit state durations
+    /// (and no self-transitions) on the sequences.
+    /// The model is saved as
+    /// `data/hsmms/N<N>__M<M>_t<type>__<durations>_D<D>[_I<I>]/<class>.hsmm`.
+    #[structopt(long)]
+    hsmm: bool,
+
+    /// With `--hsmm`, family of the duration distributions:
+    /// poisson, gamma or nonparametric.
+    #[structopt(long, default_value = "poisson")]
+    durations: hsmm::DurationKind,
+
+    /// With `--hsmm`, maximum state duration D (in frames).
+    #[structopt(short = 'D', long, default_value = "50")]
+    max_duration: usize,
+
+    /// Held-out sequences for the selection of the model with `--restarts`.
+    /// If directories are included, then all `.seq` under them will be used.
+    #[structopt(long, parse(from_os_str))]
+    heldout: Vec<PathBuf>,
+
     /// Training sequences.
     /// If a single `.csv` file is given, then the "TRAIN" files indicated there will be used,
     /// and only the ones corresponding to a class name if `--class-name` is given.
@@ -89,6 +206,58 @@ pub struct HmmLearnOpts {
     class_name: Option<String>,
 }
 
+#[derive(StructOpt, Debug)]
+pub struct HmmLearnAllOpts {
+    /// Number of states
+    #[structopt(short = 'N', long, name = "N", default_value = "5")]
+    num_states: usize,
+
+    /// Number of symbols (codebook size)
+    #[structopt(short = 'M', long, name = "M", required = true)]
+    codebook_size: usize,
+
+    /// Type of model to generate (see `hmm learn`)
+    #[structopt(short = 't', default_value = "3")]
+    type_: usize,
+
+    /// Maximum number of iterations. Default (-1) means no limit.
+    #[structopt(short = 'I', long, name = "I", default_value = "-1")]
+    max_iterations: i32,
+
+    /// epsilon restriction on B.
+    /// 0 means do not apply this restriction
+    #[structopt(short = 'e', default_value = "1e-05")]
+    epsilon: f64,
+
+    /// val_auto.
+    #[structopt(short = 'a', default_value = "0.3")]
+    val_auto: f64,
+
+    /// Seed for random numbers, used for every class. Negative means random seed.
+    #[structopt(short = 's', long, default_value = "-1")]
+    seed: i64,
+
+    /// Allowed state transitions (see `hmm learn`).
+    #[structopt(long, name = "topology")]
+    topology: Option<String>,
+
+    /// Precision of the forward-backward procedure: scaled or log-space.
+    /// Recorded in the models.
+    #[structopt(long, default_value = "scaled")]
+    precision: Precision,
+
+    /// Maximum number of classes trained at a time (0 means the number of CPUs).
+    /// Each training is serialized.
+    #[structopt(long, default_value = "0")]
+    threads: usize,
+
+    /// The "TRAIN" files indicated in this `.csv` file are grouped by class,
+    /// and a model is trained for each class.
+    /// The models are saved as `data/hmms/N<N>__M<M>_t<type>__a<val_auto>[_I<I>]/<class>.hmm`.
+    #[structopt(parse(from_os_str))]
+    tt_list: PathBuf,
+}
+
 #[derive(StructOpt, Debug)]
 pub struct HmmClassifyOpts {
     /// Show ranked models for incorrect classifications
@@ -96,11 +265,34 @@ pub struct HmmClassifyOpts {
     show_ranked: bool,
 
     /// File to report classification results for each sequence.
+    /// With `--zrs`, a CSV with the filename, true class, predicted class,
+    /// rank of the true class, and the log probability under each model
+    /// (by default `hmm_<M>_scores.csv`).
     #[structopt(short, long = "c12n", parse(from_os_str))]
     classification_filename: Option<PathBuf>,
 
+    /// Use Rust implementation to classify the sequences.
+    #[structopt(long)]
+    zrs: bool,
+
+    /// With `--zrs`, classify soft sequences (as generated by `vq quantize --zrs --top-k`).
+    #[structopt(long)]
+    soft: bool,
+
+    /// Temperature for the soft observations, relative to the average
+    /// distortion of the nearest symbols (0 means only the nearest symbol).
+    #[structopt(long, default_value = "1")]
+    temperature: f64,
+
+    /// With `--zrs`, precision for the log probabilities: scaled or log-space.
+    /// By default, the one recorded in the models, if any, or scaled.
+    /// Not applicable to continuous HMMs (always scaled) and HSMMs (always log-space).
+    #[structopt(long)]
+    precision: Option<Precision>,
+
     /// HMM models.
-    /// If directories are included, then all `.hmm` under them will be used.
+    /// If directories are included, then all `.hmm` under them will be used
+    /// (`.chmm` with `--predictors` and no `--codebooks`).
     #[structopt(short, long, required = true, min_values = 1, parse(from_os_str))]
     models: Vec<PathBuf>,
 
@@ -113,6 +305,11 @@ pub struct HmmClassifyOpts {
     #[structopt(long, name = "class")]
     class_name: Option<String>,
 
+    /// Classify the sequences with HSMMs
+    /// (`.hsmm` models, see `hmm learn --hsmm`).
+    #[structopt(long)]
+    hsmm: bool,
+
     /// Sequences to classify.
     /// If directories are included, then all `.seq` under them will be used.
     #[structopt(
@@ -126,8 +323,8 @@ pub struct HmmClassifyOpts {
 
     /// Number of symbols (codebook size) when `--sequences` with
     /// a `.csv` file is given. Helps determine the path to the sequences.
-    #[structopt(short = 'M', long, required = true)]
-    codebook_size: usize,
+    #[structopt(short = 'M', long, required_unless("predictors"))]
+    codebook_size: Option<usize>,
 
     /// Predictor files to classify.
     /// If a single `.csv` file is given, then only the ones indicated with `--tt` will be used.
@@ -140,28 +337,151 @@ pub struct HmmClassifyOpts {
 
     /// Codebook models when `--predictors` is given.
     /// If directories are included, then all `.cb` under them will be used.
-    #[structopt(long, required_unless("sequences"), min_values = 1, parse(from_os_str))]
+    /// Without codebooks, the predictors are classified with continuous HMMs
+    /// (see `hmm learn --continuous`).
+    #[structopt(long, min_values = 1, parse(from_os_str))]
     codebooks: Vec<PathBuf>,
 }
 
 #[derive(StructOpt, Debug)]
 pub struct HmmShowOpts {
     /// HMM model.
-    #[structopt(short, long, parse(from_os_str))]
+    /// A continuous HMM (`.chmm`) or an HSMM (`.hsmm`) is shown with the Rust implementation.
+    /// (Note: no short option, which would conflict with `-h` for help.)
+    #[structopt(long, parse(from_os_str))]
     hmm: PathBuf,
 
     /// HMM model.
     #[structopt(short, long, default_value = "%Lg ")]
     format: String,
+
+    /// Use Rust implementation: show the model
+    /// and check that pi and the rows of A and B are stochastic.
+    #[structopt(long)]
+    zrs: bool,
+
+    /// Export the model to the given file in JSON format
+    /// (use `-` for standard output).
+    #[structopt(long, name = "json-file", parse(from_os_str))]
+    json: Option<PathBuf>,
+
+    /// Export pi, A and B to `<prefix>_pi.csv`, `<prefix>_A.csv` and `<prefix>_B.csv`.
+    #[structopt(long, name = "csv-prefix")]
+    csv: Option<String>,
+
+    /// Export pi, A and B to `<prefix>_pi.npy`, `<prefix>_A.npy` and `<prefix>_B.npy`.
+    #[structopt(long, name = "npy-prefix")]
+    npy: Option<String>,
+
+    /// Write the state transitions as a Graphviz graph to the given file
+    /// (use `-` for standard output), with each state labeled by its
+    /// dominant emission symbols.
+    #[structopt(long, name = "dot-file", parse(from_os_str))]
+    dot: Option<PathBuf>,
+
+    /// With `--dot`, only include transitions with at least this probability.
+    #[structopt(long, default_value = "0.05")]
+    threshold: f64,
+
+    /// With `--dot`, number of dominant symbols in the state labels.
+    #[structopt(long, default_value = "3")]
+    top_symbols: usize,
+
+    /// Save A in long format (`from,to,prob`) to the given CSV file
+    /// for heatmap plotting.
+    #[structopt(long, name = "heatmap-file", parse(from_os_str))]
+    heatmap: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct HmmDecodeOpts {
+    /// HMM model.
+    #[structopt(long, parse(from_os_str))]
+    hmm: PathBuf,
+
+    /// Base directory for the output files.
+    #[structopt(long, default_value = "data/posteriors")]
+    out_dir: String,
+
+    /// Write NPY files instead of CSV.
+    #[structopt(long)]
+    npy: bool,
+
+    /// Frames with state entropy (in bits) above this value
+    /// are counted as uncertain in the summary.
+    #[structopt(long, default_value = "1")]
+    uncertain_threshold: f64,
+
+    /// Sequences to decode.
+    /// If directories are included, then all `.seq` under them will be used.
+    #[structopt(required = true, min_values = 1, parse(from_os_str))]
+    sequences: Vec<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct HmmGenerateOpts {
+    /// HMM model (`.hmm` or `.hsmm`).
+    #[structopt(long, parse(from_os_str))]
+    hmm: PathBuf,
+
+    /// Number of sequences to generate.
+    #[structopt(short = 'n', long, default_value = "10")]
+    num_sequences: usize,
+
+    /// Length of the sequences: `<n>`, `uniform:<min>-<max>` or `normal:<mean>,<sd>`.
+    #[structopt(long, default_value = "100")]
+    length: Length,
+
+    /// Seed for random numbers. Negative means random seed.
+    #[structopt(short = 's', long, default_value = "-1")]
+    seed: i64,
+
+    /// Sequences are saved under `<out-dir>/M<M>/<class>/`.
+    #[structopt(long, default_value = "data/sequences/synthetic")]
+    out_dir: String,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct HmmCompareOpts {
+    /// Number of Monte Carlo sequences generated from each model.
+    #[structopt(short = 'n', long, default_value = "20")]
+    num_sequences: usize,
+
+    /// Length of the generated sequences: `<n>`, `uniform:<min>-<max>` or `normal:<mean>,<sd>`.
+    #[structopt(long, default_value = "200")]
+    length: Length,
+
+    /// Seed for random numbers. Negative means random seed.
+    #[structopt(short = 's', long, default_value = "-1")]
+    seed: i64,
+
+    /// The distance matrix is saved as `<prefix>_distances.csv` and the
+    /// dendrogram as `<prefix>_dendrogram.nwk`.
+    /// By default, `compare` in the directory of the first model.
+    #[structopt(long, name = "prefix")]
+    out_prefix: Option<String>,
+
+    /// HMM models (at least two).
+    /// If directories are included, then all `.hmm` under them will be used.
+    #[structopt(required = true, min_values = 1, parse(from_os_str))]
+    models: Vec<PathBuf>,
 }
 
 pub fn main(opts: HmmMainOpts) {
     let res = match opts.cmd {
         Learn(opts) => main_hmm_learn(opts),
 
+        LearnAll(opts) => main_hmm_learn_all(opts),
+
         Classify(opts) => main_hmm_classify(opts),
 
         Show(opts) => main_hmm_show(opts),
+
+        Decode(opts) => main_hmm_decode(opts),
+
+        Generate(opts) => main_hmm_generate(opts),
+
+        Compare(opts) => main_hmm_compare(opts),
     };
 
     if let Err(err) = res {
@@ -179,10 +499,68 @@ pub fn main_hmm_learn(opts: HmmLearnOpts) -> Result<(), Box<dyn Error>> {
         val_auto,
         seed,
         ser,
+        zrs,
+        precision,
+        curve,
+        checkpoint_every,
+        resume,
+        restarts,
+        topology,
+        continuous,
+        hsmm,
+        durations,
+        max_duration,
+        mixtures,
+        cepstrum_order,
+        predictors,
+        predictors_dir_template,
+        heldout,
         sequences,
         class_name,
     } = opts;
 
+    if continuous && hsmm {
+        return Err("--continuous and --hsmm are mutually exclusive".into());
+    }
+
+    // for the Rust implementations
+    let params = || {
+        learn_params(
+            num_states,
+            type_,
+            epsilon,
+            val_auto,
+            max_iterations,
+            seed,
+            topology.as_deref(),
+            precision.unwrap_or(Precision::Scaled),
+        )
+        .map(|params| learn::HmmLearnParams {
+            parallel: !ser,
+            ..params
+        })
+    };
+
+    if continuous {
+        if resume || restarts > 1 || curve.is_some() || precision.is_some() {
+            return Err(
+                "--resume, --restarts, --curve and --precision not supported with --continuous"
+                    .into(),
+            );
+        }
+        let params = params()?;
+        return learn_continuous(
+            params,
+            mixtures,
+            cepstrum_order,
+            &predictors,
+            predictors_dir_template,
+            class_name,
+        );
+    }
+
+    let codebook_size = codebook_size.ok_or("-M is required")?;
+
     let seq_filenames = utl::resolve_files(
         sequences,
         "TRAIN",
@@ -191,6 +569,86 @@ pub fn main_hmm_learn(opts: HmmLearnOpts) -> Result<(), Box<dyn Error>> {
         ".seq",
     )?;
 
+    if hsmm {
+        if resume || restarts > 1 || precision.is_some() {
+            return Err("--resume, --restarts and --precision not supported with --hsmm".into());
+        }
+        let params = params()?;
+        println!("sequences: {}", seq_filenames.len());
+        println!("{:?}", params);
+
+        let (model, log_prob, learning_curve) = hsmm::learn(
+            codebook_size,
+            &seq_filenames,
+            &params,
+            durations,
+            max_duration,
+        )?;
+
+        let mut dir = format!(
+            "data/hsmms/N{}__M{}_t{}__{}_D{}",
+            num_states, codebook_size, type_, durations, max_duration
+        );
+        if max_iterations >= 0 {
+            dir.push_str(&format!("_I{}", max_iterations));
+        }
+        std::fs::create_dir_all(&dir)?;
+        let model_filename = format!("{}/{}{}", dir, model.class_name, hsmm::EXTENSION);
+        model.save(&model_filename)?;
+        let curve_prefix = curve.unwrap_or_else(|| format!("{}/{}_curve", dir, model.class_name));
+        learning_curve.save(&curve_prefix)?;
+        println!("log P = {}\n{} saved", log_prob, model_filename);
+        return Ok(());
+    }
+
+    if zrs {
+        let params = params()?;
+        println!("sequences: {}", seq_filenames.len());
+        println!("{:?}", params);
+
+        let hmm_dir = hmm_dir(num_states, codebook_size, type_, val_auto, max_iterations);
+        std::fs::create_dir_all(&hmm_dir)?;
+        let output = learn::TrainingOutput {
+            dir: hmm_dir,
+            curve_prefix: curve,
+            checkpoint_every,
+            resume,
+        };
+
+        let (hmm, log_prob) = if restarts > 1 {
+            if resume {
+                return Err("--resume not supported with --restarts".into());
+            }
+            let heldout_filenames = utl::resolve_filenames(heldout, ".seq", "")?;
+            learn::learn_restarts(
+                codebook_size,
+                &seq_filenames,
+                &heldout_filenames,
+                &params,
+                restarts,
+                &output,
+            )?
+        } else {
+            learn::learn(codebook_size, &seq_filenames, &params, Some(&output))?
+        };
+
+        let hmm_filename = format!("{}/{}.hmm", output.dir, hmm.class_name);
+        hmm.save(&hmm_filename)?;
+        println!("log P = {}\n{} saved", log_prob, hmm_filename);
+
+        let checkpoint = output.checkpoint(&hmm.class_name);
+        if std::path::Path::new(&checkpoint).exists() {
+            std::fs::remove_file(checkpoint)?;
+        }
+        return Ok(());
+    }
+
+    if resume || restarts > 1 || topology.is_some() || precision.is_some() {
+        return Err(
+            "--resume, --restarts, --topology and --precision only supported with --zrs".into(),
+        );
+    }
+
     println!("ECOZ2 C version: {}", version()?);
 
     println!("sequences: {}", seq_filenames.len());
@@ -198,8 +656,24 @@ pub fn main_hmm_learn(opts: HmmLearnOpts) -> Result<(), Box<dyn Error>> {
 
     set_random_seed(seed);
 
-    fn callback(_var: &str, _val: f64) {
-        //println!("rust callback called var={} val={}", var, val);
+    // only one ongoing training here, as required by hmm_learn
+    static CALLBACK_RECORDS: Mutex<Vec<curve::CallbackRecord>> = Mutex::new(Vec::new());
+    static START: Mutex<Option<Instant>> = Mutex::new(None);
+    *START.lock().unwrap() = Some(Instant::now());
+
+    fn callback(var: &str, val: f64) {
+        let elapsed_secs = START
+            .lock()
+            .unwrap()
+            .map_or(0.0, |start| start.elapsed().as_secs_f64());
+        CALLBACK_RECORDS
+            .lock()
+            .unwrap()
+            .push(curve::CallbackRecord {
+                variable: var.to_string(),
+                value: val,
+                elapsed_secs,
+            });
     }
 
     hmm_learn(
@@ -213,6 +687,207 @@ pub fn main_hmm_learn(opts: HmmLearnOpts) -> Result<(), Box<dyn Error>> {
         callback,
     );
 
+    if let Some(prefix) = curve {
+        let records = std::mem::take(&mut *CALLBACK_RECORDS.lock().unwrap());
+        let learning_curve = curve::LearningCurve::from_callback_records(&records);
+        if learning_curve.points.is_empty() {
+            println!(
+                "{}",
+                format!(
+                    "WARN: no '{}' values reported via the callback",
+                    curve::LOG_PROB_VARIABLE
+                )
+                .yellow()
+            );
+        }
+        learning_curve.save(&prefix)?;
+        println!(
+            "learning curve ({} points) saved: {}.{{json,csv}}",
+            learning_curve.points.len(),
+            prefix
+        );
+    }
+
+    Ok(())
+}
+
+/// Training parameters for the Rust implementations (parallel and verbose).
+#[allow(clippy::too_many_arguments)]
+fn learn_params(
+    num_states: usize,
+    type_: usize,
+    epsilon: f64,
+    val_auto: f64,
+    max_iterations: i32,
+    seed: i64,
+    topology: Option<&str>,
+    precision: Precision,
+) -> Result<learn::HmmLearnParams, Box<dyn Error>> {
+    let mask = match topology {
+        Some(spec) => Some(topology::TransitionMask::parse(spec, num_states)?),
+        None => None,
+    };
+    Ok(learn::HmmLearnParams {
+        num_states,
+        type_,
+        epsilon,
+        val_auto,
+        max_iterations,
+        seed: set_random_seed(seed),
+        parallel: true,
+        verbose: true,
+        precision,
+        mask,
+    })
+}
+
+/// Directory for the models trained with the given parameters.
+fn hmm_dir(
+    num_states: usize,
+    codebook_size: usize,
+    type_: usize,
+    val_auto: f64,
+    max_iterations: i32,
+) -> String {
+    let mut dir = format!(
+        "data/hmms/N{}__M{}_t{}__a{}",
+        num_states, codebook_size, type_, val_auto
+    );
+    if max_iterations >= 0 {
+        dir.push_str(&format!("_I{}", max_iterations));
+    }
+    dir
+}
+
+pub fn main_hmm_learn_all(opts: HmmLearnAllOpts) -> Result<(), Box<dyn Error>> {
+    let HmmLearnAllOpts {
+        num_states,
+        codebook_size,
+        type_,
+        max_iterations,
+        epsilon,
+        val_auto,
+        seed,
+        topology,
+        precision,
+        threads,
+        tt_list,
+    } = opts;
+
+    let params = learn::HmmLearnParams {
+        parallel: false,
+        verbose: false,
+        ..learn_params(
+            num_states,
+            type_,
+            epsilon,
+            val_auto,
+            max_iterations,
+            seed,
+            topology.as_deref(),
+            precision,
+        )?
+    };
+    println!("{:?}", params);
+
+    let groups = learn_all::get_class_files_from_csv(
+        &tt_list,
+        "TRAIN",
+        &format!("sequences/M{}", codebook_size),
+        ".seq",
+    )?;
+
+    let dir = hmm_dir(num_states, codebook_size, type_, val_auto, max_iterations);
+    std::fs::create_dir_all(&dir)?;
+    let output = learn::TrainingOutput {
+        dir,
+        curve_prefix: None,
+        checkpoint_every: 0,
+        resume: false,
+    };
+
+    let threads = if threads == 0 {
+        num_cpus::get()
+    } else {
+        threads
+    };
+    learn_all::learn_all(&groups, threads, |_, seq_filenames| {
+        let (hmm, log_prob) = learn::learn(codebook_size, seq_filenames, &params, Some(&output))?;
+        let hmm_filename = format!("{}/{}.hmm", output.dir, hmm.class_name);
+        hmm.save(&hmm_filename)?;
+        Ok(format!("log P = {:.2}  {}", log_prob, hmm_filename))
+    })?;
+    Ok(())
+}
+
+fn learn_continuous(
+    params: learn::HmmLearnParams,
+    num_mixtures: usize,
+    cepstrum_order: usize,
+    predictors: &[PathBuf],
+    predictors_dir_template: String,
+    class_name: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let prd_filenames = utl::resolve_files3(
+        predictors,
+        "TRAIN",
+        &class_name,
+        "".to_string(),
+        predictors_dir_template,
+        ".prd",
+    )?;
+    if prd_filenames.is_empty() {
+        return Err("No training predictors".into());
+    }
+
+    let mut resolved_class_name = class_name;
+    let mut cepstrum_order = cepstrum_order;
+    let mut sequences = Vec::new();
+    for prd_filename in &prd_filenames {
+        let filename = prd_filename.to_str().unwrap();
+        let predictor = prd::load(filename)?;
+        let predictor_class_name = predictor.resolved_class_name(prd_filename);
+        match &resolved_class_name {
+            None => resolved_class_name = Some(predictor_class_name),
+            Some(class_name) if *class_name != predictor_class_name => {
+                return Err(format!(
+                    "{}: conformity error: class_name: {} != {}",
+                    filename, class_name, predictor_class_name
+                )
+                .into());
+            }
+            _ => {}
+        }
+        if cepstrum_order == 0 {
+            cepstrum_order = predictor.prediction_order + 1;
+        }
+        sequences.push(continuous::features(&predictor, cepstrum_order)?);
+    }
+    let class_name = resolved_class_name.unwrap();
+
+    println!("predictors: {}", prd_filenames.len());
+    println!("{:?}", params);
+
+    let (model, log_prob, curve) = continuous::learn(
+        class_name,
+        cepstrum_order,
+        num_mixtures,
+        &sequences,
+        &params,
+    )?;
+
+    let mut dir = format!(
+        "data/chmms/N{}__K{}__Q{}",
+        params.num_states, num_mixtures, cepstrum_order
+    );
+    if params.max_iterations >= 0 {
+        dir.push_str(&format!("_I{}", params.max_iterations));
+    }
+    std::fs::create_dir_all(&dir)?;
+    let model_filename = format!("{}/{}{}", dir, model.class_name, continuous::EXTENSION);
+    model.save(&model_filename)?;
+    curve.save(&format!("{}/{}_curve", dir, model.class_name))?;
+    println!("log P = {}\n{} saved", log_prob, model_filename);
     Ok(())
 }
 
@@ -220,9 +895,14 @@ pub fn main_hmm_classify(opts: HmmClassifyOpts) -> Result<(), Box<dyn Error>> {
     let HmmClassifyOpts {
         show_ranked,
         classification_filename,
+        zrs,
+        soft,
+        temperature,
+        precision,
         models,
         tt,
         class_name,
+        hsmm,
         sequences,
         codebook_size,
         predictors,
@@ -232,17 +912,86 @@ pub fn main_hmm_classify(opts: HmmClassifyOpts) -> Result<(), Box<dyn Error>> {
 
     assert_ne!(predictors.is_empty(), sequences.is_empty());
 
+    if precision.is_some() && (hsmm || (!predictors.is_empty() && codebooks.is_empty())) {
+        return Err(
+            "--precision does not apply to continuous HMMs (scaled) or HSMMs (log-space)".into(),
+        );
+    }
+
+    if !predictors.is_empty() && codebooks.is_empty() {
+        let model_filenames = utl::resolve_filenames(models, continuous::EXTENSION, "models")?;
+        let prd_filenames = utl::resolve_files3(
+            &predictors,
+            tt.as_str(),
+            &class_name,
+            "".to_string(),
+            predictors_dir_template,
+            ".prd",
+        )?;
+        println!(
+            "number of continuous HMM models: {}  number of predictors: {}",
+            model_filenames.len(),
+            prd_filenames.len()
+        );
+        return continuous::classify(model_filenames, prd_filenames, show_ranked);
+    }
+
+    if hsmm {
+        if sequences.is_empty() {
+            return Err("--hsmm requires --sequences".into());
+        }
+        let codebook_size = codebook_size.ok_or("-M is required")?;
+        let model_filenames = utl::resolve_filenames(models, hsmm::EXTENSION, "models")?;
+        let seq_filenames = utl::resolve_files(
+            sequences,
+            tt.as_str(),
+            class_name,
+            format!("sequences/M{}", codebook_size),
+            ".seq",
+        )?;
+        println!(
+            "number of HSMM models: {}  number of sequences: {}",
+            model_filenames.len(),
+            seq_filenames.len()
+        );
+        return hsmm::classify(model_filenames, seq_filenames, show_ranked);
+    }
+
     let hmm_filenames = utl::resolve_filenames(models, ".hmm", "models")?;
 
+    if soft && !(zrs && !sequences.is_empty()) {
+        return Err("--soft requires --zrs and --sequences".into());
+    }
+    if precision.is_some() && !zrs {
+        return Err("--precision requires --zrs".into());
+    }
+
     if !sequences.is_empty() {
+        let codebook_size = codebook_size.ok_or("-M is required")?;
         let seq_filenames = utl::resolve_files(
             sequences,
             tt.as_str(),
             class_name,
             format!("sequences/M{}", codebook_size),
-            ".seq",
+            if soft { ".sseq" } else { ".seq" },
         )?;
 
+        if zrs {
+            println!(
+                "number of HMM models: {}  number of sequences: {}",
+                hmm_filenames.len(),
+                seq_filenames.len()
+            );
+            return classify::classify(
+                hmm_filenames,
+                seq_filenames,
+                show_ranked,
+                if soft { Some(temperature) } else { None },
+                precision,
+                classification_filename,
+            );
+        }
+
         println!("ECOZ2 C version: {}", version()?);
 
         println!(
@@ -283,9 +1032,194 @@ pub fn main_hmm_classify(opts: HmmClassifyOpts) -> Result<(), Box<dyn Error>> {
 }
 
 pub fn main_hmm_show(opts: HmmShowOpts) -> Result<(), Box<dyn Error>> {
-    let HmmShowOpts { hmm, format } = opts;
+    let HmmShowOpts {
+        hmm,
+        format,
+        zrs,
+        json,
+        csv,
+        npy,
+        dot,
+        threshold,
+        top_symbols,
+        heatmap,
+    } = opts;
+
+    let filename = hmm.to_str().unwrap();
+    if (filename.ends_with(continuous::EXTENSION) || filename.ends_with(hsmm::EXTENSION))
+        && (json.is_some() || csv.is_some() || npy.is_some() || dot.is_some() || heatmap.is_some())
+    {
+        return Err(
+            "--json, --csv, --npy, --dot and --heatmap only supported with .hmm models".into(),
+        );
+    }
+    if filename.ends_with(continuous::EXTENSION) {
+        continuous::load(filename)?.show();
+        return Ok(());
+    }
+    if filename.ends_with(hsmm::EXTENSION) {
+        hsmm::load(filename)?.show();
+        return Ok(());
+    }
+
+    if dot.is_some() || heatmap.is_some() {
+        let model = model::load(hmm.to_str().unwrap())?;
+        if let Some(dot) = dot {
+            let labels: Vec<String> = (0..model.n)
+                .map(|i| {
+                    let mut lines = vec![format!("s{}", i)];
+                    lines.extend(utl::dot::dominant_symbols(model.b.row(i), top_symbols));
+                    lines.join("\n")
+                })
+                .collect();
+            let graph = utl::dot::transitions_dot(
+                &model.class_name,
+                &labels,
+                model.pi.view(),
+                model.a.view(),
+                threshold,
+                true,
+            );
+            utl::dot::save_dot(&graph, &dot)?;
+        }
+        if let Some(heatmap) = heatmap {
+            utl::dot::save_heatmap_csv(model.a.view(), &heatmap)?;
+        }
+        return Ok(());
+    }
+
+    if zrs || json.is_some() || csv.is_some() || npy.is_some() {
+        let model = model::load(hmm.to_str().unwrap())?;
+        if json.is_some() || csv.is_some() || npy.is_some() {
+            model.validate();
+            return model.export(json, csv, npy);
+        }
+        model.show();
+        return Ok(());
+    }
 
     hmm_show(hmm, format);
 
     Ok(())
 }
+
+pub fn main_hmm_decode(opts: HmmDecodeOpts) -> Result<(), Box<dyn Error>> {
+    let HmmDecodeOpts {
+        hmm,
+        out_dir,
+        npy,
+        uncertain_threshold,
+        sequences,
+    } = opts;
+
+    let model = model::load(hmm.to_str().unwrap())?;
+    let seq_filenames = utl::resolve_filenames(sequences, ".seq", "sequences")?;
+    decode::decode(&model, &seq_filenames, &out_dir, npy, uncertain_threshold)
+}
+
+pub fn main_hmm_generate(opts: HmmGenerateOpts) -> Result<(), Box<dyn Error>> {
+    let HmmGenerateOpts {
+        hmm,
+        num_sequences,
+        length,
+        seed,
+        out_dir,
+    } = opts;
+
+    let mut rng = StdRng::seed_from_u64(set_random_seed(seed));
+    if hmm.to_str().unwrap().ends_with(hsmm::EXTENSION) {
+        let model = hsmm::load(hmm.to_str().unwrap())?;
+        return generate::save_generated(
+            &model.class_name,
+            model.m,
+            num_sequences,
+            &out_dir,
+            || model.generate(&mut rng, &length),
+        );
+    }
+    let model = model::load(hmm.to_str().unwrap())?;
+    generate::save_generated(&model.class_name, model.m, num_sequences, &out_dir, || {
+        model.generate(&mut rng, &length)
+    })
+}
+
+pub fn main_hmm_compare(opts: HmmCompareOpts) -> Result<(), Box<dyn Error>> {
+    let HmmCompareOpts {
+        num_sequences,
+        length,
+        seed,
+        out_prefix,
+        models,
+    } = opts;
+
+    let hmm_filenames = utl::resolve_filenames(models, ".hmm", "models")?;
+    if hmm_filenames.len() < 2 {
+        return Err("At least two models are required".into());
+    }
+    let hmms = hmm_filenames
+        .iter()
+        .map(|f| model::load(f.to_str().unwrap()))
+        .collect::<Result<Vec<_>, _>>()?;
+    for (hmm, filename) in hmms.iter().zip(&hmm_filenames) {
+        if hmm.m != hmms[0].m {
+            return Err(format!(
+                "conformity error: {}: codebook size {} (expecting {})",
+                filename.display(),
+                hmm.m,
+                hmms[0].m
+            )
+            .into());
+        }
+    }
+    // model names: the class names, or the file names if these are not unique
+    let mut names: Vec<String> = hmms.iter().map(|h| h.class_name.clone()).collect();
+    let mut unique = names.clone();
+    unique.sort();
+    unique.dedup();
+    if unique.len() < names.len() {
+        names = hmm_filenames
+            .iter()
+            .map(|f| f.with_extension("").to_str().unwrap().to_string())
+            .collect();
+    }
+
+    println!(
+        "{} models, {} sequences (length {:?}) per model",
+        hmms.len(),
+        num_sequences,
+        length
+    );
+    let distances = compare::distances(&hmms, num_sequences, &length, set_random_seed(seed));
+
+    let mut pairs: Vec<(usize, usize)> = (0..hmms.len())
+        .flat_map(|i| (i + 1..hmms.len()).map(move |j| (i, j)))
+        .collect();
+    pairs.sort_by(|&(a, b), &(c, d)| distances[[a, b]].total_cmp(&distances[[c, d]]));
+    println!("\nclosest pairs:");
+    for &(i, j) in pairs.iter().take(10) {
+        println!("  {:>12.6}  {} - {}", distances[[i, j]], names[i], names[j]);
+    }
+
+    let out_prefix = out_prefix.unwrap_or_else(|| {
+        let dir = hmm_filenames[0].parent().unwrap().to_str().unwrap();
+        format!("{}/compare", if dir.is_empty() { "." } else { dir })
+    });
+    let csv_filename = format!("{}_distances.csv", out_prefix);
+    compare::save_csv(&names, &distances, &csv_filename)?;
+    let (_, num_infinite) = compare::clamp_infinite(&distances);
+    if num_infinite > 0 {
+        println!(
+            "{}",
+            format!(
+                "WARN: {} pair(s) with infinite distance (zero probability), \
+                 shown in the dendrogram with twice the largest finite distance",
+                num_infinite
+            )
+            .yellow()
+        );
+    }
+    let newick_filename = format!("{}_dendrogram.nwk", out_prefix);
+    std::fs::write(&newick_filename, compare::newick(&names, &distances) + "\n")?;
+    println!("\n{} saved\n{} saved", csv_filename, newick_filename);
+    Ok(())
+}
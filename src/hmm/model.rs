@@ -0,0 +1,619 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use ndarray::prelude::*;
+use rand::rngs::StdRng;
+
+use crate::sequence::generate::{sample_index, Length};
+use crate::utl;
+
+const HMM_IDENT: &str = "<hmm>";
+
+const PRECISION_IDENT: &str = "<precision>";
+
+const EQ_EPSILON: f64 = 1e-5;
+
+/// A discrete HMM, with the same contents and file format as in the C implementation:
+///
+/// ```text
+///   ident      : "<hmm>"  (16 bytes, \0 padded)
+///   class_name : (96 bytes, \0 padded)
+///   N          : u32
+///   M          : u32
+///   pi         : N f64
+///   A          : N x N f64 (by rows)
+///   B          : N x M f64 (by rows)
+/// ```
+///
+/// All numbers in little endian.
+/// Models trained by the Rust implementation are followed by a trailer,
+/// not read by the C implementation, recording the precision of the training:
+///
+/// ```text
+///   ident      : "<precision>"  (16 bytes, \0 padded)
+///   precision  : "scaled" or "log-space"  (16 bytes, \0 padded)
+/// ```
+#[derive(Debug, Clone)]
+pub struct Hmm {
+    pub class_name: String,
+
+    /// Number of states.
+    pub n: usize,
+
+    /// Number of symbols.
+    pub m: usize,
+
+    /// Initial state distribution.
+    pub pi: Array1<f64>,
+
+    /// State transition probabilities.
+    pub a: Array2<f64>,
+
+    /// Symbol emission probabilities for each state.
+    pub b: Array2<f64>,
+
+    /// Precision used in the training, if recorded.
+    pub precision: Option<Precision>,
+}
+
+/// Representation of the probabilities in the forward-backward procedure,
+/// chosen at runtime. Both give the same results up to rounding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Precision {
+    /// Probabilities normalized at each time step (see `forward_scaled`).
+    Scaled,
+
+    /// Log probabilities combined with log-sum-exp (see `forward_log`).
+    LogSpace,
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scaled" => Ok(Precision::Scaled),
+            "log-space" => Ok(Precision::LogSpace),
+            _ => Err(format!(
+                "invalid precision: {} (expecting scaled or log-space)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Precision::Scaled => "scaled",
+            Precision::LogSpace => "log-space",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Hmm {
+    pub fn show(&self) {
+        println!(
+            "class_name='{}', N={}, M={}",
+            self.class_name, self.n, self.m
+        );
+        if let Some(precision) = self.precision {
+            println!("precision = {}", precision);
+        }
+        println!("pi = {}", self.pi);
+        println!("A =");
+        for (i, a_row) in self.a.axis_iter(Axis(0)).enumerate() {
+            println!(" [{}]: {}", i, a_row);
+        }
+        println!("B =");
+        for (i, b_row) in self.b.axis_iter(Axis(0)).enumerate() {
+            println!(" [{}]: {}", i, b_row);
+        }
+        self.validate();
+    }
+
+    /// Asserts that pi, and the rows of A and B, are stochastic.
+    pub fn validate(&self) {
+        assert_stochastic(self.pi.view());
+        for a_row in self.a.axis_iter(Axis(0)) {
+            assert_stochastic(a_row);
+        }
+        for b_row in self.b.axis_iter(Axis(0)) {
+            assert_stochastic(b_row);
+        }
+    }
+
+    /// Exports the model to any of the given files.
+    /// JSON includes all the model (to stdout if its filename is `-`);
+    /// for CSV and NPY, the given prefix is used for the
+    /// `<prefix>_pi`, `<prefix>_A` and `<prefix>_B` files.
+    pub fn export(
+        &self,
+        json: Option<PathBuf>,
+        csv: Option<String>,
+        npy: Option<String>,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(json_filename) = json {
+            let export = HmmExport {
+                class_name: &self.class_name,
+                num_states: self.n,
+                codebook_size: self.m,
+                pi: self.pi.to_vec(),
+                a: to_rows(&self.a),
+                b: to_rows(&self.b),
+            };
+            if json_filename.to_str() == Some("-") {
+                println!("{}", serde_json::to_string_pretty(&export)?);
+            } else {
+                utl::save_json(&export, json_filename.to_str().unwrap())?;
+                println!("model exported to {:?}", json_filename);
+            }
+        }
+
+        let matrices = [
+            ("pi", "", vec![self.pi.to_vec()]),
+            ("A", "s", to_rows(&self.a)),
+            ("B", "o", to_rows(&self.b)),
+        ];
+
+        if let Some(prefix) = csv {
+            for (name, col_prefix, rows) in &matrices {
+                let filename = PathBuf::from(format!("{}_{}.csv", prefix, name));
+                let header: Vec<String> = (0..rows[0].len())
+                    .map(|j| format!("{}{}", col_prefix, j))
+                    .collect();
+                utl::save_csv(&header, rows, &filename)?;
+                println!("{} saved to {:?}", name, filename);
+            }
+        }
+
+        if let Some(prefix) = npy {
+            for (name, _, rows) in &matrices {
+                let filename = PathBuf::from(format!("{}_{}.npy", prefix, name));
+                utl::save_npy(rows, &filename)?;
+                println!("{} saved to {:?}", name, filename);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets the entries of B less than `epsilon` to `epsilon`, taking the
+    /// added mass proportionally from the other entries in the row.
+    /// Nothing is done if `epsilon` is 0 or not less than `1/M`.
+    pub fn apply_epsilon(&mut self, epsilon: f64) {
+        apply_epsilon(&mut self.b, epsilon);
+    }
+
+    /// Emission probabilities `b_j(o_t)` (T x N) of the given sequence.
+    pub fn emissions(&self, symbols: &[u16]) -> Array2<f64> {
+        Array2::from_shape_fn((symbols.len(), self.n), |(t, j)| {
+            self.b[[j, symbols[t] as usize]]
+        })
+    }
+
+    /// Scaled forward procedure (see `forward_scaled`).
+    pub fn forward(&self, symbols: &[u16]) -> Option<(Array2<f64>, Vec<f64>)> {
+        forward_scaled(&self.pi, &self.a, &self.emissions(symbols))
+    }
+
+    /// Scaled backward procedure with the scale factors from `forward`.
+    pub fn backward(&self, symbols: &[u16], scales: &[f64]) -> Array2<f64> {
+        backward_scaled(&self.a, &self.emissions(symbols), scales)
+    }
+
+    /// State posteriors `gamma[t][i] = P(q_t = i | O, λ)` (T x N)
+    /// via the scaled forward-backward procedure,
+    /// or `None` if the sequence is empty or has zero probability.
+    pub fn posteriors(&self, symbols: &[u16]) -> Option<Array2<f64>> {
+        let (alpha, scales) = self.forward(symbols)?;
+        let beta = self.backward(symbols, &scales);
+        let mut gamma = alpha * beta;
+        for (mut row, c) in gamma.axis_iter_mut(Axis(0)).zip(scales) {
+            row /= c;
+        }
+        Some(gamma)
+    }
+
+    /// Log probability (natural log) of the given soft observations
+    /// (see `SoftSequence::observations`), with emission probabilities
+    /// `sum_k w_k b_j(s_k)`; `-inf` if zero.
+    pub fn log_prob_soft(&self, observations: &[Vec<(usize, f64)>], precision: Precision) -> f64 {
+        let emissions = Array2::from_shape_fn((observations.len(), self.n), |(t, j)| {
+            observations[t]
+                .iter()
+                .map(|&(symbol, w)| w * self.b[[j, symbol]])
+                .sum()
+        });
+        self.log_prob_emissions(&emissions, precision)
+    }
+
+    /// Log probability (natural log) of the given sequence,
+    /// `-inf` if the sequence has zero probability.
+    pub fn log_prob(&self, symbols: &[u16]) -> f64 {
+        self.log_prob_with(symbols, Precision::Scaled)
+    }
+
+    /// `log_prob` computed with the given precision.
+    pub fn log_prob_with(&self, symbols: &[u16], precision: Precision) -> f64 {
+        self.log_prob_emissions(&self.emissions(symbols), precision)
+    }
+
+    fn log_prob_emissions(&self, emissions: &Array2<f64>, precision: Precision) -> f64 {
+        let log_prob = match precision {
+            Precision::Scaled => forward_scaled(&self.pi, &self.a, emissions)
+                .map(|(_, scales)| -scales.iter().map(|c| c.ln()).sum::<f64>()),
+            Precision::LogSpace => forward_log(&self.pi, &self.a, &emissions.mapv(f64::ln))
+                .map(|(_, log_prob)| log_prob),
+        };
+        log_prob.unwrap_or(f64::NEG_INFINITY)
+    }
+
+    /// Viterbi algorithm (in the log domain).
+    /// Returns the most likely state sequence and its log probability,
+    /// or `None` if the sequence has zero probability.
+    pub fn viterbi(&self, symbols: &[u16]) -> Option<(Vec<usize>, f64)> {
+        let n = self.n;
+        let t_len = symbols.len();
+        if t_len == 0 {
+            return None;
+        }
+        let log_a = self.a.mapv(f64::ln);
+        let log_b = |j: usize, t: usize| self.b[[j, symbols[t] as usize]].ln();
+
+        let mut delta: Vec<f64> = (0..n).map(|j| self.pi[j].ln() + log_b(j, 0)).collect();
+        let mut psi = Array2::<usize>::zeros((t_len, n));
+        for t in 1..t_len {
+            let mut next = vec![f64::NEG_INFINITY; n];
+            for (j, next_j) in next.iter_mut().enumerate() {
+                let (best_i, best) = (0..n).map(|i| (i, delta[i] + log_a[[i, j]])).fold(
+                    (0, f64::NEG_INFINITY),
+                    |acc, x| if x.1 > acc.1 { x } else { acc },
+                );
+                psi[[t, j]] = best_i;
+                *next_j = best + log_b(j, t);
+            }
+            delta = next;
+        }
+
+        let (last, log_prob) =
+            delta
+                .iter()
+                .copied()
+                .enumerate()
+                .fold(
+                    (0, f64::NEG_INFINITY),
+                    |acc, x| if x.1 > acc.1 { x } else { acc },
+                );
+        if log_prob == f64::NEG_INFINITY {
+            return None;
+        }
+        let mut states = vec![last; t_len];
+        for t in (1..t_len).rev() {
+            states[t - 1] = psi[[t, states[t]]];
+        }
+        Some((states, log_prob))
+    }
+
+    /// Generates a symbol sequence from this model.
+    pub fn generate(&self, rng: &mut StdRng, length: &Length) -> Vec<u16> {
+        let len = length.sample(rng);
+        let mut symbols = Vec::with_capacity(len);
+        let mut state = sample_index(rng, self.pi.iter().copied());
+        for t in 0..len {
+            if t > 0 {
+                state = sample_index(rng, self.a.row(state).iter().copied());
+            }
+            symbols.push(sample_index(rng, self.b.row(state).iter().copied()) as u16);
+        }
+        symbols
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        let f = File::create(filename)?;
+        let mut bw = BufWriter::new(f);
+
+        utl::write_file_ident(&mut bw, HMM_IDENT)?;
+        utl::write_class_name(&mut bw, &self.class_name)?;
+        utl::write_u32(&mut bw, self.n as u32)?;
+        utl::write_u32(&mut bw, self.m as u32)?;
+        for v in self.pi.iter().chain(self.a.iter()).chain(self.b.iter()) {
+            utl::write_f64(&mut bw, *v)?;
+        }
+        if let Some(precision) = self.precision {
+            utl::write_file_ident(&mut bw, PRECISION_IDENT)?;
+            utl::write_file_ident(&mut bw, &precision.to_string())?;
+        }
+        bw.flush()?;
+        Ok(())
+    }
+}
+
+/// Scaled forward procedure given the emission probabilities `emissions[t][j]`
+/// (T x N) of a sequence.
+/// Returns the normalized alphas (T x N) and the scale factors `c_t`
+/// such that `log P(O) = -sum_t log c_t`, or `None` if the sequence
+/// is empty or has zero probability.
+pub fn forward_scaled(
+    pi: &Array1<f64>,
+    a: &Array2<f64>,
+    emissions: &Array2<f64>,
+) -> Option<(Array2<f64>, Vec<f64>)> {
+    let (t_len, n) = emissions.dim();
+    if t_len == 0 {
+        return None;
+    }
+    let mut alpha = Array2::zeros((t_len, n));
+    let mut scales = Vec::with_capacity(t_len);
+    for t in 0..t_len {
+        for j in 0..n {
+            let prev = if t == 0 {
+                pi[j]
+            } else {
+                (0..n).map(|i| alpha[[t - 1, i]] * a[[i, j]]).sum()
+            };
+            alpha[[t, j]] = prev * emissions[[t, j]];
+        }
+        let sum = alpha.row(t).sum();
+        if sum <= 0.0 || !sum.is_finite() {
+            return None;
+        }
+        let c = 1.0 / sum;
+        alpha.row_mut(t).mapv_inplace(|v| v * c);
+        scales.push(c);
+    }
+    Some((alpha, scales))
+}
+
+/// Forward procedure in the log domain given the log emission probabilities
+/// `log_emissions[t][j]` (T x N) of a sequence.
+/// Returns `log alpha` (T x N) and `log P(O)`, or `None` if the sequence
+/// is empty or has zero probability.
+pub fn forward_log(
+    pi: &Array1<f64>,
+    a: &Array2<f64>,
+    log_emissions: &Array2<f64>,
+) -> Option<(Array2<f64>, f64)> {
+    let (t_len, n) = log_emissions.dim();
+    let log_a = a.mapv(f64::ln);
+    let mut log_alpha = Array2::zeros((t_len, n));
+    for t in 0..t_len {
+        for j in 0..n {
+            let prev = if t == 0 {
+                pi[j].ln()
+            } else {
+                log_sum_exp((0..n).map(|i| log_alpha[[t - 1, i]] + log_a[[i, j]]))
+            };
+            log_alpha[[t, j]] = prev + log_emissions[[t, j]];
+        }
+    }
+    let log_prob = log_sum_exp(log_alpha.row(t_len.checked_sub(1)?).iter().copied());
+    if !log_prob.is_finite() {
+        return None;
+    }
+    Some((log_alpha, log_prob))
+}
+
+/// Backward procedure in the log domain: `log beta` (T x N; empty if T = 0).
+pub fn backward_log(a: &Array2<f64>, log_emissions: &Array2<f64>) -> Array2<f64> {
+    let (t_len, n) = log_emissions.dim();
+    let log_a = a.mapv(f64::ln);
+    let mut log_beta = Array2::zeros((t_len, n));
+    for t in (0..t_len.saturating_sub(1)).rev() {
+        for i in 0..n {
+            log_beta[[t, i]] = log_sum_exp(
+                (0..n).map(|j| log_a[[i, j]] + log_emissions[[t + 1, j]] + log_beta[[t + 1, j]]),
+            );
+        }
+    }
+    log_beta
+}
+
+/// `log(sum(exp(v)))` of the given values, `-inf` if all are `-inf`.
+pub fn log_sum_exp(values: impl Iterator<Item = f64>) -> f64 {
+    let values: Vec<f64> = values.collect();
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if max == f64::NEG_INFINITY {
+        return max;
+    }
+    max + values.iter().map(|v| (v - max).exp()).sum::<f64>().ln()
+}
+
+/// See `Hmm::apply_epsilon`.
+pub fn apply_epsilon(b: &mut Array2<f64>, epsilon: f64) {
+    if epsilon <= 0.0 || epsilon * b.ncols() as f64 >= 1.0 {
+        return;
+    }
+    for mut b_row in b.axis_iter_mut(Axis(0)) {
+        let num_small = b_row.iter().filter(|&&p| p < epsilon).count();
+        if num_small > 0 {
+            let rest: f64 = b_row.iter().filter(|&&p| p >= epsilon).sum();
+            let factor = (1.0 - num_small as f64 * epsilon) / rest;
+            b_row.mapv_inplace(|p| if p < epsilon { epsilon } else { p * factor });
+        }
+    }
+}
+
+/// Scaled backward procedure with the scale factors from `forward_scaled`
+/// (T x N; empty if T = 0).
+pub fn backward_scaled(a: &Array2<f64>, emissions: &Array2<f64>, scales: &[f64]) -> Array2<f64> {
+    let (t_len, n) = emissions.dim();
+    let mut beta = Array2::zeros((t_len, n));
+    let Some(last) = t_len.checked_sub(1) else {
+        return beta;
+    };
+    beta.row_mut(last).fill(scales[last]);
+    for t in (0..last).rev() {
+        for i in 0..n {
+            let sum: f64 = (0..n)
+                .map(|j| a[[i, j]] * emissions[[t + 1, j]] * beta[[t + 1, j]])
+                .sum();
+            beta[[t, i]] = sum * scales[t];
+        }
+    }
+    beta
+}
+
+#[derive(serde::Serialize)]
+struct HmmExport<'a> {
+    class_name: &'a str,
+    num_states: usize,
+    codebook_size: usize,
+    pi: Vec<f64>,
+    #[serde(rename = "A")]
+    a: Vec<Vec<f64>>,
+    #[serde(rename = "B")]
+    b: Vec<Vec<f64>>,
+}
+
+pub fn load(filename: &str) -> Result<Hmm, Box<dyn Error>> {
+    let f = File::open(filename)?;
+    let mut br = BufReader::new(f);
+
+    let ident = utl::read_file_ident(&mut br)?;
+    if !ident.starts_with(HMM_IDENT) {
+        return Err(format!("{}: Not an HMM", filename).into());
+    }
+
+    let class_name = utl::read_class_name(&mut br)?;
+    let n = utl::read_u32(&mut br)? as usize;
+    let m = utl::read_u32(&mut br)? as usize;
+
+    let mut read_values = |len: usize| -> Result<Vec<f64>, Box<dyn Error>> {
+        (0..len).map(|_| utl::read_f64(&mut br)).collect()
+    };
+    let pi = Array1::from(read_values(n)?);
+    let a = Array2::from_shape_vec((n, n), read_values(n * n)?)?;
+    let b = Array2::from_shape_vec((n, m), read_values(n * m)?)?;
+
+    let precision = if br.fill_buf()?.is_empty() {
+        None
+    } else if utl::read_file_ident(&mut br)? == PRECISION_IDENT {
+        Some(utl::read_file_ident(&mut br)?.parse()?)
+    } else {
+        return Err(format!("{}: unexpected contents after B", filename).into());
+    };
+
+    Ok(Hmm {
+        class_name,
+        n,
+        m,
+        pi,
+        a,
+        b,
+        precision,
+    })
+}
+
+fn assert_stochastic(row: ArrayView1<f64>) {
+    assert!(row.iter().all(|&p| p >= 0f64));
+    assert_approx_eq!(row.sum(), 1_f64, EQ_EPSILON);
+}
+
+fn to_rows(matrix: &Array2<f64>) -> Vec<Vec<f64>> {
+    matrix.axis_iter(Axis(0)).map(|row| row.to_vec()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let hmm = Hmm {
+            class_name: "some_class".to_string(),
+            n: 2,
+            m: 3,
+            pi: array![0.25, 0.75],
+            a: array![[0.9, 0.1], [0.0, 1.0]],
+            b: array![[0.5, 0.25, 0.25], [0.1, 0.2, 0.7]],
+            precision: None,
+        };
+
+        let path = std::env::temp_dir().join("ecoz2_test_save_load.hmm");
+        let filename = path.to_str().unwrap();
+        for precision in [None, Some(Precision::LogSpace)] {
+            let hmm = Hmm {
+                precision,
+                ..hmm.clone()
+            };
+            hmm.save(filename).unwrap();
+            let loaded = load(filename).unwrap();
+
+            assert_eq!(loaded.class_name, hmm.class_name);
+            assert_eq!((loaded.n, loaded.m), (2, 3));
+            assert_eq!(loaded.pi, hmm.pi);
+            assert_eq!(loaded.a, hmm.a);
+            assert_eq!(loaded.b, hmm.b);
+            assert_eq!(loaded.precision, precision);
+            loaded.validate();
+        }
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_viterbi() {
+        let hmm = Hmm {
+            class_name: "c".to_string(),
+            n: 2,
+            m: 2,
+            pi: array![1.0, 0.0],
+            a: array![[0.6, 0.4], [0.0, 1.0]],
+            b: array![[0.9, 0.1], [0.2, 0.8]],
+            precision: None,
+        };
+        let symbols = [0, 0, 1, 1, 1];
+        let (states, log_prob) = hmm.viterbi(&symbols).unwrap();
+        assert_eq!(states, vec![0, 0, 1, 1, 1]);
+        let expected = (0.9 * 0.6 * 0.9 * 0.4 * 0.8 * 0.8 * 0.8_f64).ln();
+        assert_approx_eq!(log_prob, expected, 1e-12);
+        assert!(hmm.log_prob(&symbols) >= log_prob);
+        assert_approx_eq!(
+            hmm.log_prob_with(&symbols, Precision::LogSpace),
+            hmm.log_prob(&symbols),
+            1e-12
+        );
+        assert_eq!(hmm.viterbi(&[]), None);
+
+        let gamma = hmm.posteriors(&symbols).unwrap();
+        for row in gamma.axis_iter(Axis(0)) {
+            assert_approx_eq!(row.sum(), 1.0, 1e-12);
+        }
+        assert_eq!(gamma[[0, 0]], 1.0);
+
+        // empty sequence
+        assert_eq!(hmm.posteriors(&[]), None);
+        assert_eq!(hmm.log_prob(&[]), f64::NEG_INFINITY);
+        assert_eq!(
+            hmm.log_prob_with(&[], Precision::LogSpace),
+            f64::NEG_INFINITY
+        );
+        let empty = Array2::zeros((0, 2));
+        assert_eq!(backward_scaled(&hmm.a, &empty, &[]).dim(), (0, 2));
+        assert_eq!(backward_log(&hmm.a, &empty).dim(), (0, 2));
+
+        // one-hot soft observations are the same as the symbols
+        let hard: Vec<Vec<(usize, f64)>> = symbols
+            .iter()
+            .map(|&s| vec![(s as usize, 1.0), (1 - s as usize, 0.0)])
+            .collect();
+        assert_approx_eq!(
+            hmm.log_prob_soft(&hard, Precision::Scaled),
+            hmm.log_prob(&symbols),
+            1e-12
+        );
+        let soft: Vec<Vec<(usize, f64)>> = symbols
+            .iter()
+            .map(|&s| vec![(s as usize, 0.5), (1 - s as usize, 0.5)])
+            .collect();
+        assert!(hmm.log_prob_soft(&soft, Precision::LogSpace) < hmm.log_prob(&symbols));
+    }
+}
@@ -0,0 +1,184 @@
+use std::error::Error;
+use std::fs;
+
+use ndarray::prelude::*;
+
+/// Allowed state transitions (N x N), enforced in the initialization
+/// and throughout the re-estimation of the HMM.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransitionMask {
+    pub allowed: Array2<bool>,
+}
+
+/// Named topologies for `TransitionMask::parse`.
+pub const NAMES: [&str; 4] = ["ergodic", "left-right", "left-right-skip", "cyclic"];
+
+impl TransitionMask {
+    /// Mask given by the name of a topology or by a file.
+    ///
+    /// Named topologies:
+    /// - `ergodic`: all transitions;
+    /// - `left-right`: to the same and the next state;
+    /// - `left-right-skip`: to the same and the next two states;
+    /// - `cyclic`: as `left-right` but also from the last state to the first one.
+    ///
+    /// A file has N rows with N values (0 or 1) separated by commas or whitespace;
+    /// lines that are empty or start with `#` are ignored.
+    pub fn parse(spec: &str, n: usize) -> Result<TransitionMask, Box<dyn Error>> {
+        let allowed = match spec {
+            "ergodic" => Array2::from_elem((n, n), true),
+            "left-right" => Array2::from_shape_fn((n, n), |(i, j)| j == i || j == i + 1),
+            "left-right-skip" => Array2::from_shape_fn((n, n), |(i, j)| i <= j && j <= i + 2),
+            "cyclic" => Array2::from_shape_fn((n, n), |(i, j)| j == i || j == (i + 1) % n),
+            _ => return TransitionMask::load(spec, n),
+        };
+        Ok(TransitionMask { allowed })
+    }
+
+    fn load(filename: &str, n: usize) -> Result<TransitionMask, Box<dyn Error>> {
+        let contents = fs::read_to_string(filename).map_err(|e| {
+            format!(
+                "{}: {} (expecting a file or one of: {})",
+                filename,
+                e,
+                NAMES.join(", ")
+            )
+        })?;
+        let mut values = Vec::with_capacity(n * n);
+        let mut rows = 0;
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let row: Vec<&str> = line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|v| !v.is_empty())
+                .collect();
+            if row.len() != n {
+                return Err(format!("{}: expecting {} values per row", filename, n).into());
+            }
+            for v in row {
+                match v {
+                    "0" => values.push(false),
+                    "1" => values.push(true),
+                    _ => return Err(format!("{}: invalid value: {}", filename, v).into()),
+                }
+            }
+            rows += 1;
+        }
+        if rows != n {
+            return Err(format!("{}: expecting {} rows, got {}", filename, n, rows).into());
+        }
+        let mask = TransitionMask {
+            allowed: Array2::from_shape_vec((n, n), values)?,
+        };
+        mask.check()?;
+        Ok(mask)
+    }
+
+    /// Each state must have at least one allowed transition.
+    fn check(&self) -> Result<(), Box<dyn Error>> {
+        for (i, row) in self.allowed.axis_iter(Axis(0)).enumerate() {
+            if !row.iter().any(|&a| a) {
+                return Err(format!("no allowed transitions from state {}", i).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the disallowed transitions to zero and renormalizes the rows.
+    /// A row left without probability mass becomes uniform over the allowed
+    /// transitions.
+    pub fn apply(&self, a: &mut Array2<f64>) {
+        for (mut a_row, allowed) in a
+            .axis_iter_mut(Axis(0))
+            .zip(self.allowed.axis_iter(Axis(0)))
+        {
+            a_row.zip_mut_with(&allowed, |p, &ok| {
+                if !ok {
+                    *p = 0.0;
+                }
+            });
+            let sum = a_row.sum();
+            if sum > 0.0 {
+                a_row /= sum;
+            } else {
+                let num_allowed = allowed.iter().filter(|&&ok| ok).count() as f64;
+                a_row.zip_mut_with(&allowed, |p, &ok| {
+                    *p = if ok { 1.0 / num_allowed } else { 0.0 };
+                });
+            }
+        }
+    }
+
+    /// Transition matrix with `val_auto` for the self-transitions, and the
+    /// rest evenly distributed among the other allowed transitions.
+    /// (If only the self-transition is allowed, it gets probability 1; if it
+    /// is not allowed, the allowed transitions get the same probability.)
+    pub fn cascade(&self, val_auto: f64) -> Array2<f64> {
+        let n = self.allowed.nrows();
+        let mut a = Array2::zeros((n, n));
+        for i in 0..n {
+            let others: Vec<usize> = (0..n).filter(|&j| j != i && self.allowed[[i, j]]).collect();
+            if !self.allowed[[i, i]] {
+                for &j in &others {
+                    a[[i, j]] = 1.0 / others.len() as f64;
+                }
+            } else if others.is_empty() {
+                a[[i, i]] = 1.0;
+            } else {
+                a[[i, i]] = val_auto;
+                for &j in &others {
+                    a[[i, j]] = (1.0 - val_auto) / others.len() as f64;
+                }
+            }
+        }
+        a
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_named() {
+        let mask = TransitionMask::parse("cyclic", 3).unwrap();
+        assert_eq!(
+            mask.allowed,
+            array![
+                [true, true, false],
+                [false, true, true],
+                [true, false, true]
+            ]
+        );
+
+        let a = TransitionMask::parse("left-right-skip", 4)
+            .unwrap()
+            .cascade(0.4);
+        assert_eq!(a.row(0), array![0.4, 0.3, 0.3, 0.0]);
+        assert_eq!(a.row(2), array![0.0, 0.0, 0.4, 0.6]);
+        assert_eq!(a.row(3), array![0.0, 0.0, 0.0, 1.0]);
+
+        let mut a = Array2::from_elem((3, 3), 1.0 / 3.0);
+        TransitionMask::parse("left-right", 3)
+            .unwrap()
+            .apply(&mut a);
+        assert_eq!(a.row(0), array![0.5, 0.5, 0.0]);
+        assert_eq!(a.row(2), array![0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join("ecoz2_test_mask.txt");
+        let filename = path.to_str().unwrap();
+        std::fs::write(filename, "# a mask\n1,1,0\n0 1 1\n\n1 0 1\n").unwrap();
+        let mask = TransitionMask::parse(filename, 3);
+        std::fs::write(filename, "1,1,0\n0,0,0\n1,0,1\n").unwrap();
+        let no_transitions = TransitionMask::parse(filename, 3);
+        std::fs::remove_file(filename).unwrap();
+
+        assert_eq!(mask.unwrap(), TransitionMask::parse("cyclic", 3).unwrap());
+        assert!(no_transitions.is_err());
+    }
+}
@@ -13,6 +13,7 @@ mod lpc_rs;
 pub mod lpca_cepstrum_rs;
 pub mod lpca_r_rs;
 mod lpca_rs;
+pub mod ref2raas_rs;
 
 #[derive(StructOpt, Debug)]
 pub struct LpcOpts {
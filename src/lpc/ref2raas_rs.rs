@@ -0,0 +1,72 @@
+#![allow(clippy::many_single_char_names)]
+#![allow(clippy::doc_overindented_list_items)]
+
+///
+/// Rust version of `ref2raas`.
+/// Gets the autocorrelation of the predictor coefficients (the "raas")
+/// corresponding to the given reflection coefficients.
+///
+/// ## Arguments:
+///
+/// * `p`    - Prediction order.
+/// * `rc`   - Reflection coefficients `rc[1 ..= p]` (`rc[0]` is ignored).
+/// * `a`    - Prediction coefficients `a[0 ..= p]` are stored here.
+/// * `raas` - `raas[0 ..= p]` are stored here, with `raas[n]`, `n > 0`,
+///            already multiplied by 2 so the likelihood ratio distortion
+///            reduces to a dot product with a (gain normalized) autocorrelation.
+///
+#[inline]
+pub fn ref2raas(p: usize, rc: &[f64], a: &mut [f64], raas: &mut [f64]) {
+    // get predictor coefficients (step-up recursion as in lpca_r):
+    a[0] = 1.0f64;
+    for k in 1..=p {
+        let akk = rc[k];
+        a[k] = akk;
+        for i in 1..=k >> 1 {
+            let ai = a[i];
+            let aj = a[k - i];
+            a[i] = ai + akk * aj;
+            a[k - i] = aj + akk * ai;
+        }
+    }
+
+    // autocorrelation of the predictor coefficients:
+    for n in 0..=p {
+        let mut sum = 0.0f64;
+        for k in 0..=p - n {
+            sum += a[k] * a[k + n];
+        }
+        raas[n] = if n == 0 { sum } else { 2.0f64 * sum };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::lpca_rs::{lpca1, lpca_load_input};
+    use super::*;
+
+    /// The raas obtained from the reflection coefficients of a frame must give
+    /// a (normalized) prediction error of 1 on the frame's own autocorrelation.
+    #[test]
+    fn test_ref2raas_own_prediction_error() {
+        let input = lpca_load_input("signal_frame.inputs").unwrap();
+        let p = 36;
+
+        let mut r = vec![0f64; p + 1];
+        let mut rc = vec![0f64; p + 1];
+        let mut a = vec![0f64; p + 1];
+        let (res, pe) = lpca1(&input.x[..], p, &mut r, &mut rc, &mut a);
+        assert_eq!(res, 0);
+
+        let mut a2 = vec![0f64; p + 1];
+        let mut raas = vec![0f64; p + 1];
+        ref2raas(p, &rc, &mut a2, &mut raas);
+
+        for (x, y) in a.iter().zip(&a2) {
+            assert_approx_eq!(x, y, 1e-9);
+        }
+
+        let sum: f64 = r.iter().zip(&raas).map(|(r, a)| r / pe * a).sum();
+        assert_approx_eq!(sum, 1f64, 1e-6);
+    }
+}
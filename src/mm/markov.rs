@@ -8,9 +8,11 @@ use std::path::PathBuf;
 
 use colored::*;
 use ndarray::prelude::*;
+use rand::rngs::StdRng;
 
 use crate::c12n;
 use crate::sequence;
+use crate::sequence::generate::{sample_index, Length};
 use crate::serde;
 
 const EQ_EPSILON: f32 = 1e-5;
@@ -39,14 +41,60 @@ impl MM {
         }
     }
 
-    /// log probability of generating the symbol sequence
+    /// log probability of generating the symbol sequence (0 if empty)
     pub fn log_prob_sequence(&self, seq: &sequence::Sequence) -> f32 {
-        let mut p = self.pi[seq.symbols[0] as usize].log10();
-        for t in 0..seq.symbols.len() - 1 {
-            p += self.a[[seq.symbols[t] as usize, seq.symbols[t + 1] as usize]].log10();
+        let Some(&first) = seq.symbols.first() else {
+            return 0f32;
+        };
+        let mut p = self.pi[first as usize].log10();
+        for pair in seq.symbols.windows(2) {
+            p += self.a[[pair[0] as usize, pair[1] as usize]].log10();
         }
         p
     }
+
+    /// log probability of generating the soft observations (see
+    /// `sequence::SoftSequence::observations`), obtained with the forward
+    /// algorithm where the weights in each frame act as the emission
+    /// probabilities of the actual (hidden) symbols. 0 if there are no observations.
+    pub fn log_prob_soft_sequence(&self, observations: &[Vec<(usize, f64)>]) -> f32 {
+        let Some(first) = observations.first() else {
+            return 0f32;
+        };
+        let mut p = 0f64;
+        let mut alpha: Vec<f64> = first.iter().map(|(s, w)| self.pi[*s] as f64 * w).collect();
+        for t in 1..observations.len() {
+            let scale: f64 = alpha.iter().sum();
+            p += scale.log10();
+            alpha = observations[t]
+                .iter()
+                .map(|(k, w)| {
+                    let sum: f64 = observations[t - 1]
+                        .iter()
+                        .zip(&alpha)
+                        .map(|((j, _), a)| a / scale * self.a[[*j, *k]] as f64)
+                        .sum();
+                    sum * w
+                })
+                .collect();
+        }
+        p += alpha.iter().sum::<f64>().log10();
+        p as f32
+    }
+
+    /// Generates a symbol sequence from this model.
+    pub fn generate(&self, rng: &mut StdRng, length: &Length) -> Vec<u16> {
+        let len = length.sample(rng);
+        let mut symbols = Vec::with_capacity(len);
+        let mut symbol = sample_index(rng, self.pi.iter().map(|&p| p as f64));
+        for t in 0..len {
+            if t > 0 {
+                symbol = sample_index(rng, self.a.row(symbol).iter().map(|&p| p as f64));
+            }
+            symbols.push(symbol as u16);
+        }
+        symbols
+    }
 }
 
 pub fn load(filename: &str) -> Result<MM, Box<dyn Error>> {
@@ -125,11 +173,14 @@ pub fn learn(codebook_size: usize, seq_filenames: &[PathBuf]) -> Result<MM, Box<
     Ok(MM { class_name, pi, a })
 }
 
+/// Classifies the given sequences or, if `soft` is given, the given soft
+/// sequences using the `soft` value as the temperature for the observations.
 pub fn classify(
     mm_filenames: Vec<PathBuf>,
     seq_filenames: Vec<PathBuf>,
     show_ranked: bool,
     codebook_size: usize,
+    soft: Option<f64>,
 ) -> Result<(), Box<dyn Error>> {
     println!("Loading MM models");
     let models: Vec<MM> = mm_filenames
@@ -143,16 +194,30 @@ pub fn classify(
     println!("Classifying sequences");
     for filename in seq_filenames {
         let filename = filename.to_str().unwrap();
-        let seq = sequence::load(filename)?;
+        let (class_name, probs): (String, Vec<f64>) = match soft {
+            Some(temperature) => {
+                let seq = sequence::load_soft(filename)?;
+                let observations = seq.observations(temperature);
+                let probs = models
+                    .iter()
+                    .map(|m| m.log_prob_soft_sequence(&observations) as f64)
+                    .collect();
+                (seq.class_name, probs)
+            }
+            None => {
+                let seq = sequence::load(filename)?;
+                let probs = models
+                    .iter()
+                    .map(|m| m.log_prob_sequence(&seq) as f64)
+                    .collect();
+                (seq.class_name, probs)
+            }
+        };
 
-        let class_id_opt = &models.iter().position(|m| m.class_name == seq.class_name);
+        let class_id_opt = &models.iter().position(|m| m.class_name == class_name);
         if let Some(class_id) = *class_id_opt {
-            let probs: Vec<f64> = models
-                .iter()
-                .map(|m| m.log_prob_sequence(&seq) as f64)
-                .collect();
-            c12n.add_case(class_id, &seq.class_name, probs, show_ranked, || {
-                format!("\n{}: '{}'", filename, seq.class_name)
+            c12n.add_case(class_id, &class_name, probs, show_ranked, || {
+                format!("\n{}: '{}'", filename, class_name)
             });
         }
     }
@@ -165,3 +230,51 @@ pub fn classify(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_prob_soft_sequence() {
+        let mm = MM {
+            class_name: "c".to_string(),
+            pi: array![0.5, 0.3, 0.2],
+            a: array![[0.6, 0.3, 0.1], [0.2, 0.5, 0.3], [0.4, 0.4, 0.2]],
+        };
+        let seq = sequence::Sequence {
+            class_name: "c".to_string(),
+            codebook_size: 3,
+            symbols: vec![0, 1, 1, 2, 0],
+        };
+
+        // a single symbol with weight 1 in each frame is the hard sequence
+        let hard: Vec<Vec<(usize, f64)>> = seq
+            .symbols
+            .iter()
+            .map(|s| vec![(*s as usize, 1.0)])
+            .collect();
+        assert_approx_eq!(
+            mm.log_prob_soft_sequence(&hard),
+            mm.log_prob_sequence(&seq),
+            1e-5
+        );
+
+        // all symbols equally weighted in each frame: 5 frames with prob 1/3
+        let uniform: Vec<Vec<(usize, f64)>> = (0..5)
+            .map(|_| (0..3).map(|s| (s, 1.0 / 3.0)).collect())
+            .collect();
+        assert_approx_eq!(
+            mm.log_prob_soft_sequence(&uniform),
+            -5.0 * 3f32.log10(),
+            1e-5
+        );
+
+        let empty = sequence::Sequence {
+            symbols: vec![],
+            ..seq
+        };
+        assert_eq!(mm.log_prob_sequence(&empty), 0.0);
+        assert_eq!(mm.log_prob_soft_sequence(&[]), 0.0);
+    }
+}
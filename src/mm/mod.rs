@@ -4,12 +4,18 @@ use std::path::PathBuf;
 
 use clap::StructOpt;
 use colored::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 
+use crate::ecoz2_lib::set_random_seed;
+use crate::sequence::generate::{self, Length};
 use crate::utl;
+use crate::utl::learn_all;
 
-use self::EcozMMCommand::{Classify, Learn, Show};
+use self::EcozMMCommand::{Classify, Generate, Learn, LearnAll, Show};
 
 mod markov;
+mod ngram;
 
 #[derive(StructOpt, Debug)]
 pub struct MMMainOpts {
@@ -23,11 +29,17 @@ enum EcozMMCommand {
     #[structopt(about = "MM training")]
     Learn(MMLearnOpts),
 
+    #[structopt(about = "MM training of all classes")]
+    LearnAll(MMLearnAllOpts),
+
     #[structopt(about = "MM based classification")]
     Classify(MMClassifyOpts),
 
     #[structopt(about = "Show MM model")]
     Show(MMShowOpts),
+
+    #[structopt(about = "Generate synthetic sequences from MM model")]
+    Generate(MMGenerateOpts),
 }
 
 #[derive(StructOpt, Debug)]
@@ -40,6 +52,17 @@ pub struct MMLearnOpts {
     #[structopt(long, name = "class")]
     class_name: Option<String>,
 
+    /// Train a model of order k (1 to 4) instead of the first-order one with add-one
+    /// counts: the probability of a symbol depends on the previous k symbols,
+    /// with `--smoothing` and backoff to the lower orders.
+    /// The model is saved as `data/mms/M<M>_k<k>_<smoothing>/<class>.kmm`.
+    #[structopt(short = 'k', long)]
+    order: Option<usize>,
+
+    /// With `--order`, smoothing: kneser-ney or witten-bell.
+    #[structopt(long, default_value = "kneser-ney")]
+    smoothing: ngram::Smoothing,
+
     /// Training sequences.
     /// If a single `.csv` file is given, then the "TRAIN" files indicated there will be used.
     /// Otherwise, if directories are included, then all `.seq` under them will be used.
@@ -47,6 +70,33 @@ pub struct MMLearnOpts {
     sequences: Vec<PathBuf>,
 }
 
+#[derive(StructOpt, Debug)]
+pub struct MMLearnAllOpts {
+    /// Number of symbols (codebook size)
+    #[structopt(short = 'M', long, name = "M", required = true)]
+    codebook_size: usize,
+
+    /// Train a model of order k (1 to 4) instead of the first-order one with add-one
+    /// counts: the probability of a symbol depends on the previous k symbols,
+    /// with `--smoothing` and backoff to the lower orders.
+    /// The model is saved as `data/mms/M<M>_k<k>_<smoothing>/<class>.kmm`.
+    #[structopt(short = 'k', long)]
+    order: Option<usize>,
+
+    /// With `--order`, smoothing: kneser-ney or witten-bell.
+    #[structopt(long, default_value = "kneser-ney")]
+    smoothing: ngram::Smoothing,
+
+    /// Maximum number of classes trained at a time (0 means the number of CPUs).
+    #[structopt(long, default_value = "0")]
+    threads: usize,
+
+    /// The "TRAIN" files indicated in this `.csv` file are grouped by class,
+    /// and a model is trained for each class.
+    #[structopt(parse(from_os_str))]
+    tt_list: PathBuf,
+}
+
 #[derive(StructOpt, Debug)]
 pub struct MMClassifyOpts {
     /// Number of symbols (codebook size)
@@ -62,30 +112,85 @@ pub struct MMClassifyOpts {
     tt: String,
 
     /// MM models.
-    /// If directories are included, then all `.mm` under them will be used.
+    /// If directories are included, then all `.mm` or all `.kmm` (order-k models)
+    /// under them will be used; mixing `.mm` and `.kmm` models is rejected.
     #[structopt(long, required = true, min_values = 1, parse(from_os_str))]
     models: Vec<PathBuf>,
 
     /// Sequences to classify.
-    /// If directories are included, then all `.seq` under them will be used.
+    /// If directories are included, then all `.seq` under them will be used
+    /// (`.sseq` with `--soft`).
     #[structopt(long, required = true, min_values = 1, parse(from_os_str))]
     sequences: Vec<PathBuf>,
+
+    /// Classify soft sequences (as generated by `vq quantize --zrs --top-k`).
+    /// Only with first-order models.
+    #[structopt(long)]
+    soft: bool,
+
+    /// Temperature for the soft observations, relative to the average
+    /// distortion of the nearest symbols (0 means only the nearest symbol).
+    #[structopt(long, default_value = "1")]
+    temperature: f64,
 }
 
 #[derive(StructOpt, Debug)]
 pub struct MMShowOpts {
+    /// MM model (`.mm`, or `.kmm` for an order-k model).
+    #[structopt(short, long, parse(from_os_str))]
+    model: PathBuf,
+
+    /// Write the symbol transitions as a Graphviz graph to the given file
+    /// (use `-` for standard output). Symbols without any included
+    /// transition are omitted.
+    #[structopt(long, name = "dot-file", parse(from_os_str))]
+    dot: Option<PathBuf>,
+
+    /// With `--dot`, only include transitions with at least this probability.
+    #[structopt(long, default_value = "0.05")]
+    threshold: f64,
+
+    /// Save A in long format (`from,to,prob`) to the given CSV file
+    /// for heatmap plotting.
+    /// `--dot` and `--heatmap` only apply to first-order models.
+    #[structopt(long, name = "heatmap-file", parse(from_os_str))]
+    heatmap: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct MMGenerateOpts {
     /// MM model.
     #[structopt(short, long, parse(from_os_str))]
     model: PathBuf,
+
+    /// Number of sequences to generate.
+    #[structopt(short = 'n', long, default_value = "10")]
+    num_sequences: usize,
+
+    /// Length of the sequences: `<n>`, `uniform:<min>-<max>` or `normal:<mean>,<sd>`.
+    #[structopt(long, default_value = "100")]
+    length: Length,
+
+    /// Seed for random numbers. Negative means random seed.
+    #[structopt(short = 's', long, default_value = "-1")]
+    seed: i64,
+
+    /// Sequences are saved under `<out-dir>/M<M>/<class>/`.
+    #[structopt(long, default_value = "data/sequences/synthetic")]
+    out_dir: String,
 }
 
 pub fn main(opts: MMMainOpts) {
     let res = match opts.cmd {
         Learn(opts) => main_mm_learn(opts),
 
+        LearnAll(opts) => main_mm_learn_all(opts),
+
         Classify(opts) => main_mm_classify(opts),
 
         Show(opts) => main_mm_show(opts),
+
+        Generate(opts) => main_mm_generate(opts),
     };
 
     if let Err(err) = res {
@@ -97,6 +202,8 @@ pub fn main_mm_learn(opts: MMLearnOpts) -> Result<(), Box<dyn Error>> {
     let MMLearnOpts {
         codebook_size,
         class_name,
+        order,
+        smoothing,
         sequences,
     } = opts;
 
@@ -108,7 +215,30 @@ pub fn main_mm_learn(opts: MMLearnOpts) -> Result<(), Box<dyn Error>> {
         ".seq",
     )?;
 
-    let model = markov::learn(codebook_size, &seq_filenames)?;
+    learn_and_save(codebook_size, &seq_filenames, order, smoothing)?;
+    Ok(())
+}
+
+/// Trains and saves a model, of the given order if any,
+/// returning its filename.
+fn learn_and_save(
+    codebook_size: usize,
+    seq_filenames: &[PathBuf],
+    order: Option<usize>,
+    smoothing: ngram::Smoothing,
+) -> Result<String, Box<dyn Error>> {
+    if let Some(order) = order {
+        let model = ngram::learn(codebook_size, seq_filenames, order, smoothing)?;
+        let mm_dir = format!("data/mms/M{}_k{}_{}", codebook_size, order, smoothing);
+        std::fs::create_dir_all(&mm_dir)?;
+        let filename = format!("{}/{}{}", mm_dir, model.class_name, ngram::EXTENSION);
+        println!("MM model trained");
+        utl::save_ser(&model, filename.as_str())?;
+        println!("MM model saved: {}\n\n", filename);
+        return Ok(filename);
+    }
+
+    let model = markov::learn(codebook_size, seq_filenames)?;
 
     let mm_dir_str = format!("data/mms/M{}", codebook_size);
     let mm_dir = Path::new(&mm_dir_str);
@@ -117,6 +247,32 @@ pub fn main_mm_learn(opts: MMLearnOpts) -> Result<(), Box<dyn Error>> {
     println!("MM model trained");
     utl::save_ser(&model, filename.as_str())?;
     println!("MM model saved: {}\n\n", filename);
+    Ok(filename)
+}
+
+pub fn main_mm_learn_all(opts: MMLearnAllOpts) -> Result<(), Box<dyn Error>> {
+    let MMLearnAllOpts {
+        codebook_size,
+        order,
+        smoothing,
+        threads,
+        tt_list,
+    } = opts;
+
+    let groups = learn_all::get_class_files_from_csv(
+        &tt_list,
+        "TRAIN",
+        &format!("sequences/M{}", codebook_size),
+        ".seq",
+    )?;
+    let threads = if threads == 0 {
+        num_cpus::get()
+    } else {
+        threads
+    };
+    learn_all::learn_all(&groups, threads, |_, seq_filenames| {
+        learn_and_save(codebook_size, seq_filenames, order, smoothing)
+    })?;
     Ok(())
 }
 
@@ -127,33 +283,114 @@ pub fn main_mm_classify(opts: MMClassifyOpts) -> Result<(), Box<dyn Error>> {
         tt,
         models,
         sequences,
+        soft,
+        temperature,
     } = opts;
 
-    let mm_filenames = utl::resolve_filenames(models, ".mm", "models")?;
+    let mm_filenames = utl::resolve_filenames(models.clone(), ".mm", "")?;
+    let kmm_filenames = utl::resolve_filenames(models, ngram::EXTENSION, "")?;
+    if mm_filenames.is_empty() && kmm_filenames.is_empty() {
+        return Err("No models given".into());
+    }
+    if !mm_filenames.is_empty() && !kmm_filenames.is_empty() {
+        return Err(format!(
+            "Both first-order (.mm) and order-k ({}) models given; select only one kind",
+            ngram::EXTENSION
+        )
+        .into());
+    }
+    if soft && !kmm_filenames.is_empty() {
+        return Err("--soft only supported with first-order models".into());
+    }
 
     let seq_filenames = utl::resolve_files(
         sequences,
         tt.as_str(),
         None,
         format!("sequences/M{}", codebook_size),
-        ".seq",
+        if soft { ".sseq" } else { ".seq" },
     )?;
 
     println!(
         "number of MM models: {}  number of sequences: {}",
-        mm_filenames.len(),
+        mm_filenames.len() + kmm_filenames.len(),
         seq_filenames.len()
     );
     println!("show_ranked = {}", show_ranked);
 
-    markov::classify(mm_filenames, seq_filenames, show_ranked, codebook_size)
+    if !kmm_filenames.is_empty() {
+        return ngram::classify(kmm_filenames, seq_filenames, show_ranked, codebook_size);
+    }
+
+    let soft = if soft { Some(temperature) } else { None };
+    markov::classify(
+        mm_filenames,
+        seq_filenames,
+        show_ranked,
+        codebook_size,
+        soft,
+    )
 }
 
 pub fn main_mm_show(opts: MMShowOpts) -> Result<(), Box<dyn Error>> {
-    let MMShowOpts { model } = opts;
+    let MMShowOpts {
+        model,
+        dot,
+        threshold,
+        heatmap,
+    } = opts;
+
+    if model.to_str().unwrap().ends_with(ngram::EXTENSION) {
+        if dot.is_some() || heatmap.is_some() {
+            return Err("--dot and --heatmap only supported with first-order models".into());
+        }
+        ngram::load(model.to_str().unwrap())?.show();
+        return Ok(());
+    }
 
     let mut model = markov::load(model.to_str().unwrap())?;
-    model.show();
+    if dot.is_none() && heatmap.is_none() {
+        model.show();
+        return Ok(());
+    }
+
+    let pi = model.pi.mapv(f64::from);
+    let a = model.a.mapv(f64::from);
+    if let Some(dot) = dot {
+        let labels: Vec<String> = (0..pi.len()).map(|i| i.to_string()).collect();
+        let graph = utl::dot::transitions_dot(
+            &model.class_name,
+            &labels,
+            pi.view(),
+            a.view(),
+            threshold,
+            false,
+        );
+        utl::dot::save_dot(&graph, &dot)?;
+    }
+    if let Some(heatmap) = heatmap {
+        utl::dot::save_heatmap_csv(a.view(), &heatmap)?;
+    }
 
     Ok(())
 }
+
+pub fn main_mm_generate(opts: MMGenerateOpts) -> Result<(), Box<dyn Error>> {
+    let MMGenerateOpts {
+        model,
+        num_sequences,
+        length,
+        seed,
+        out_dir,
+    } = opts;
+
+    let model = markov::load(model.to_str().unwrap())?;
+    let mut rng = StdRng::seed_from_u64(set_random_seed(seed));
+    generate::save_generated(
+        &model.class_name,
+        model.pi.len(),
+        num_sequences,
+        &out_dir,
+        || model.generate(&mut rng, &length),
+    )
+}
@@ -0,0 +1,452 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use colored::*;
+
+use crate::c12n;
+use crate::sequence;
+
+/// File extension of the order-k models.
+pub const EXTENSION: &str = ".kmm";
+
+/// Maximum supported order.
+pub const MAX_ORDER: usize = 4;
+
+/// Smoothing of the order-k model, interpolating with the lower orders.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Smoothing {
+    /// Interpolated Kneser-Ney: absolute discounting, with continuation
+    /// counts (number of distinct preceding symbols) for the lower orders.
+    KneserNey,
+
+    /// Witten-Bell: the weight of the lower order is proportional to the
+    /// number of distinct symbols seen after the context.
+    WittenBell,
+}
+
+impl FromStr for Smoothing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kneser-ney" => Ok(Smoothing::KneserNey),
+            "witten-bell" => Ok(Smoothing::WittenBell),
+            _ => Err(format!(
+                "invalid smoothing: {} (expecting kneser-ney or witten-bell)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Smoothing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Smoothing::KneserNey => "kneser-ney",
+            Smoothing::WittenBell => "witten-bell",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Counts of the symbols following a context.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+struct Successors {
+    counts: BTreeMap<u16, f64>,
+    total: f64,
+}
+
+/// A Markov model of order k (the probability of a symbol depends on the
+/// previous k symbols), stored sparsely: only the contexts seen in training
+/// are kept, and unseen ones back off to the shorter contexts.
+///
+/// Sequences are padded at the start with k start symbols (`codebook_size`),
+/// so the initial symbols are also modeled.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct NgramMM {
+    pub class_name: String,
+    pub codebook_size: usize,
+    pub order: usize,
+    pub smoothing: Smoothing,
+
+    /// Kneser-Ney discount for each context length `0..=order`.
+    pub discounts: Vec<f64>,
+
+    /// For each context length `0..=order`, the successor counts of each context
+    /// (continuation counts for the lengths below `order` with Kneser-Ney).
+    levels: Vec<BTreeMap<Vec<u16>, Successors>>,
+}
+
+impl NgramMM {
+    /// Probability of `symbol` following the given context (the previous
+    /// symbols, at most `order` of them, possibly including start symbols).
+    pub fn prob(&self, context: &[u16], symbol: u16) -> f64 {
+        let lower = match context.split_first() {
+            Some((_, shorter)) => self.prob(shorter, symbol),
+            None => 1.0 / self.codebook_size as f64,
+        };
+        let Some(successors) = self.levels[context.len()].get(context) else {
+            return lower;
+        };
+        let count = successors.counts.get(&symbol).copied().unwrap_or(0.0);
+        let types = successors.counts.len() as f64;
+        match self.smoothing {
+            Smoothing::WittenBell => (count + types * lower) / (successors.total + types),
+            Smoothing::KneserNey => {
+                let d = self.discounts[context.len()];
+                ((count - d).max(0.0) + d * types * lower) / successors.total
+            }
+        }
+    }
+
+    /// log probability of generating the symbol sequence
+    pub fn log_prob_sequence(&self, seq: &sequence::Sequence) -> f64 {
+        self.log_prob(&seq.symbols)
+    }
+
+    fn log_prob(&self, symbols: &[u16]) -> f64 {
+        let k = self.order;
+        let padded = pad(symbols, k, self.codebook_size);
+        (0..symbols.len())
+            .map(|t| self.prob(&padded[t..t + k], padded[t + k]).log10())
+            .sum()
+    }
+
+    pub fn show(&self) {
+        println!(
+            "class_name='{}', codebook_size={}, order={}, smoothing={}",
+            self.class_name, self.codebook_size, self.order, self.smoothing
+        );
+        for (n, level) in self.levels.iter().enumerate() {
+            let entries: usize = level.values().map(|s| s.counts.len()).sum();
+            print!(
+                "  context length {}: {:>7} contexts {:>8} entries",
+                n,
+                level.len(),
+                entries
+            );
+            if self.smoothing == Smoothing::KneserNey {
+                print!("  discount = {:.3}", self.discounts[n]);
+            }
+            println!();
+        }
+
+        let mut contexts: Vec<(&Vec<u16>, &Successors)> = self.levels[self.order].iter().collect();
+        contexts.sort_by(|x, y| y.1.total.total_cmp(&x.1.total));
+        println!("most frequent contexts (count): most probable next symbols");
+        for (context, successors) in contexts.into_iter().take(10) {
+            let mut next: Vec<(u16, f64)> = (0..self.codebook_size as u16)
+                .map(|s| (s, self.prob(context, s)))
+                .collect();
+            next.sort_by(|x, y| y.1.total_cmp(&x.1));
+            let context: Vec<String> = context
+                .iter()
+                .map(|&s| {
+                    if s as usize == self.codebook_size {
+                        "^".to_string()
+                    } else {
+                        s.to_string()
+                    }
+                })
+                .collect();
+            let next: Vec<String> = next
+                .iter()
+                .take(3)
+                .map(|(s, p)| format!("{} ({:.2})", s, p))
+                .collect();
+            println!(
+                " [{}] ({}): {}",
+                context.join(" "),
+                successors.total,
+                next.join("  ")
+            );
+        }
+    }
+}
+
+fn pad(symbols: &[u16], order: usize, codebook_size: usize) -> Vec<u16> {
+    let mut padded = vec![codebook_size as u16; order];
+    padded.extend_from_slice(symbols);
+    padded
+}
+
+pub fn load(filename: &str) -> Result<NgramMM, Box<dyn Error>> {
+    let f = File::open(filename)?;
+    let br = BufReader::new(f);
+    let mm = serde_cbor::from_reader(br)?;
+    Ok(mm)
+}
+
+pub fn learn(
+    codebook_size: usize,
+    seq_filenames: &[PathBuf],
+    order: usize,
+    smoothing: Smoothing,
+) -> Result<NgramMM, Box<dyn Error>> {
+    let seq = sequence::load(seq_filenames[0].to_str().unwrap())?;
+    let class_name = seq.class_name;
+
+    println!(
+        "MM learn: num sequences={} class='{}' codebook_size={} order={} smoothing={}",
+        seq_filenames.len(),
+        class_name,
+        codebook_size,
+        order,
+        smoothing
+    );
+
+    let mut sequences = Vec::with_capacity(seq_filenames.len());
+    for seq_filename in seq_filenames {
+        let seq = sequence::load(seq_filename.to_str().unwrap())?;
+        print!("{}", ".".magenta());
+        std::io::stdout().flush().unwrap();
+
+        if codebook_size != seq.codebook_size as usize {
+            return Err(format!(
+                "conformity error: codebook size: {} != {}",
+                codebook_size, seq.codebook_size
+            )
+            .into());
+        }
+        if class_name != seq.class_name {
+            return Err(format!(
+                "conformity error: class_name: {} != {}",
+                class_name, seq.class_name
+            )
+            .into());
+        }
+        sequences.push(seq.symbols);
+    }
+    println!();
+
+    learn_symbols(class_name, codebook_size, &sequences, order, smoothing)
+}
+
+/// Trains the model on the given symbol sequences.
+fn learn_symbols(
+    class_name: String,
+    codebook_size: usize,
+    sequences: &[Vec<u16>],
+    order: usize,
+    smoothing: Smoothing,
+) -> Result<NgramMM, Box<dyn Error>> {
+    if !(1..=MAX_ORDER).contains(&order) {
+        return Err(format!("order must be between 1 and {}", MAX_ORDER).into());
+    }
+
+    let mut levels: Vec<BTreeMap<Vec<u16>, Successors>> =
+        (0..=order).map(|_| BTreeMap::new()).collect();
+    // Kneser-Ney: distinct symbols preceding each lower order context and symbol
+    let mut preceding: BTreeMap<(Vec<u16>, u16), BTreeSet<u16>> = BTreeMap::new();
+
+    for symbols in sequences {
+        let padded = pad(symbols, order, codebook_size);
+        for t in order..padded.len() {
+            let symbol = padded[t];
+            for n in 0..=order {
+                let context = &padded[t - n..t];
+                if n == order || smoothing == Smoothing::WittenBell {
+                    add_count(&mut levels[n], context, symbol, 1.0);
+                } else {
+                    preceding
+                        .entry((context.to_vec(), symbol))
+                        .or_default()
+                        .insert(padded[t - n - 1]);
+                }
+            }
+        }
+    }
+    for ((context, symbol), symbols) in preceding {
+        add_count(
+            &mut levels[context.len()],
+            &context,
+            symbol,
+            symbols.len() as f64,
+        );
+    }
+
+    let discounts = levels.iter().map(discount).collect();
+    Ok(NgramMM {
+        class_name,
+        codebook_size,
+        order,
+        smoothing,
+        discounts,
+        levels,
+    })
+}
+
+fn add_count(level: &mut BTreeMap<Vec<u16>, Successors>, context: &[u16], symbol: u16, count: f64) {
+    let successors = level.entry(context.to_vec()).or_default();
+    *successors.counts.entry(symbol).or_insert(0.0) += count;
+    successors.total += count;
+}
+
+/// Absolute discount `n1 / (n1 + 2 n2)`, with `n1` and `n2` the number of
+/// entries with count 1 and 2 (0.5 if there are no entries with count 1).
+fn discount(level: &BTreeMap<Vec<u16>, Successors>) -> f64 {
+    let counts = level.values().flat_map(|s| s.counts.values());
+    let (n1, n2) = counts.fold((0.0, 0.0), |(n1, n2), &c| {
+        (n1 + (c == 1.0) as u8 as f64, n2 + (c == 2.0) as u8 as f64)
+    });
+    if n1 > 0.0 {
+        n1 / (n1 + 2.0 * n2)
+    } else {
+        0.5
+    }
+}
+
+/// Classifies the given sequences.
+pub fn classify(
+    model_filenames: Vec<PathBuf>,
+    seq_filenames: Vec<PathBuf>,
+    show_ranked: bool,
+    codebook_size: usize,
+) -> Result<(), Box<dyn Error>> {
+    println!("Loading order-k MM models");
+    let models = model_filenames
+        .iter()
+        .map(|n| load(n.to_str().unwrap()))
+        .collect::<Result<Vec<NgramMM>, _>>()?;
+    if models.is_empty() {
+        return Err("No models given".into());
+    }
+    let (order, smoothing) = (models[0].order, models[0].smoothing);
+    for (model, filename) in models.iter().zip(&model_filenames) {
+        if model.codebook_size != codebook_size {
+            return Err(format!(
+                "conformity error: {}: codebook size {} (expecting {})",
+                filename.display(),
+                model.codebook_size,
+                codebook_size
+            )
+            .into());
+        }
+        if (model.order, model.smoothing) != (order, smoothing) {
+            return Err(format!(
+                "conformity error: {}: order {} with {} (expecting order {} with {})",
+                filename.display(),
+                model.order,
+                model.smoothing,
+                order,
+                smoothing
+            )
+            .into());
+        }
+    }
+
+    let model_class_names = models.iter().map(|m| m.class_name.clone()).collect();
+    let mut c12n = c12n::C12nResults::new(model_class_names);
+
+    println!("Classifying sequences");
+    for filename in seq_filenames {
+        let filename = filename.to_str().unwrap();
+        let seq = sequence::load(filename)?;
+        if seq.codebook_size as usize != codebook_size {
+            return Err(format!(
+                "{}: conformity error: codebook size: {} != {}",
+                filename, seq.codebook_size, codebook_size
+            )
+            .into());
+        }
+
+        let class_id_opt = models.iter().position(|m| m.class_name == seq.class_name);
+        if let Some(class_id) = class_id_opt {
+            let probs: Vec<f64> = models.iter().map(|m| m.log_prob_sequence(&seq)).collect();
+            c12n.add_case(class_id, &seq.class_name, probs, show_ranked, || {
+                format!("\n{}: '{}'", filename, seq.class_name)
+            });
+        }
+    }
+
+    println!();
+
+    let class_names: Vec<&String> = models.iter().map(|m| &m.class_name).collect();
+    let out_base_name = format!("mm_{}_k{}_{}", codebook_size, order, smoothing);
+    c12n.report_results(class_names, out_base_name);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_learn() {
+        let sequences: Vec<Vec<u16>> = vec![
+            vec![0, 1, 2, 3, 0, 1, 2, 3, 0, 1, 2, 3],
+            vec![1, 2, 3, 0, 1, 2, 3, 0, 1, 2],
+            vec![0, 1, 2, 0, 1, 2, 3],
+        ];
+        let cycle: Vec<u16> = vec![0, 1, 2, 3, 0, 1, 2, 3];
+        let reversed: Vec<u16> = cycle.iter().rev().copied().collect();
+        for smoothing in [Smoothing::KneserNey, Smoothing::WittenBell] {
+            for order in 1..=MAX_ORDER {
+                let mm = learn_symbols("c".to_string(), 5, &sequences, order, smoothing).unwrap();
+
+                // normalized for seen and unseen contexts, with all symbols possible
+                for context in [vec![5; order], vec![2; order], vec![4; order]] {
+                    let probs: Vec<f64> = (0..5).map(|s| mm.prob(&context, s)).collect();
+                    assert_approx_eq!(probs.iter().sum::<f64>(), 1.0, 1e-9);
+                    assert!(probs.iter().all(|&p| p > 0.0));
+                }
+                assert!(mm.log_prob(&cycle) > mm.log_prob(&reversed));
+            }
+        }
+        assert!(learn_symbols("c".to_string(), 5, &sequences, 5, Smoothing::KneserNey).is_err());
+    }
+
+    #[test]
+    fn test_classify_conformity() {
+        let sequences: Vec<Vec<u16>> = vec![vec![0, 1, 2, 3, 0, 1, 2, 3]];
+        let dir = std::env::temp_dir();
+        let model_filenames: Vec<PathBuf> = [(2, Smoothing::KneserNey), (3, Smoothing::KneserNey)]
+            .iter()
+            .enumerate()
+            .map(|(i, &(order, smoothing))| {
+                let mm = learn_symbols(format!("c{}", i), 5, &sequences, order, smoothing).unwrap();
+                let filename = dir.join(format!("ecoz2_test_classify_c{}.kmm", i));
+                serde_cbor::to_writer(File::create(&filename).unwrap(), &mm).unwrap();
+                filename
+            })
+            .collect();
+
+        // a sequence from a codebook of size 4 against models of size 5
+        let seq_filename = dir.join("ecoz2_test_classify_c0.seq");
+        sequence::Sequence {
+            class_name: "c0".to_string(),
+            codebook_size: 4,
+            symbols: vec![0, 1, 2, 3],
+        }
+        .save(seq_filename.to_str().unwrap())
+        .unwrap();
+
+        let result = classify(model_filenames.clone(), vec![], false, 5);
+        let seq_result = classify(
+            model_filenames[..1].to_vec(),
+            vec![seq_filename.clone()],
+            false,
+            5,
+        );
+        for filename in model_filenames {
+            std::fs::remove_file(filename).unwrap();
+        }
+        std::fs::remove_file(seq_filename).unwrap();
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("conformity error"));
+        assert!(seq_result
+            .unwrap_err()
+            .to_string()
+            .contains("conformity error: codebook size"));
+        assert!(classify(vec![], vec![], false, 5).is_err());
+    }
+}
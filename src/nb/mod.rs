@@ -5,8 +5,9 @@ use std::path::PathBuf;
 use clap::StructOpt;
 
 use crate::utl;
+use crate::utl::learn_all;
 
-use self::EcozNBayesCommand::{Classify, Learn, Show};
+use self::EcozNBayesCommand::{Classify, Learn, LearnAll, Show};
 
 mod nbayes;
 
@@ -22,6 +23,9 @@ enum EcozNBayesCommand {
     #[structopt(about = "NBayes training")]
     Learn(NBayesLearnOpts),
 
+    #[structopt(about = "NBayes training of all classes")]
+    LearnAll(NBayesLearnAllOpts),
+
     #[structopt(about = "NBayes based classification")]
     Classify(NBayesClassifyOpts),
 
@@ -46,6 +50,22 @@ pub struct NBayesLearnOpts {
     sequences: Vec<PathBuf>,
 }
 
+#[derive(StructOpt, Debug)]
+pub struct NBayesLearnAllOpts {
+    /// Number of symbols (codebook size)
+    #[structopt(short = 'M', long, name = "M", required = true)]
+    codebook_size: usize,
+
+    /// Maximum number of classes trained at a time (0 means the number of CPUs).
+    #[structopt(long, default_value = "0")]
+    threads: usize,
+
+    /// The "TRAIN" files indicated in this `.csv` file are grouped by class,
+    /// and a model is trained for each class.
+    #[structopt(parse(from_os_str))]
+    tt_list: PathBuf,
+}
+
 #[derive(StructOpt, Debug)]
 pub struct NBayesClassifyOpts {
     /// Number of symbols (codebook size)
@@ -66,9 +86,19 @@ pub struct NBayesClassifyOpts {
     models: Vec<PathBuf>,
 
     /// Sequences to classify.
-    /// If directories are included, then all `.seq` under them will be used.
+    /// If directories are included, then all `.seq` under them will be used
+    /// (`.sseq` with `--soft`).
     #[structopt(long, required = true, min_values = 1, parse(from_os_str))]
     sequences: Vec<PathBuf>,
+
+    /// Classify soft sequences (as generated by `vq quantize --zrs --top-k`).
+    #[structopt(long)]
+    soft: bool,
+
+    /// Temperature for the soft observations, relative to the average
+    /// distortion of the nearest symbols (0 means only the nearest symbol).
+    #[structopt(long, default_value = "1")]
+    temperature: f64,
 }
 
 #[derive(StructOpt, Debug)]
@@ -82,6 +112,8 @@ pub fn main(opts: NBayesMainOpts) {
     let res = match opts.cmd {
         Learn(opts) => main_nbayes_learn(opts),
 
+        LearnAll(opts) => main_nbayes_learn_all(opts),
+
         Classify(opts) => main_nbayes_classify(opts),
 
         Show(opts) => main_nbayes_show(opts),
@@ -107,6 +139,15 @@ pub fn main_nbayes_learn(opts: NBayesLearnOpts) -> Result<(), Box<dyn Error>> {
         ".seq",
     )?;
 
+    learn_and_save(codebook_size, seq_filenames)?;
+    Ok(())
+}
+
+/// Trains and saves a model, returning its filename.
+fn learn_and_save(
+    codebook_size: usize,
+    seq_filenames: Vec<PathBuf>,
+) -> Result<String, Box<dyn Error>> {
     let model = nbayes::learn(codebook_size, seq_filenames)?;
 
     let nb_dir_str = format!("data/nbs/M{}", codebook_size);
@@ -116,6 +157,30 @@ pub fn main_nbayes_learn(opts: NBayesLearnOpts) -> Result<(), Box<dyn Error>> {
     println!("NB model trained");
     utl::save_ser(&model, filename.as_str())?;
     println!("NB model saved: {}\n\n", filename);
+    Ok(filename)
+}
+
+pub fn main_nbayes_learn_all(opts: NBayesLearnAllOpts) -> Result<(), Box<dyn Error>> {
+    let NBayesLearnAllOpts {
+        codebook_size,
+        threads,
+        tt_list,
+    } = opts;
+
+    let groups = learn_all::get_class_files_from_csv(
+        &tt_list,
+        "TRAIN",
+        &format!("sequences/M{}", codebook_size),
+        ".seq",
+    )?;
+    let threads = if threads == 0 {
+        num_cpus::get()
+    } else {
+        threads
+    };
+    learn_all::learn_all(&groups, threads, |_, seq_filenames| {
+        learn_and_save(codebook_size, seq_filenames.to_vec())
+    })?;
     Ok(())
 }
 
@@ -126,6 +191,8 @@ pub fn main_nbayes_classify(opts: NBayesClassifyOpts) -> Result<(), Box<dyn Erro
         tt,
         models,
         sequences,
+        soft,
+        temperature,
     } = opts;
 
     let nb_filenames = utl::resolve_filenames(models, ".nb", "models")?;
@@ -135,7 +202,7 @@ pub fn main_nbayes_classify(opts: NBayesClassifyOpts) -> Result<(), Box<dyn Erro
         tt.as_str(),
         None,
         format!("sequences/M{}", codebook_size),
-        ".seq",
+        if soft { ".sseq" } else { ".seq" },
     )?;
 
     println!(
@@ -145,7 +212,14 @@ pub fn main_nbayes_classify(opts: NBayesClassifyOpts) -> Result<(), Box<dyn Erro
     );
     println!("show_ranked = {}", show_ranked);
 
-    nbayes::classify(nb_filenames, seq_filenames, show_ranked, codebook_size)
+    let soft = if soft { Some(temperature) } else { None };
+    nbayes::classify(
+        nb_filenames,
+        seq_filenames,
+        show_ranked,
+        codebook_size,
+        soft,
+    )
 }
 
 pub fn main_nbayes_show(opts: NBayesShowOpts) -> Result<(), Box<dyn Error>> {
@@ -51,6 +51,16 @@ impl NBayes {
             .iter()
             .fold(0_f64, |acc, s| acc + self.log_prob_symbol(*s as usize))
     }
+
+    /// log probability of generating the soft observations (see
+    /// `sequence::SoftSequence::observations`), with the probability of
+    /// each frame being the weighted sum of the probabilities of its symbols.
+    pub fn log_prob_soft_sequence(&self, observations: &[Vec<(usize, f64)>]) -> f64 {
+        observations.iter().fold(0_f64, |acc, frame| {
+            let prob: f64 = frame.iter().map(|(s, w)| w * self.prob_symbol(*s)).sum();
+            acc + prob.log10()
+        })
+    }
 }
 
 pub fn load(filename: &str) -> Result<NBayes, Box<dyn Error>> {
@@ -113,11 +123,14 @@ pub fn learn(codebook_size: usize, seq_filenames: Vec<PathBuf>) -> Result<NBayes
     })
 }
 
+/// Classifies the given sequences or, if `soft` is given, the given soft
+/// sequences using the `soft` value as the temperature for the observations.
 pub fn classify(
     nb_filenames: Vec<PathBuf>,
     seq_filenames: Vec<PathBuf>,
     show_ranked: bool,
     codebook_size: usize,
+    soft: Option<f64>,
 ) -> Result<(), Box<dyn Error>> {
     println!("Loading NBayes models");
     let models: Vec<NBayes> = nb_filenames
@@ -132,13 +145,27 @@ pub fn classify(
     println!("Classifying sequences");
     for filename in seq_filenames {
         let filename = filename.to_str().unwrap();
-        let seq = sequence::load(filename)?;
-
-        let class_id_opt = &models.iter().position(|m| m.class_name == seq.class_name);
+        let (class_name, probs): (String, Vec<f64>) = match soft {
+            Some(temperature) => {
+                let seq = sequence::load_soft(filename)?;
+                let observations = seq.observations(temperature);
+                let probs = models
+                    .iter()
+                    .map(|m| m.log_prob_soft_sequence(&observations))
+                    .collect();
+                (seq.class_name, probs)
+            }
+            None => {
+                let seq = sequence::load(filename)?;
+                let probs = models.iter().map(|m| m.log_prob_sequence(&seq)).collect();
+                (seq.class_name, probs)
+            }
+        };
+
+        let class_id_opt = &models.iter().position(|m| m.class_name == class_name);
         if let Some(class_id) = *class_id_opt {
-            let probs: Vec<f64> = models.iter().map(|m| m.log_prob_sequence(&seq)).collect();
-            c12n.add_case(class_id, &seq.class_name, probs, show_ranked, || {
-                format!("\n{}: '{}'\n", filename, seq.class_name)
+            c12n.add_case(class_id, &class_name, probs, show_ranked, || {
+                format!("\n{}: '{}'\n", filename, class_name)
             });
         }
     }
@@ -151,3 +178,37 @@ pub fn classify(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_prob_soft_sequence() {
+        let nbayes = NBayes {
+            class_name: "c".to_string(),
+            total_symbols: 7,
+            frequencies: vec![4, 2, 1],
+        };
+        let seq = sequence::Sequence {
+            class_name: "c".to_string(),
+            codebook_size: 3,
+            symbols: vec![0, 1, 1, 2, 0],
+        };
+
+        let hard: Vec<Vec<(usize, f64)>> = seq
+            .symbols
+            .iter()
+            .map(|s| vec![(*s as usize, 1.0)])
+            .collect();
+        let expected = nbayes.log_prob_sequence(&seq);
+        assert!((nbayes.log_prob_soft_sequence(&hard) - expected).abs() < 1e-12);
+
+        // a frame split between two symbols: weighted sum of their probabilities
+        let soft = vec![vec![(0, 0.25), (2, 0.75)]];
+        let expected = (0.25 * nbayes.prob_symbol(0) + 0.75 * nbayes.prob_symbol(2)).log10();
+        assert!((nbayes.log_prob_soft_sequence(&soft) - expected).abs() < 1e-12);
+
+        assert_eq!(nbayes.log_prob_soft_sequence(&[]), 0.0);
+    }
+}
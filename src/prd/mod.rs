@@ -3,17 +3,21 @@ extern crate serde;
 
 use std::error::Error;
 use std::fs::File;
+use std::io::BufRead;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use clap::StructOpt;
 
 use crate::ecoz2_lib::prd_show_file;
 use crate::lpc::lpca_cepstrum_rs::lpca_get_cepstrum;
 use crate::lpc::lpca_r_rs::lpca_r;
+use crate::utl;
 
 use self::EcozPrdCommand::Show;
 
+const PREDICTOR_IDENT: &str = "<predictor>";
+
 #[derive(StructOpt, Debug)]
 pub struct PrdMainOpts {
     #[structopt(subcommand)]
@@ -236,7 +240,8 @@ impl Predictor {
         predictors
     }
 
-    fn get_cepstrum(&mut self, q: usize) -> Vec<Vec<f64>> {
+    /// Cepstral coefficients `c[0 .. q]` of each vector (`q` > prediction order).
+    pub fn get_cepstrum(&self, q: usize) -> Vec<Vec<f64>> {
         let p = self.prediction_order;
         debug_assert!(p < q);
         let mut cepstra = Vec::new();
@@ -260,6 +265,21 @@ impl Predictor {
         cepstra
     }
 
+    /// The class name of this predictor or, if not set (`_`, as with `lpc --zrs`),
+    /// the name of the directory containing the given predictor file
+    /// (`data/predictors/<class>/<selection>.prd`).
+    pub fn resolved_class_name(&self, filename: &Path) -> String {
+        if !self.class_name.is_empty() && self.class_name != "_" {
+            return self.class_name.clone();
+        }
+        filename
+            .parent()
+            .and_then(|dir| dir.file_name())
+            .and_then(|name| name.to_str())
+            .unwrap_or("_")
+            .to_string()
+    }
+
     fn get_reflections(&mut self) -> Vec<Vec<f64>> {
         let p = self.prediction_order;
         let mut reflections = Vec::new();
@@ -273,9 +293,49 @@ impl Predictor {
     }
 }
 
+/// Loads a predictor file, either as generated by the Rust implementation
+/// (CBOR) or by the C implementation (legacy format, see `load_legacy`).
 pub fn load(filename: &str) -> Result<Predictor, Box<dyn Error>> {
     let f = File::open(filename)?;
-    let br = BufReader::new(f);
+    let mut br = BufReader::new(f);
+    if br.fill_buf()?.starts_with(PREDICTOR_IDENT.as_bytes()) {
+        return load_legacy(filename, &mut br);
+    }
     let predictor = serde_cbor::from_reader(br)?;
     Ok(predictor)
 }
+
+/// Loads a predictor in the format of the C implementation:
+///
+/// ```text
+///   ident      : "<predictor>"  (16 bytes, \0 padded)
+///   class_name : (96 bytes, \0 padded)
+///   T          : u32
+///   P          : u32
+///   vectors    : T x (P + 1) f64
+/// ```
+fn load_legacy(filename: &str, br: &mut BufReader<File>) -> Result<Predictor, Box<dyn Error>> {
+    let ident = utl::read_file_ident(br)?;
+    if !ident.starts_with(PREDICTOR_IDENT) {
+        return Err(format!("{}: Not a predictor", filename).into());
+    }
+
+    let class_name = utl::read_class_name(br)?;
+    let num_vectors = utl::read_u32(br)? as usize;
+    let prediction_order = utl::read_u32(br)? as usize;
+
+    let mut vectors = Vec::with_capacity(num_vectors);
+    for _ in 0..num_vectors {
+        let mut vector = Vec::with_capacity(prediction_order + 1);
+        for _ in 0..=prediction_order {
+            vector.push(utl::read_f64(br)?);
+        }
+        vectors.push(vector);
+    }
+
+    Ok(Predictor {
+        class_name,
+        prediction_order,
+        vectors,
+    })
+}
@@ -9,8 +9,11 @@ use clap::StructOpt;
 //use utl;
 
 use self::EcozSeqCommand::Show;
+use crate::hmm::model;
 use crate::utl;
 
+mod states;
+
 #[derive(StructOpt, Debug)]
 pub struct SeqMainOpts {
     #[structopt(subcommand)]
@@ -26,12 +29,12 @@ enum EcozSeqCommand {
 
 #[derive(StructOpt, Debug)]
 pub struct SeqShowOpts {
-    // The TODO's because of pending impl on rust side.
-    /// (TODO) Show associated probabilities
+    /// Show the log probability of the sequence under the `--hmm` model
     #[structopt(short = 'P')]
     pub with_prob: bool,
 
-    /// (TODO) Show most likely state sequence
+    /// Show the most likely state sequence under the `--hmm` model,
+    /// as runs `<state>x<length>`
     #[structopt(short = 'Q')]
     pub gen_q_opt: bool,
 
@@ -39,10 +42,32 @@ pub struct SeqShowOpts {
     #[structopt(short = 'c')]
     pub no_sequence: bool,
 
-    /// (TODO) HMM model
+    /// HMM model for `-P`, `-Q`, `--json` and `--csv`
     #[structopt(long, parse(from_os_str))]
     pub hmm: Option<PathBuf>,
 
+    /// Save the log probability, state sequence and state runs
+    /// for all the given sequences to the given JSON file
+    /// (use `-` for standard output). Requires `--hmm`.
+    #[structopt(long, name = "json-file", parse(from_os_str))]
+    pub json: Option<PathBuf>,
+
+    /// Save the state runs (state, start frame, number of frames and
+    /// time span in milliseconds) for all the given sequences
+    /// to the given CSV file. Requires `--hmm`.
+    #[structopt(long, name = "csv-file", parse(from_os_str))]
+    pub csv: Option<PathBuf>,
+
+    /// Analysis window length in milliseconds used to generate the
+    /// sequences, to report the time spans of the state runs
+    #[structopt(short = 'W', long, default_value = "45")]
+    pub window_length_ms: usize,
+
+    /// Window offset length in milliseconds used to generate the
+    /// sequences, to report the time spans of the state runs
+    #[structopt(short = 'O', long, default_value = "15")]
+    pub offset_length_ms: usize,
+
     /// Only show length of the sequence
     #[structopt(short = 'L')]
     pub only_length: bool,
@@ -117,10 +142,37 @@ pub fn seq_show(opts: &SeqShowOpts) -> Result<(), Box<dyn Error>> {
         }
     }
 
+    let hmm = match &opts.hmm {
+        Some(hmm_filename) => Some(model::load(hmm_filename.to_str().unwrap())?),
+        None => {
+            if opts.with_prob || opts.gen_q_opt || opts.json.is_some() || opts.csv.is_some() {
+                return Err("--hmm required for -P, -Q, --json or --csv".into());
+            }
+            None
+        }
+    };
+    let timing = states::FrameTiming {
+        window_length_ms: opts.window_length_ms,
+        offset_length_ms: opts.offset_length_ms,
+    };
+
     // NOTE here the gathered sequences are just as explicitly given
+    let mut all_decoded = Vec::new();
     for seq_filename in &opts.seq_filenames {
         let mut seq = load(seq_filename.to_str().unwrap())?;
         seq.show(opts);
+        if let Some(hmm) = &hmm {
+            let decoded = states::decode(hmm, &seq, seq_filename, timing)?;
+            decoded.show(opts.with_prob, opts.gen_q_opt, opts.full);
+            all_decoded.push(decoded);
+        }
+    }
+
+    if let Some(json_filename) = &opts.json {
+        states::save_json(&all_decoded, json_filename)?;
+    }
+    if let Some(csv_filename) = &opts.csv {
+        states::save_runs_csv(&all_decoded, csv_filename)?;
     }
 
     // let SeqShowOpts {
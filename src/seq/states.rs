@@ -0,0 +1,188 @@
+use std::error::Error;
+use std::path::Path;
+
+use itertools::Itertools;
+
+use crate::hmm::model::Hmm;
+use crate::sequence::Sequence;
+use crate::utl;
+
+/// A run of consecutive frames assigned to the same state.
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub struct StateRun {
+    pub state: usize,
+    pub start_frame: usize,
+    pub num_frames: usize,
+    pub start_ms: usize,
+    pub end_ms: usize,
+}
+
+/// Log probability and most likely state sequence of a sequence.
+#[derive(serde::Serialize)]
+pub struct Decoded {
+    pub filename: String,
+    pub class_name: String,
+    pub num_frames: usize,
+    pub log_prob: f64,
+    pub viterbi_log_prob: f64,
+    pub states: Vec<usize>,
+    pub runs: Vec<StateRun>,
+}
+
+/// Frame timing as used in the LPC analysis, to convert frame
+/// indices into time spans.
+#[derive(Clone, Copy)]
+pub struct FrameTiming {
+    pub window_length_ms: usize,
+    pub offset_length_ms: usize,
+}
+
+pub fn decode(
+    hmm: &Hmm,
+    seq: &Sequence,
+    seq_filename: &Path,
+    timing: FrameTiming,
+) -> Result<Decoded, Box<dyn Error>> {
+    if seq.codebook_size as usize != hmm.m {
+        return Err(format!(
+            "{}: conformity error: codebook size: {} != {}",
+            seq_filename.display(),
+            seq.codebook_size,
+            hmm.m
+        )
+        .into());
+    }
+    let log_prob = hmm.log_prob(&seq.symbols);
+    let (states, viterbi_log_prob) = hmm
+        .viterbi(&seq.symbols)
+        .unwrap_or((vec![], f64::NEG_INFINITY));
+    let runs = state_runs(&states, timing);
+    Ok(Decoded {
+        filename: seq_filename.display().to_string(),
+        class_name: seq.class_name.clone(),
+        num_frames: seq.symbols.len(),
+        log_prob,
+        viterbi_log_prob,
+        states,
+        runs,
+    })
+}
+
+/// Frame `t` spans `[t * offset, t * offset + window)` milliseconds.
+pub fn state_runs(states: &[usize], timing: FrameTiming) -> Vec<StateRun> {
+    let mut runs = Vec::new();
+    let mut start_frame = 0;
+    for (state, group) in &states.iter().chunk_by(|&&s| s) {
+        let num_frames = group.count();
+        let last_frame = start_frame + num_frames - 1;
+        runs.push(StateRun {
+            state,
+            start_frame,
+            num_frames,
+            start_ms: start_frame * timing.offset_length_ms,
+            end_ms: last_frame * timing.offset_length_ms + timing.window_length_ms,
+        });
+        start_frame += num_frames;
+    }
+    runs
+}
+
+impl Decoded {
+    pub fn show(&self, with_prob: bool, gen_q_opt: bool, full: bool) {
+        if with_prob {
+            println!("  log P = {}", self.log_prob);
+        }
+        if gen_q_opt {
+            let runs = if full || self.runs.len() <= 20 {
+                self.runs
+                    .iter()
+                    .map(|r| format!("{}x{}", r.state, r.num_frames))
+                    .join(" ")
+            } else {
+                let head = self.runs[..8].iter();
+                let tail = self.runs[self.runs.len() - 8..].iter();
+                let fmt = |r: &StateRun| format!("{}x{}", r.state, r.num_frames);
+                format!(
+                    "{} ... {}",
+                    head.map(fmt).join(" "),
+                    tail.map(fmt).join(" ")
+                )
+            };
+            println!(
+                "  Q* (log P = {}, {} runs): {}",
+                self.viterbi_log_prob,
+                self.runs.len(),
+                runs
+            );
+        }
+    }
+}
+
+/// Saves the state runs of all the decoded sequences to a CSV file.
+pub fn save_runs_csv(decoded: &[Decoded], filename: &Path) -> Result<(), Box<dyn Error>> {
+    let mut wrt = csv::Writer::from_path(filename)?;
+    wrt.write_record([
+        "filename",
+        "class",
+        "run",
+        "state",
+        "start_frame",
+        "num_frames",
+        "start_ms",
+        "end_ms",
+    ])?;
+    for d in decoded {
+        for (i, r) in d.runs.iter().enumerate() {
+            wrt.write_record(&[
+                d.filename.clone(),
+                d.class_name.clone(),
+                i.to_string(),
+                r.state.to_string(),
+                r.start_frame.to_string(),
+                r.num_frames.to_string(),
+                r.start_ms.to_string(),
+                r.end_ms.to_string(),
+            ])?;
+        }
+    }
+    wrt.flush()?;
+    println!("state runs saved to {:?}", filename);
+    Ok(())
+}
+
+/// Saves all the decoded sequences to a JSON file (`-` for stdout).
+pub fn save_json(decoded: &[Decoded], filename: &Path) -> Result<(), Box<dyn Error>> {
+    if filename.to_str() == Some("-") {
+        println!("{}", serde_json::to_string_pretty(decoded)?);
+    } else {
+        utl::save_json(&decoded, filename.to_str().unwrap())?;
+        println!("decoded sequences saved to {:?}", filename);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_runs() {
+        let timing = FrameTiming {
+            window_length_ms: 45,
+            offset_length_ms: 15,
+        };
+        let runs = state_runs(&[0, 0, 0, 2, 1, 1], timing);
+        assert_eq!(runs.len(), 3);
+        assert_eq!(
+            runs[1],
+            StateRun {
+                state: 2,
+                start_frame: 3,
+                num_frames: 1,
+                start_ms: 45,
+                end_ms: 90,
+            }
+        );
+        assert_eq!((runs[2].start_frame, runs[2].end_ms), (4, 120));
+    }
+}
@@ -0,0 +1,133 @@
+use std::error::Error;
+use std::str::FromStr;
+
+use rand::rngs::StdRng;
+use rand::RngExt;
+
+use super::Sequence;
+
+/// Length of the generated sequences.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Length {
+    /// `<n>`: always the same length.
+    Fixed(usize),
+
+    /// `uniform:<min>-<max>`: uniformly distributed in `min ..= max`.
+    Uniform(usize, usize),
+
+    /// `normal:<mean>,<sd>`: normally distributed (rounded, and at least 1).
+    Normal(f64, f64),
+}
+
+impl FromStr for Length {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "invalid length: {} (expecting <n>, uniform:<min>-<max> or normal:<mean>,<sd>)",
+                s
+            )
+        };
+        let length = if let Some(range) = s.strip_prefix("uniform:") {
+            let (min, max) = range.split_once('-').ok_or_else(err)?;
+            Length::Uniform(
+                min.trim().parse().map_err(|_| err())?,
+                max.trim().parse().map_err(|_| err())?,
+            )
+        } else if let Some(params) = s.strip_prefix("normal:") {
+            let (mean, sd) = params.split_once(',').ok_or_else(err)?;
+            Length::Normal(
+                mean.trim().parse().map_err(|_| err())?,
+                sd.trim().parse().map_err(|_| err())?,
+            )
+        } else {
+            Length::Fixed(s.trim().parse().map_err(|_| err())?)
+        };
+        match length {
+            Length::Fixed(n) if n > 0 => Ok(length),
+            Length::Uniform(min, max) if 0 < min && min <= max => Ok(length),
+            Length::Normal(mean, sd) if mean >= 1.0 && sd >= 0.0 => Ok(length),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl Length {
+    pub fn sample(&self, rng: &mut StdRng) -> usize {
+        match *self {
+            Length::Fixed(n) => n,
+            Length::Uniform(min, max) => rng.random_range(min..=max),
+            Length::Normal(mean, sd) => {
+                // Box-Muller
+                let u1: f64 = 1.0 - rng.random::<f64>();
+                let u2: f64 = rng.random();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                (mean + sd * z).round().max(1.0) as usize
+            }
+        }
+    }
+}
+
+/// Index sampled from the given (discrete) probability distribution.
+pub fn sample_index(rng: &mut StdRng, probs: impl Iterator<Item = f64>) -> usize {
+    let r: f64 = rng.random();
+    let mut cumulative = 0.0;
+    let mut last = 0;
+    for (i, p) in probs.enumerate() {
+        cumulative += p;
+        if r < cumulative {
+            return i;
+        }
+        if p > 0.0 {
+            last = i;
+        }
+    }
+    // rounding: the last index with nonzero probability
+    last
+}
+
+/// Saves `num_sequences` sequences generated with `generate` under
+/// `<out_dir>/M<M>/<class_name>/` as `gen_<i>.seq`.
+pub fn save_generated(
+    class_name: &str,
+    codebook_size: usize,
+    num_sequences: usize,
+    out_dir: &str,
+    mut generate: impl FnMut() -> Vec<u16>,
+) -> Result<(), Box<dyn Error>> {
+    let dir = format!("{}/M{}/{}", out_dir, codebook_size, class_name);
+    std::fs::create_dir_all(&dir)?;
+    let mut total_length = 0;
+    for i in 0..num_sequences {
+        let sequence = Sequence {
+            class_name: class_name.to_string(),
+            codebook_size: codebook_size as u32,
+            symbols: generate(),
+        };
+        total_length += sequence.symbols.len();
+        sequence.save(&format!("{}/gen_{:04}.seq", dir, i))?;
+    }
+    println!(
+        "{} sequence(s) saved under {}/ (avg length: {:.1})",
+        num_sequences,
+        dir,
+        total_length as f64 / num_sequences.max(1) as f64
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length() {
+        assert_eq!("100".parse(), Ok(Length::Fixed(100)));
+        assert_eq!("uniform:5-9".parse(), Ok(Length::Uniform(5, 9)));
+        assert_eq!("normal:120,20".parse(), Ok(Length::Normal(120.0, 20.0)));
+        assert!("0".parse::<Length>().is_err());
+        assert!("uniform:9-5".parse::<Length>().is_err());
+        assert!("poisson:3".parse::<Length>().is_err());
+    }
+}
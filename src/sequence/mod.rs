@@ -1,12 +1,19 @@
 use std::error::Error;
 use std::fs::File;
 use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
 
 use itertools::Itertools;
 
 use crate::seq;
 use crate::utl;
 
+pub mod generate;
+
+const SEQUENCE_IDENT: &str = "<sequence>";
+const SOFT_SEQUENCE_IDENT: &str = "<softsequence>";
+
 #[derive(Debug)]
 pub struct Sequence {
     pub class_name: String,
@@ -15,6 +22,22 @@ pub struct Sequence {
 }
 
 impl Sequence {
+    /// Saves this sequence in the same format as the C implementation.
+    pub fn save(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        let f = File::create(filename)?;
+        let mut bw = BufWriter::new(f);
+
+        utl::write_file_ident(&mut bw, SEQUENCE_IDENT)?;
+        utl::write_class_name(&mut bw, &self.class_name)?;
+        utl::write_u32(&mut bw, self.symbols.len() as u32)?;
+        utl::write_u32(&mut bw, self.codebook_size)?;
+        for symbol in &self.symbols {
+            utl::write_u16(&mut bw, *symbol)?;
+        }
+        bw.flush()?;
+        Ok(())
+    }
+
     pub fn show(&mut self, opts: &seq::SeqShowOpts) {
         if opts.no_sequence {
             return;
@@ -51,7 +74,7 @@ pub fn load(filename: &str) -> Result<Sequence, Box<dyn Error>> {
     let mut br = BufReader::new(f);
 
     let ident = utl::read_file_ident(&mut br)?;
-    if !ident.starts_with("<sequence>") {
+    if !ident.starts_with(SEQUENCE_IDENT) {
         return Err(format!("{}: Not a sequence", filename).into());
     }
 
@@ -73,3 +96,189 @@ pub fn load(filename: &str) -> Result<Sequence, Box<dyn Error>> {
         symbols,
     })
 }
+
+/// A "soft" sequence: the `k` nearest symbols, along with their distortions
+/// (in increasing order), for each frame.
+///
+/// ```text
+///   ident      : "<softsequence>"  (16 bytes, \0 padded)
+///   class_name : (96 bytes, \0 padded)
+///   T          : u32
+///   M          : u32
+///   k          : u32
+///   frames     : T x k (symbol: u16, distortion: f64)
+/// ```
+#[derive(Debug)]
+pub struct SoftSequence {
+    pub class_name: String,
+    pub codebook_size: u32,
+    pub k: usize,
+    /// `(symbol, distortion)` for the `k` nearest codewords of each frame.
+    pub frames: Vec<Vec<(u16, f64)>>,
+}
+
+impl SoftSequence {
+    /// Soft observations: for each frame, the `k` symbols with weights
+    /// proportional to `exp(-(d - d_min) / tau)`, normalized to add up to 1,
+    /// where `tau` is `temperature` times the average distortion of the nearest
+    /// symbols in the sequence (which makes `temperature` independent of the
+    /// distortion measure). A temperature of 0 gives all weight to the nearest symbol.
+    pub fn observations(&self, temperature: f64) -> Vec<Vec<(usize, f64)>> {
+        let num_frames = self.frames.len().max(1) as f64;
+        let avg_nearest = self.frames.iter().map(|f| f[0].1).sum::<f64>() / num_frames;
+        let tau = temperature * avg_nearest;
+
+        self.frames
+            .iter()
+            .map(|frame| {
+                let d_min = frame[0].1;
+                let weights: Vec<f64> = frame
+                    .iter()
+                    .map(|(_, d)| {
+                        if tau > 0.0 {
+                            (-(d - d_min) / tau).exp()
+                        } else if *d == d_min {
+                            1.0
+                        } else {
+                            0.0
+                        }
+                    })
+                    .collect();
+                let sum: f64 = weights.iter().sum();
+                frame
+                    .iter()
+                    .zip(weights)
+                    .map(|((s, _), w)| (*s as usize, w / sum))
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        let f = File::create(filename)?;
+        let mut bw = BufWriter::new(f);
+
+        utl::write_file_ident(&mut bw, SOFT_SEQUENCE_IDENT)?;
+        utl::write_class_name(&mut bw, &self.class_name)?;
+        utl::write_u32(&mut bw, self.frames.len() as u32)?;
+        utl::write_u32(&mut bw, self.codebook_size)?;
+        utl::write_u32(&mut bw, self.k as u32)?;
+        for frame in &self.frames {
+            for (symbol, d) in frame {
+                utl::write_u16(&mut bw, *symbol)?;
+                utl::write_f64(&mut bw, *d)?;
+            }
+        }
+        bw.flush()?;
+        Ok(())
+    }
+}
+
+pub fn load_soft(filename: &str) -> Result<SoftSequence, Box<dyn Error>> {
+    let f = File::open(filename)?;
+    let mut br = BufReader::new(f);
+
+    let ident = utl::read_file_ident(&mut br)?;
+    if !ident.starts_with(SOFT_SEQUENCE_IDENT) {
+        return Err(format!("{}: Not a soft sequence", filename).into());
+    }
+
+    let class_name = utl::read_class_name(&mut br)?;
+    let len = utl::read_u32(&mut br)?;
+    let codebook_size = utl::read_u32(&mut br)?;
+    let k = utl::read_u32(&mut br)? as usize;
+
+    let mut frames = Vec::with_capacity(len as usize);
+    for _ in 0..len {
+        let mut frame = Vec::with_capacity(k);
+        for _ in 0..k {
+            let symbol = utl::read_u16(&mut br)?;
+            let d = utl::read_f64(&mut br)?;
+            frame.push((symbol, d));
+        }
+        frames.push(frame);
+    }
+
+    Ok(SoftSequence {
+        class_name,
+        codebook_size,
+        k,
+        frames,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let sequence = Sequence {
+            class_name: "some_class".to_string(),
+            codebook_size: 64,
+            symbols: vec![0, 63, 12, 12, 5],
+        };
+
+        let path = std::env::temp_dir().join("ecoz2_test_save_load.seq");
+        let filename = path.to_str().unwrap();
+        sequence.save(filename).unwrap();
+        let loaded = load(filename).unwrap();
+        std::fs::remove_file(filename).unwrap();
+
+        assert_eq!(loaded.class_name, sequence.class_name);
+        assert_eq!(loaded.codebook_size, sequence.codebook_size);
+        assert_eq!(loaded.symbols, sequence.symbols);
+    }
+
+    fn soft_sequence() -> SoftSequence {
+        SoftSequence {
+            class_name: "some_class".to_string(),
+            codebook_size: 64,
+            k: 3,
+            frames: vec![
+                vec![(3, 1.0), (7, 2.0), (1, 3.0)],
+                vec![(5, 3.0), (3, 3.0), (60, 4.0)],
+            ],
+        }
+    }
+
+    #[test]
+    fn test_save_load_soft() {
+        let sequence = soft_sequence();
+        let path = std::env::temp_dir().join("ecoz2_test_save_load.sseq");
+        let filename = path.to_str().unwrap();
+        sequence.save(filename).unwrap();
+        let loaded = load_soft(filename).unwrap();
+        std::fs::remove_file(filename).unwrap();
+
+        assert_eq!(loaded.class_name, sequence.class_name);
+        assert_eq!(loaded.codebook_size, sequence.codebook_size);
+        assert_eq!(loaded.k, sequence.k);
+        assert_eq!(loaded.frames, sequence.frames);
+    }
+
+    #[test]
+    fn test_observations() {
+        let sequence = soft_sequence();
+
+        // temperature 0: all weight to the nearest symbol(s)
+        let observations = sequence.observations(0.0);
+        assert_eq!(observations[0], vec![(3, 1.0), (7, 0.0), (1, 0.0)]);
+        assert_eq!(observations[1], vec![(5, 0.5), (3, 0.5), (60, 0.0)]);
+
+        // tau = temperature * 2 (the average nearest distortion)
+        let observations = sequence.observations(0.5);
+        let weights = [1.0, (-1.0f64).exp(), (-2.0f64).exp()];
+        let sum: f64 = weights.iter().sum();
+        for ((_, w), expected) in observations[0].iter().zip(weights) {
+            assert!((w - expected / sum).abs() < 1e-12);
+        }
+        for frame in &observations {
+            assert!((frame.iter().map(|(_, w)| w).sum::<f64>() - 1.0).abs() < 1e-12);
+        }
+
+        // higher temperature: flatter weights
+        let hot = sequence.observations(10.0);
+        assert!(hot[0][2].1 > observations[0][2].1);
+    }
+}
@@ -0,0 +1,132 @@
+use std::error::Error;
+use std::fmt::Write;
+use std::path::Path;
+
+use ndarray::prelude::*;
+
+/// Graphviz (DOT) digraph of a transition structure.
+///
+/// Only the transitions (and initial probabilities, shown as edges from
+/// a `start` point) with probability at least `threshold` are included.
+/// If `keep_all_states` is false, states without any included edge are omitted.
+/// `labels[i]` is the label of state `i` (lines separated by `\n`).
+pub fn transitions_dot(
+    name: &str,
+    labels: &[String],
+    pi: ArrayView1<f64>,
+    a: ArrayView2<f64>,
+    threshold: f64,
+    keep_all_states: bool,
+) -> String {
+    let n = labels.len();
+    let initial: Vec<usize> = (0..n).filter(|&i| pi[i] >= threshold).collect();
+    let edges: Vec<(usize, usize)> = (0..n)
+        .flat_map(|i| (0..n).map(move |j| (i, j)))
+        .filter(|&(i, j)| a[[i, j]] >= threshold)
+        .collect();
+    let included: Vec<bool> = (0..n)
+        .map(|i| {
+            keep_all_states
+                || initial.contains(&i)
+                || edges.iter().any(|&(from, to)| from == i || to == i)
+        })
+        .collect();
+
+    let mut dot = String::new();
+    writeln!(dot, "digraph \"{}\" {{", escape(name)).unwrap();
+    writeln!(dot, "  rankdir=LR;").unwrap();
+    writeln!(dot, "  node [shape=ellipse];").unwrap();
+    writeln!(dot, "  start [shape=point];").unwrap();
+    for (i, label) in labels.iter().enumerate() {
+        if included[i] {
+            writeln!(dot, "  s{} [label=\"{}\"];", i, escape(label)).unwrap();
+        }
+    }
+    for &i in &initial {
+        writeln!(dot, "  start -> s{} [label=\"{:.2}\"];", i, pi[i]).unwrap();
+    }
+    for &(i, j) in &edges {
+        let p = a[[i, j]];
+        writeln!(
+            dot,
+            "  s{} -> s{} [label=\"{:.2}\", penwidth={:.2}];",
+            i,
+            j,
+            p,
+            1.0 + 3.0 * p
+        )
+        .unwrap();
+    }
+    writeln!(dot, "}}").unwrap();
+    dot
+}
+
+/// Label lines `<symbol> (<prob>)` for the `top` most probable symbols in `b_row`
+/// (ignoring those that would be shown with probability 0.00).
+pub fn dominant_symbols(b_row: ArrayView1<f64>, top: usize) -> Vec<String> {
+    let mut symbols: Vec<usize> = (0..b_row.len()).filter(|&s| b_row[s] >= 0.005).collect();
+    symbols.sort_by(|&x, &y| b_row[y].total_cmp(&b_row[x]));
+    symbols
+        .into_iter()
+        .take(top)
+        .map(|s| format!("{} ({:.2})", s, b_row[s]))
+        .collect()
+}
+
+/// Writes the DOT graph to the given file, or to standard output if `-`.
+pub fn save_dot(dot: &str, filename: &Path) -> Result<(), Box<dyn Error>> {
+    if filename.to_str() == Some("-") {
+        print!("{}", dot);
+    } else {
+        std::fs::write(filename, dot)?;
+        println!("{} saved", filename.display());
+    }
+    Ok(())
+}
+
+/// Saves the matrix in long format (`from,to,prob`, all entries) for heatmap plotting.
+pub fn save_heatmap_csv(a: ArrayView2<f64>, filename: &Path) -> Result<(), Box<dyn Error>> {
+    let mut wrt = csv::Writer::from_path(filename)?;
+    wrt.write_record(["from", "to", "prob"])?;
+    for ((i, j), p) in a.indexed_iter() {
+        wrt.write_record(&[i.to_string(), j.to_string(), format!("{:e}", p)])?;
+    }
+    wrt.flush()?;
+    println!("{} saved", filename.display());
+    Ok(())
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transitions_dot() {
+        let labels = vec![
+            "s0".to_string(),
+            "s1\n3 (0.90)".to_string(),
+            "s2".to_string(),
+        ];
+        let pi = array![1.0, 0.0, 0.0];
+        let a = array![[0.5, 0.5, 0.0], [0.0, 0.99, 0.01], [0.0, 0.0, 1.0]];
+        let dot = transitions_dot("A", &labels, pi.view(), a.view(), 0.05, false);
+        assert!(dot.contains("start -> s0 [label=\"1.00\"]"));
+        assert!(dot.contains("s1 [label=\"s1\\n3 (0.90)\"]"));
+        assert!(dot.contains("s0 -> s1 "));
+        assert!(!dot.contains("s1 -> s2"));
+        assert!(dot.contains("s2 -> s2 "));
+
+        let b_row = array![0.1, 0.6, 0.3, 0.0];
+        assert_eq!(
+            dominant_symbols(b_row.view(), 2),
+            vec!["1 (0.60)".to_string(), "2 (0.30)".to_string()]
+        );
+        assert_eq!(dominant_symbols(array![0.001, 0.999].view(), 3).len(), 1);
+    }
+}
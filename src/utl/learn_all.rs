@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+use colored::*;
+
+use super::TTRow;
+
+/// Class name with its files.
+pub type ClassFiles = (String, Vec<PathBuf>);
+
+/// The `tt` (TRAIN or TEST) files from the given csv grouped by class,
+/// in class name order. Filenames are composed as in `get_files_from_csv`.
+pub fn get_class_files_from_csv(
+    filename: &Path,
+    tt: &str,
+    subdir: &str,
+    file_ext: &str,
+) -> Result<Vec<ClassFiles>, Box<dyn Error>> {
+    let br = BufReader::new(File::open(filename)?);
+    let mut rdr = csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .delimiter(b',')
+        .from_reader(br);
+
+    let mut groups: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for result in rdr.deserialize() {
+        let row: TTRow = result?;
+        if row.tt != tt {
+            continue;
+        }
+        let filename = format!(
+            "data/{}/{}/{}{}",
+            subdir, row.class, row.selection, file_ext
+        );
+        groups
+            .entry(row.class)
+            .or_default()
+            .push(PathBuf::from(filename));
+    }
+    if groups.is_empty() {
+        return Err(format!("No {} {} given in given file", tt, subdir).into());
+    }
+    Ok(groups.into_iter().collect())
+}
+
+/// Outcome of the training of one class.
+pub struct ClassOutcome {
+    pub class_name: String,
+    pub num_sequences: usize,
+    pub elapsed_secs: f64,
+    /// Description of the trained model (e.g., its filename), or the error.
+    pub result: Result<String, String>,
+}
+
+/// Trains every class with `learn` (given the class name and its files),
+/// using up to `max_threads` classes at a time (at least one), and prints
+/// a summary table. Returns an error if any class failed.
+pub fn learn_all<F>(
+    groups: &[ClassFiles],
+    max_threads: usize,
+    learn: F,
+) -> Result<Vec<ClassOutcome>, Box<dyn Error>>
+where
+    F: Fn(&str, &[PathBuf]) -> Result<String, Box<dyn Error>> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let outcomes: Mutex<Vec<ClassOutcome>> = Mutex::new(Vec::new());
+    let before = Instant::now();
+
+    thread::scope(|s| {
+        for _ in 0..max_threads.clamp(1, groups.len().max(1)) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let Some((class_name, filenames)) = groups.get(i) else {
+                    break;
+                };
+                let start = Instant::now();
+                let result = match filenames.iter().find(|f| !f.exists()) {
+                    Some(missing) => Err(format!("{}: not found", missing.display())),
+                    None => learn(class_name, filenames).map_err(|e| e.to_string()),
+                };
+                outcomes.lock().unwrap().push(ClassOutcome {
+                    class_name: class_name.clone(),
+                    num_sequences: filenames.len(),
+                    elapsed_secs: start.elapsed().as_secs_f64(),
+                    result,
+                });
+            });
+        }
+    });
+
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by(|a, b| a.class_name.cmp(&b.class_name));
+    show_summary(&outcomes, before.elapsed().as_secs_f64());
+
+    let failed = outcomes.iter().filter(|o| o.result.is_err()).count();
+    if failed > 0 {
+        return Err(format!("{} of {} classes failed", failed, outcomes.len()).into());
+    }
+    Ok(outcomes)
+}
+
+fn show_summary(outcomes: &[ClassOutcome], total_secs: f64) {
+    let margin = outcomes
+        .iter()
+        .map(|o| o.class_name.len())
+        .max()
+        .unwrap_or(0)
+        .max(5);
+    println!(
+        "\n{:margin$}  {:>9}  {:>9}  model",
+        "class",
+        "sequences",
+        "secs",
+        margin = margin
+    );
+    for o in outcomes {
+        let result = match &o.result {
+            Ok(model) => model.normal(),
+            Err(err) => err.red(),
+        };
+        println!(
+            "{:margin$}  {:>9}  {:>9.2}  {}",
+            o.class_name,
+            o.num_sequences,
+            o.elapsed_secs,
+            result,
+            margin = margin
+        );
+    }
+    println!(
+        "\n{} classes trained in {:.2}s",
+        outcomes.iter().filter(|o| o.result.is_ok()).count(),
+        total_secs
+    );
+}
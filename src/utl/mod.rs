@@ -14,6 +14,9 @@ use byteorder::*;
 
 use self::walkdir::WalkDir;
 
+pub mod dot;
+pub mod learn_all;
+
 // first few defs to deal with files generated from C version
 
 pub const FILE_IDENT_LEN: usize = 16;
@@ -54,6 +57,55 @@ pub fn read_u16(br: &mut BufReader<File>) -> Result<u16, Box<dyn Error>> {
     }
 }
 
+pub fn read_f64(br: &mut BufReader<File>) -> Result<f64, Box<dyn Error>> {
+    match br.read_f64::<LittleEndian>() {
+        Ok(v) => Ok(v),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn write_file_ident(bw: &mut BufWriter<File>, ident: &str) -> Result<(), Box<dyn Error>> {
+    write_fixed_size_string(bw, ident, FILE_IDENT_LEN)
+}
+
+pub fn write_class_name(bw: &mut BufWriter<File>, class_name: &str) -> Result<(), Box<dyn Error>> {
+    write_fixed_size_string(bw, class_name, MAX_CLASS_NAME_LEN)
+}
+
+fn write_fixed_size_string(
+    bw: &mut BufWriter<File>,
+    s: &str,
+    fixed_len: usize,
+) -> Result<(), Box<dyn Error>> {
+    let mut bytes = s.as_bytes().to_vec();
+    // note: always leaving room for the \0 byte:
+    bytes.resize(fixed_len - 1, 0);
+    bytes.push(0);
+    bw.write_all(&bytes)?;
+    Ok(())
+}
+
+pub fn write_u32(bw: &mut BufWriter<File>, v: u32) -> Result<(), Box<dyn Error>> {
+    match bw.write_u32::<LittleEndian>(v) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn write_u16(bw: &mut BufWriter<File>, v: u16) -> Result<(), Box<dyn Error>> {
+    match bw.write_u16::<LittleEndian>(v) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn write_f64(bw: &mut BufWriter<File>, v: f64) -> Result<(), Box<dyn Error>> {
+    match bw.write_f64::<LittleEndian>(v) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
 /// General "resolution" for a file listing including case with a single given
 /// `.csv` indicating such list plus some filtering (tt, class_name).
 ///
@@ -274,6 +326,51 @@ pub fn save_json<T: serde::Serialize>(model: &T, filename: &str) -> Result<(), B
     Ok(())
 }
 
+/// Saves the given rows as a 2-D `f64` array in NPY (version 1.0) format.
+pub fn save_npy(rows: &[Vec<f64>], filename: &Path) -> Result<(), Box<dyn Error>> {
+    let num_cols = rows.first().map_or(0, |row| row.len());
+    if rows.iter().any(|row| row.len() != num_cols) {
+        return Err("save_npy: rows must have the same length".into());
+    }
+
+    let mut header = format!(
+        "{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, {}), }}",
+        rows.len(),
+        num_cols
+    );
+    // magic (6) + version (2) + header length (2) + header, padded to a multiple of 64:
+    let total_len = (10 + header.len() + 1).div_ceil(64) * 64;
+    header.push_str(&" ".repeat(total_len - 10 - header.len() - 1));
+    header.push('\n');
+
+    let f = File::create(filename)?;
+    let mut bw = BufWriter::new(f);
+    bw.write_all(b"\x93NUMPY\x01\x00")?;
+    bw.write_u16::<LittleEndian>(header.len() as u16)?;
+    bw.write_all(header.as_bytes())?;
+    for row in rows {
+        for v in row {
+            bw.write_f64::<LittleEndian>(*v)?;
+        }
+    }
+    bw.flush()?;
+    Ok(())
+}
+
+pub fn save_csv(
+    header: &[String],
+    rows: &[Vec<f64>],
+    filename: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_path(filename)?;
+    wtr.write_record(header)?;
+    for row in rows {
+        wtr.write_record(row.iter().map(|v| v.to_string()))?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
 pub fn to_pickle<T: serde::Serialize>(obj: &T, filename: &Path) -> Result<(), Box<dyn Error>> {
     let serialized = serde_pickle::to_vec(&obj, serde_pickle::SerOptions::new())?;
     let f = File::create(filename)?;
@@ -0,0 +1,185 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::c12n;
+use crate::prd;
+
+use super::codebook::{self, Codebook};
+
+/// Rust implementation of the VQ based classification.
+///
+/// Each predictor file is scored against each class codebook by the average
+/// distortion of its vectors, the best model being the one with the lowest
+/// distortion. The class of a predictor file is that of the predictor or,
+/// if not set, the name of the directory containing the file.
+/// Results are reported with `C12nResults` under the base name `vq_<M>`.
+pub fn classify(
+    cb_filenames: Vec<PathBuf>,
+    prd_filenames: Vec<PathBuf>,
+    show_ranked: bool,
+) -> Result<(), Box<dyn Error>> {
+    println!("Loading codebooks");
+    let codebooks = cb_filenames
+        .iter()
+        .map(|n| codebook::load(n.to_str().unwrap()))
+        .collect::<Result<Vec<Codebook>, _>>()?;
+    if codebooks.is_empty() {
+        return Err("No codebooks given".into());
+    }
+
+    let prediction_order = codebooks[0].prediction_order;
+    let codebook_size = codebooks[0].size();
+    for (codebook, filename) in codebooks.iter().zip(&cb_filenames) {
+        if codebook.prediction_order != prediction_order || codebook.size() != codebook_size {
+            return Err(format!(
+                "conformity error: {}: P={} M={} (expecting P={} M={})",
+                filename.display(),
+                codebook.prediction_order,
+                codebook.size(),
+                prediction_order,
+                codebook_size
+            )
+            .into());
+        }
+        if codebook.distortion.name() != codebooks[0].distortion.name() {
+            return Err(format!(
+                "conformity error: {}: distortion {} (expecting {})",
+                filename.display(),
+                codebook.distortion.name(),
+                codebooks[0].distortion.name()
+            )
+            .into());
+        }
+    }
+
+    let model_class_names = codebooks.iter().map(|m| m.class_name.clone()).collect();
+    let mut c12n = c12n::C12nResults::new(model_class_names);
+
+    println!("Classifying predictors");
+    for prd_filename in &prd_filenames {
+        let filename = prd_filename.to_str().unwrap();
+        let predictor = prd::load(filename)?;
+        if predictor.prediction_order != prediction_order {
+            return Err(format!(
+                "conformity error: {}: P={} (expecting P={})",
+                filename, predictor.prediction_order, prediction_order
+            )
+            .into());
+        }
+        if predictor.vectors.is_empty() {
+            continue;
+        }
+        let class_name = predictor.resolved_class_name(prd_filename);
+
+        let class_id_opt = codebooks.iter().position(|m| m.class_name == class_name);
+        if let Some(class_id) = class_id_opt {
+            let probs = scores(&codebooks, &predictor.vectors);
+            c12n.add_case(class_id, &class_name, probs, show_ranked, || {
+                format!("\n{}: '{}'", filename, class_name)
+            });
+        }
+    }
+
+    println!();
+
+    let class_names: Vec<&String> = codebooks.iter().map(|m| &m.class_name).collect();
+    let out_base_name = format!("vq_{}", codebook_size);
+    c12n.report_results(class_names, out_base_name);
+
+    Ok(())
+}
+
+/// Score of the given (gain normalized) autocorrelation vectors against each
+/// of the codebooks, which must share the distortion measure: the negated
+/// average distortion, so the higher the better.
+fn scores(codebooks: &[Codebook], vectors: &[Vec<f64>]) -> Vec<f64> {
+    // all codebooks share the measure, so vectors are prepared only once:
+    let p = codebooks[0].prediction_order;
+    let distortion = codebooks[0].distortion;
+    let prepared: Vec<Vec<f64>> = vectors
+        .iter()
+        .map(|v| distortion.prepare_vector(p, v))
+        .collect();
+    let num_vectors = prepared.len() as f64;
+    codebooks
+        .iter()
+        .map(|codebook| {
+            let total: f64 = prepared.iter().map(|v| codebook.nearest(v).1).sum();
+            -total / num_vectors
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vq::distortion::{self, autocorrelation};
+
+    #[test]
+    fn test_classify_conformity() {
+        assert!(classify(vec![], vec![], false).is_err());
+
+        let dir = std::env::temp_dir();
+        let cb_filename = dir.join("ecoz2_test_classify.cbook");
+        let measure = distortion::by_name("lr").unwrap();
+        let reflections = vec![vec![0.0, 0.5, -0.25], vec![0.0, -0.1, 0.3]];
+        Codebook::new("some_class".to_string(), 2, reflections, measure)
+            .save(cb_filename.to_str().unwrap())
+            .unwrap();
+
+        // a predictor of order 3 against codebooks of order 2
+        let prd_filename = dir.join("ecoz2_test_classify.prd");
+        let predictor = prd::Predictor {
+            class_name: "some_class".to_string(),
+            prediction_order: 3,
+            vectors: vec![vec![1.0, 0.5, 0.2, 0.1]],
+        };
+        let f = std::fs::File::create(&prd_filename).unwrap();
+        serde_cbor::to_writer(f, &predictor).unwrap();
+
+        let result = classify(vec![cb_filename.clone()], vec![prd_filename.clone()], false);
+        std::fs::remove_file(cb_filename).unwrap();
+        std::fs::remove_file(prd_filename).unwrap();
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("conformity error"));
+    }
+
+    #[test]
+    fn test_scores() {
+        let p = 2;
+        let measure = distortion::by_name("lr").unwrap();
+        let codebooks = vec![
+            Codebook::new(
+                "A".to_string(),
+                p,
+                vec![vec![0.0, 0.8, -0.4], vec![0.0, 0.7, -0.5]],
+                measure,
+            ),
+            Codebook::new(
+                "B".to_string(),
+                p,
+                vec![vec![0.0, -0.8, 0.4], vec![0.0, -0.7, 0.5]],
+                measure,
+            ),
+        ];
+
+        // predictors near the codewords of each class:
+        let predictors = [
+            (0, vec![vec![0.0, 0.75, -0.45], vec![0.0, 0.8, -0.5]]),
+            (1, vec![vec![0.0, -0.75, 0.45], vec![0.0, -0.8, 0.5]]),
+        ];
+        for (class_id, reflections) in predictors {
+            let vectors: Vec<Vec<f64>> = reflections
+                .iter()
+                .map(|reflection| autocorrelation(p, reflection))
+                .collect();
+            let scores = scores(&codebooks, &vectors);
+            let best = (0..scores.len())
+                .max_by(|&a, &b| scores[a].total_cmp(&scores[b]))
+                .unwrap();
+            assert_eq!(best, class_id, "scores: {:?}", scores);
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::PathBuf;
+use std::thread;
+
+use crate::lpc::ref2raas_rs::ref2raas;
+use crate::utl;
+
+use super::distortion::{self, Distortion};
+
+const CODEBOOK_IDENT: &str = "<codebook>";
+
+/// A codebook, with the same contents and file format as in the C implementation:
+///
+/// ```text
+///   ident      : "<codebook>"  (16 bytes, \0 padded)
+///   class_name : (96 bytes, \0 padded)
+///   P          : u32
+///   M          : u32
+///   reflections: M x (P + 1) f64
+///   distortion : (16 bytes, \0 padded) only if not the likelihood ratio
+/// ```
+///
+/// All numbers in little endian. The raas are not stored but
+/// obtained from the reflection coefficients upon creation.
+/// The trailing distortion name is ignored by the C implementation,
+/// which always uses the likelihood ratio.
+#[derive(Debug)]
+pub struct Codebook {
+    pub class_name: String,
+    pub prediction_order: usize,
+
+    /// Reflection coefficients `[0 ..= P]` for each codeword (index 0 not used).
+    pub reflections: Vec<Vec<f64>>,
+
+    /// Autocorrelation of the predictor coefficients for each codeword.
+    pub raas: Vec<Vec<f64>>,
+
+    /// Distortion measure associated to this codebook.
+    pub distortion: &'static dyn Distortion,
+
+    /// Codewords as prepared for the distortion measure.
+    codewords: Vec<Vec<f64>>,
+}
+
+impl Codebook {
+    pub fn new(
+        class_name: String,
+        prediction_order: usize,
+        reflections: Vec<Vec<f64>>,
+        distortion: &'static dyn Distortion,
+    ) -> Self {
+        let p = prediction_order;
+        let mut pred = vec![0f64; p + 1];
+        let raas = reflections
+            .iter()
+            .map(|reflection| {
+                let mut raas = vec![0f64; p + 1];
+                ref2raas(p, reflection, &mut pred, &mut raas);
+                raas
+            })
+            .collect();
+
+        let codewords = reflections
+            .iter()
+            .map(|reflection| distortion.prepare_codeword(p, reflection))
+            .collect();
+
+        Codebook {
+            class_name,
+            prediction_order,
+            reflections,
+            raas,
+            distortion,
+            codewords,
+        }
+    }
+
+    /// Codebook size.
+    pub fn size(&self) -> usize {
+        self.raas.len()
+    }
+
+    /// Nearest codeword to the given vector, as prepared by the distortion measure.
+    /// Returns the index of the codeword and the corresponding distortion.
+    #[inline]
+    pub fn nearest(&self, vector: &[f64]) -> (usize, f64) {
+        let mut best = (0, f64::MAX);
+        for (i, codeword) in self.codewords.iter().enumerate() {
+            let d = self.distortion.distortion(vector, codeword);
+            if d < best.1 {
+                best = (i, d);
+            }
+        }
+        best
+    }
+
+    /// Distortion between the given prepared vector and codeword `i`.
+    #[inline]
+    pub fn distortion_to(&self, vector: &[f64], i: usize) -> f64 {
+        self.distortion.distortion(vector, &self.codewords[i])
+    }
+
+    /// The `k` nearest codewords to the given prepared vector, in increasing
+    /// order of distortion.
+    pub fn nearest_k(&self, vector: &[f64], k: usize) -> Vec<(usize, f64)> {
+        let mut all: Vec<(usize, f64)> = self
+            .codewords
+            .iter()
+            .enumerate()
+            .map(|(i, codeword)| (i, self.distortion.distortion(vector, codeword)))
+            .collect();
+        all.sort_by(|a, b| a.1.total_cmp(&b.1));
+        all.truncate(k);
+        all
+    }
+
+    /// Nearest codeword for each of the given prepared vectors,
+    /// with the work split across the available cores.
+    pub fn quantize(&self, vectors: &[Vec<f64>]) -> Vec<(usize, f64)> {
+        let cores = num_cpus::get();
+        let chunk_size = vectors.len().div_ceil(cores).max(1);
+
+        thread::scope(|s| {
+            let handles: Vec<_> = vectors
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    s.spawn(move || chunk.iter().map(|v| self.nearest(v)).collect::<Vec<_>>())
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        })
+    }
+
+    /// Exports the selected coefficient range `from ..= to` of all codewords
+    /// to any of the given files.
+    /// A negative `from` means 1 for reflections and 0 for raas;
+    /// a negative `to` means P.
+    /// An empty range (`from > to`) is an error.
+    /// JSON output includes both reflections and raas; CSV and NPY
+    /// only include the reflections, or the raas if `raas` is true.
+    /// JSON goes to stdout if its filename is `-`.
+    pub fn export(
+        &self,
+        from: i32,
+        to: i32,
+        raas: bool,
+        json: Option<PathBuf>,
+        csv: Option<PathBuf>,
+        npy: Option<PathBuf>,
+    ) -> Result<(), Box<dyn Error>> {
+        let p = self.prediction_order;
+        let select = |vectors: &[Vec<f64>], default_from: usize| {
+            let from = if from < 0 {
+                default_from
+            } else {
+                from as usize
+            };
+            let to = if to < 0 || to as usize > p {
+                p
+            } else {
+                to as usize
+            };
+            if from > to {
+                return Err(format!(
+                    "invalid coefficient range: {} ..= {} (P={})",
+                    from, to, p
+                ));
+            }
+            let selected: Vec<Vec<f64>> = vectors.iter().map(|v| v[from..=to].to_vec()).collect();
+            Ok((from, to, selected))
+        };
+
+        if let Some(json_filename) = json {
+            let (from, to, reflections) = select(&self.reflections, 1)?;
+            let (_, _, raas) = select(&self.raas, 0)?;
+            let export = CodebookExport {
+                class_name: &self.class_name,
+                prediction_order: p,
+                codebook_size: self.size(),
+                distortion: self.distortion.name(),
+                from,
+                to,
+                reflections,
+                raas,
+            };
+            if json_filename.to_str() == Some("-") {
+                println!("{}", serde_json::to_string_pretty(&export)?);
+            } else {
+                utl::save_json(&export, json_filename.to_str().unwrap())?;
+                println!("codebook exported to {:?}", json_filename);
+            }
+        }
+
+        let (name, from, to, vectors) = if raas {
+            let (from, to, vectors) = select(&self.raas, 0)?;
+            ("raas", from, to, vectors)
+        } else {
+            let (from, to, vectors) = select(&self.reflections, 1)?;
+            ("k", from, to, vectors)
+        };
+
+        if let Some(csv_filename) = csv {
+            let header: Vec<String> = (from..=to).map(|i| format!("{}{}", name, i)).collect();
+            utl::save_csv(&header, &vectors, &csv_filename)?;
+            println!("{} vector(s) saved to {:?}", vectors.len(), csv_filename);
+        }
+
+        if let Some(npy_filename) = npy {
+            utl::save_npy(&vectors, &npy_filename)?;
+            println!("{} vector(s) saved to {:?}", vectors.len(), npy_filename);
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        let f = File::create(filename)?;
+        let mut bw = BufWriter::new(f);
+
+        utl::write_file_ident(&mut bw, CODEBOOK_IDENT)?;
+        utl::write_class_name(&mut bw, &self.class_name)?;
+        utl::write_u32(&mut bw, self.prediction_order as u32)?;
+        utl::write_u32(&mut bw, self.size() as u32)?;
+        for reflection in &self.reflections {
+            for v in reflection {
+                utl::write_f64(&mut bw, *v)?;
+            }
+        }
+        if self.distortion.name() != distortion::LIKELIHOOD_RATIO.name() {
+            utl::write_file_ident(&mut bw, self.distortion.name())?;
+        }
+        bw.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize)]
+struct CodebookExport<'a> {
+    class_name: &'a str,
+    prediction_order: usize,
+    codebook_size: usize,
+    distortion: &'a str,
+    from: usize,
+    to: usize,
+    reflections: Vec<Vec<f64>>,
+    raas: Vec<Vec<f64>>,
+}
+
+pub fn load(filename: &str) -> Result<Codebook, Box<dyn Error>> {
+    let f = File::open(filename)?;
+    let mut br = BufReader::new(f);
+
+    let ident = utl::read_file_ident(&mut br)?;
+    if !ident.starts_with(CODEBOOK_IDENT) {
+        return Err(format!("{}: Not a codebook", filename).into());
+    }
+
+    let class_name = utl::read_class_name(&mut br)?;
+    let prediction_order = utl::read_u32(&mut br)? as usize;
+    let codebook_size = utl::read_u32(&mut br)? as usize;
+
+    let mut reflections = Vec::with_capacity(codebook_size);
+    for _ in 0..codebook_size {
+        let mut reflection = Vec::with_capacity(prediction_order + 1);
+        for _ in 0..=prediction_order {
+            reflection.push(utl::read_f64(&mut br)?);
+        }
+        reflections.push(reflection);
+    }
+
+    // no trailing name (as with codebooks from the C implementation)
+    // means the likelihood ratio:
+    let distortion: &'static dyn Distortion = if br.fill_buf()?.is_empty() {
+        &distortion::LIKELIHOOD_RATIO
+    } else {
+        let name = utl::read_file_ident(&mut br)?;
+        distortion::by_name(&name).map_err(|e| format!("{}: {}", filename, e))?
+    };
+
+    Ok(Codebook::new(
+        class_name,
+        prediction_order,
+        reflections,
+        distortion,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let reflections = vec![vec![0.0, 0.5, -0.25], vec![0.0, -0.1, 0.3]];
+        let path = std::env::temp_dir().join("ecoz2_test_save_load.cbook");
+        let filename = path.to_str().unwrap();
+
+        for name in distortion::NAMES {
+            let measure = distortion::by_name(name).unwrap();
+            let codebook = Codebook::new("some_class".to_string(), 2, reflections.clone(), measure);
+            codebook.save(filename).unwrap();
+            let loaded = load(filename).unwrap();
+            std::fs::remove_file(filename).unwrap();
+
+            assert_eq!(loaded.class_name, codebook.class_name);
+            assert_eq!(loaded.prediction_order, 2);
+            assert_eq!(loaded.reflections, codebook.reflections);
+            assert_eq!(loaded.raas, codebook.raas);
+            assert_eq!(loaded.distortion.name(), name);
+        }
+
+        // a truncated trailer is an error, not the default measure
+        let measure = distortion::by_name("cepstral").unwrap();
+        let codebook = Codebook::new("some_class".to_string(), 2, reflections, measure);
+        codebook.save(filename).unwrap();
+        let len = std::fs::metadata(filename).unwrap().len();
+        let f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(filename)
+            .unwrap();
+        f.set_len(len - 4).unwrap();
+        assert!(load(filename).is_err());
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_export_range() {
+        let reflections = vec![vec![0.0, 0.5, -0.25], vec![0.0, -0.1, 0.3]];
+        let codebook = Codebook::new(
+            "some_class".to_string(),
+            2,
+            reflections,
+            &distortion::LIKELIHOOD_RATIO,
+        );
+        let csv = std::env::temp_dir().join("ecoz2_test_export_range.csv");
+        for (from, to) in [(3, 1), (4, -1), (2, 1)] {
+            let result = codebook.export(from, to, false, None, Some(csv.clone()), None);
+            assert!(result.is_err(), "from={} to={}", from, to);
+        }
+        assert!(!csv.exists());
+    }
+}
@@ -0,0 +1,350 @@
+use std::fmt::Debug;
+
+use crate::lpc::lpca_cepstrum_rs::lpca_get_cepstrum;
+use crate::lpc::lpca_r_rs::lpca_r;
+use crate::lpc::ref2raas_rs::ref2raas;
+
+/// A distortion measure between training vectors and codewords.
+///
+/// Training vectors are given as autocorrelations normalized by their own
+/// prediction error (as stored in the predictor files), and codewords as
+/// reflection coefficients (as stored in the codebooks). Each measure first
+/// maps them to the representation it works on, so this conversion is done
+/// only once, and not for every distortion evaluation.
+pub trait Distortion: Sync + Debug {
+    /// Name as given in the command line and stored in the codebook.
+    fn name(&self) -> &'static str;
+
+    /// Representation of a (gain normalized) autocorrelation vector.
+    fn prepare_vector(&self, p: usize, rxg: &[f64]) -> Vec<f64>;
+
+    /// Representation of a codeword given by its reflection coefficients.
+    fn prepare_codeword(&self, p: usize, reflection: &[f64]) -> Vec<f64>;
+
+    /// Distortion between prepared vector and codeword.
+    fn distortion(&self, vector: &[f64], codeword: &[f64]) -> f64;
+
+    /// Centroid (as reflection coefficients) of a cell given the mean of its
+    /// prepared vectors, if it can be obtained in the measure's own domain.
+    /// By default, `None`, meaning the LPC model of the mean autocorrelation
+    /// (the centroid under the likelihood ratio) is to be used.
+    fn centroid(&self, _p: usize, _mean: &[f64]) -> Option<Vec<f64>> {
+        None
+    }
+}
+
+/// The default measure, as in the C implementation.
+pub static LIKELIHOOD_RATIO: LikelihoodRatio = LikelihoodRatio;
+
+static LOG_LIKELIHOOD_RATIO: LogLikelihoodRatio = LogLikelihoodRatio;
+static SYMMETRIC_LOG_LIKELIHOOD_RATIO: SymmetricLogLikelihoodRatio = SymmetricLogLikelihoodRatio;
+static CEPSTRAL: Cepstral = Cepstral;
+static EUCLIDEAN: Euclidean = Euclidean;
+
+pub const NAMES: [&str; 5] = ["lr", "log-lr", "sym-log-lr", "cepstral", "euclidean"];
+
+pub fn by_name(name: &str) -> Result<&'static dyn Distortion, String> {
+    match name {
+        "lr" => Ok(&LIKELIHOOD_RATIO),
+        "log-lr" => Ok(&LOG_LIKELIHOOD_RATIO),
+        "sym-log-lr" => Ok(&SYMMETRIC_LOG_LIKELIHOOD_RATIO),
+        "cepstral" => Ok(&CEPSTRAL),
+        "euclidean" => Ok(&EUCLIDEAN),
+        _ => Err(format!(
+            "unrecognized distortion: {} ({})",
+            name,
+            NAMES.join(", ")
+        )),
+    }
+}
+
+/// Likelihood ratio (the C `distortion`): the ratio of the prediction error
+/// with the codeword to the one with the vector's own predictor, minus 1.
+///
+/// Since both training vectors and codewords have unit gain, this is also the
+/// Itakura-Saito distortion between their spectra (without gain optimization;
+/// see `LogLikelihoodRatio` for the gain optimized one).
+#[derive(Debug)]
+pub struct LikelihoodRatio;
+
+impl Distortion for LikelihoodRatio {
+    fn name(&self) -> &'static str {
+        "lr"
+    }
+
+    fn prepare_vector(&self, _p: usize, rxg: &[f64]) -> Vec<f64> {
+        rxg.to_vec()
+    }
+
+    fn prepare_codeword(&self, p: usize, reflection: &[f64]) -> Vec<f64> {
+        raas(p, reflection)
+    }
+
+    /// `rxg · raas - 1`, with `rxg` the autocorrelation of the vector,
+    /// normalized by its own prediction error (as stored in the predictor
+    /// files), and `raas` the autocorrelation of the codeword predictor
+    /// coefficients (see `ref2raas`).
+    /// Always >= 0, being 0 when the codeword is the optimal predictor for
+    /// the vector.
+    #[inline]
+    fn distortion(&self, vector: &[f64], codeword: &[f64]) -> f64 {
+        let sum: f64 = vector.iter().zip(codeword).map(|(r, a)| r * a).sum();
+        sum - 1.0f64
+    }
+}
+
+/// Log likelihood ratio `ln(rxg · raas)`, that is, the log of the ratio of the
+/// prediction error with the codeword to the one with the vector's own
+/// predictor (also known as the gain optimized Itakura-Saito distortion).
+/// Less sensitive than the likelihood ratio to large spectral mismatches.
+#[derive(Debug)]
+pub struct LogLikelihoodRatio;
+
+impl Distortion for LogLikelihoodRatio {
+    fn name(&self) -> &'static str {
+        "log-lr"
+    }
+
+    fn prepare_vector(&self, _p: usize, rxg: &[f64]) -> Vec<f64> {
+        rxg.to_vec()
+    }
+
+    fn prepare_codeword(&self, p: usize, reflection: &[f64]) -> Vec<f64> {
+        raas(p, reflection)
+    }
+
+    #[inline]
+    fn distortion(&self, vector: &[f64], codeword: &[f64]) -> f64 {
+        log_ratio(vector, codeword)
+    }
+}
+
+/// Average of the log likelihood ratios in both directions.
+///
+/// Prepared vectors are `rxg` followed by their own raas;
+/// prepared codewords are raas followed by their own (unit gain) autocorrelation.
+#[derive(Debug)]
+pub struct SymmetricLogLikelihoodRatio;
+
+impl Distortion for SymmetricLogLikelihoodRatio {
+    fn name(&self) -> &'static str {
+        "sym-log-lr"
+    }
+
+    fn prepare_vector(&self, p: usize, rxg: &[f64]) -> Vec<f64> {
+        let (reflection, _) = model(p, rxg);
+        let mut prepared = rxg.to_vec();
+        prepared.extend(raas(p, &reflection));
+        prepared
+    }
+
+    fn prepare_codeword(&self, p: usize, reflection: &[f64]) -> Vec<f64> {
+        let mut prepared = raas(p, reflection);
+        prepared.extend(autocorrelation(p, reflection));
+        prepared
+    }
+
+    #[inline]
+    fn distortion(&self, vector: &[f64], codeword: &[f64]) -> f64 {
+        let n = vector.len() / 2;
+        let d_vc = log_ratio(&vector[..n], &codeword[..n]);
+        let d_cv = log_ratio(&codeword[n..], &vector[n..]);
+        0.5 * (d_vc + d_cv)
+    }
+}
+
+/// Truncated cepstral distance: squared Euclidean distance between the
+/// cepstral coefficients `c[1 .. 2P]` of the LPC models.
+#[derive(Debug)]
+pub struct Cepstral;
+
+impl Distortion for Cepstral {
+    fn name(&self) -> &'static str {
+        "cepstral"
+    }
+
+    fn prepare_vector(&self, p: usize, rxg: &[f64]) -> Vec<f64> {
+        let (_, pred) = model(p, rxg);
+        cepstrum(p, &pred)
+    }
+
+    fn prepare_codeword(&self, p: usize, reflection: &[f64]) -> Vec<f64> {
+        let mut pred = vec![0f64; p + 1];
+        let mut raas = vec![0f64; p + 1];
+        ref2raas(p, reflection, &mut pred, &mut raas);
+        cepstrum(p, &pred)
+    }
+
+    #[inline]
+    fn distortion(&self, vector: &[f64], codeword: &[f64]) -> f64 {
+        squared_euclidean(vector, codeword)
+    }
+
+    /// The mean cepstrum, if it corresponds to a stable predictor.
+    fn centroid(&self, p: usize, mean: &[f64]) -> Option<Vec<f64>> {
+        // invert the recursion in lpca_get_cepstrum (with mean[i - 1] = c[i]):
+        let mut pred = vec![0f64; p + 1];
+        pred[0] = 1.0f64;
+        for i in 1..=p {
+            let mut sum = (i as f64) * mean[i - 1];
+            for k in 1..i {
+                sum += ((i - k) as f64) * mean[i - k - 1] * pred[k];
+            }
+            pred[i] = -sum;
+        }
+        reflections(p, pred)
+    }
+}
+
+/// Squared Euclidean distance between the reflection coefficients `k[1 ..= P]`.
+#[derive(Debug)]
+pub struct Euclidean;
+
+impl Distortion for Euclidean {
+    fn name(&self) -> &'static str {
+        "euclidean"
+    }
+
+    fn prepare_vector(&self, p: usize, rxg: &[f64]) -> Vec<f64> {
+        let (reflection, _) = model(p, rxg);
+        reflection[1..].to_vec()
+    }
+
+    fn prepare_codeword(&self, _p: usize, reflection: &[f64]) -> Vec<f64> {
+        reflection[1..].to_vec()
+    }
+
+    #[inline]
+    fn distortion(&self, vector: &[f64], codeword: &[f64]) -> f64 {
+        squared_euclidean(vector, codeword)
+    }
+
+    /// The mean reflection coefficients (always a stable predictor).
+    fn centroid(&self, _p: usize, mean: &[f64]) -> Option<Vec<f64>> {
+        let mut reflection = vec![0f64];
+        reflection.extend(mean);
+        Some(reflection)
+    }
+}
+
+#[inline]
+fn log_ratio(rxg: &[f64], raas: &[f64]) -> f64 {
+    let sum: f64 = rxg.iter().zip(raas).map(|(r, a)| r * a).sum();
+    sum.ln().max(0.0f64)
+}
+
+#[inline]
+fn squared_euclidean(u: &[f64], v: &[f64]) -> f64 {
+    u.iter().zip(v).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+/// Reflection and predictor coefficients of the given autocorrelation.
+fn model(p: usize, rxg: &[f64]) -> (Vec<f64>, Vec<f64>) {
+    let mut reflection = vec![0f64; p + 1];
+    let mut pred = vec![0f64; p + 1];
+    lpca_r(p, rxg, &mut reflection, &mut pred);
+    (reflection, pred)
+}
+
+fn raas(p: usize, reflection: &[f64]) -> Vec<f64> {
+    let mut pred = vec![0f64; p + 1];
+    let mut raas = vec![0f64; p + 1];
+    ref2raas(p, reflection, &mut pred, &mut raas);
+    raas
+}
+
+/// Reflection coefficients of the given predictor (step-down recursion),
+/// or `None` if the predictor is not stable.
+fn reflections(p: usize, mut pred: Vec<f64>) -> Option<Vec<f64>> {
+    let mut reflection = vec![0f64; p + 1];
+    for k in (1..=p).rev() {
+        let akk = pred[k];
+        if akk.abs() >= 1.0f64 {
+            return None;
+        }
+        reflection[k] = akk;
+        let den = 1.0f64 - akk * akk;
+        for i in 1..=k >> 1 {
+            let ai = pred[i];
+            let aj = pred[k - i];
+            pred[i] = (ai - akk * aj) / den;
+            pred[k - i] = (aj - akk * ai) / den;
+        }
+    }
+    Some(reflection)
+}
+
+/// Autocorrelation `r[0 ..= P]` of the unit gain all-pole model with the given
+/// reflection coefficients (the inverse of the recursion in `lpca_r`).
+pub fn autocorrelation(p: usize, reflection: &[f64]) -> Vec<f64> {
+    let mut r = vec![0f64; p + 1];
+    let mut a = vec![0f64; p + 1];
+
+    // r[0] such that the final prediction error is 1:
+    r[0] = 1.0f64
+        / reflection[1..=p]
+            .iter()
+            .map(|k| 1.0f64 - k * k)
+            .product::<f64>();
+
+    let mut pe = r[0];
+    a[0] = 1.0f64;
+    for k in 1..=p {
+        let akk = reflection[k];
+        let mut sum = 0.0f64;
+        for i in 1..k {
+            sum += a[k - i] * r[i];
+        }
+        r[k] = -akk * pe - sum;
+
+        a[k] = akk;
+        for i in 1..=k >> 1 {
+            let ai = a[i];
+            let aj = a[k - i];
+            a[i] = ai + akk * aj;
+            a[k - i] = aj + akk * ai;
+        }
+        pe *= 1.0f64 - akk * akk;
+    }
+    r
+}
+
+fn cepstrum(p: usize, pred: &[f64]) -> Vec<f64> {
+    let q = 2 * p;
+    let mut cepstrum = vec![0f64; q];
+    lpca_get_cepstrum(1.0f64, p, pred, q, &mut cepstrum);
+    cepstrum[1..].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The autocorrelation of a model must give back its reflection coefficients,
+    /// and every measure must give ~0 between a vector and its own model.
+    #[test]
+    fn test_own_model_distortion() {
+        let p = 4;
+        let reflection = vec![0.0, 0.6, -0.3, 0.2, -0.1];
+
+        let r = autocorrelation(p, &reflection);
+        let (reflection2, _) = model(p, &r);
+        for (k, k2) in reflection.iter().zip(&reflection2).skip(1) {
+            assert_approx_eq!(k, k2, 1e-12);
+        }
+
+        for name in NAMES {
+            let measure = by_name(name).unwrap();
+            let vector = measure.prepare_vector(p, &r);
+            let codeword = measure.prepare_codeword(p, &reflection);
+            let d = measure.distortion(&vector, &codeword);
+            assert_approx_eq!(d, 0f64, 1e-9);
+
+            if let Some(centroid) = measure.centroid(p, &vector) {
+                for (k, k2) in reflection.iter().zip(&centroid).skip(1) {
+                    assert_approx_eq!(k, k2, 1e-9);
+                }
+            }
+        }
+    }
+}
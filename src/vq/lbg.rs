@@ -0,0 +1,631 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Instant;
+
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
+
+use crate::comet_client::CometClient;
+use crate::lpc::lpca_r_rs::lpca_r;
+use crate::prd;
+
+use super::codebook::Codebook;
+use super::distortion::Distortion;
+
+/// Largest codebook size generated by the splitting procedure (as in the C code).
+const MAX_CODEBOOK_SIZE: usize = 2048;
+
+/// Relative perturbation of the reflection coefficients when splitting a codeword.
+const SPLIT_PERTURBATION: f64 = 0.01;
+
+/// Reflection coefficients are kept within this bound to guarantee a stable predictor.
+const MAX_REFLECTION: f64 = 0.999;
+
+/// Max number of additional Lloyd passes to get rid of empty cells
+/// once the distortion has converged (only with `repair_empty`).
+const MAX_REPAIR_PASSES: usize = 10;
+
+/// Initialization of the codebook for each size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Init {
+    /// Split each codeword of the previous codebook (the traditional LBG).
+    Split,
+
+    /// k-means++ seeding from the training vectors.
+    KMeansPP,
+}
+
+impl FromStr for Init {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "split" => Ok(Init::Split),
+            "kmeans++" => Ok(Init::KMeansPP),
+            _ => Err(format!("unrecognized init: {} (split, kmeans++)", s)),
+        }
+    }
+}
+
+/// Parameters for the codebook training.
+pub struct LbgParams {
+    /// Convergence threshold on the relative decrease of the total distortion.
+    pub epsilon: f64,
+
+    pub init: Init,
+
+    /// Number of trainings for each codebook size; the one with the lowest
+    /// distortion is kept. With `Init::Split`, the first restart uses the
+    /// usual perturbation and the others a random one.
+    pub restarts: usize,
+
+    /// Reassign empty cells to the training vectors with highest distortion.
+    pub repair_empty: bool,
+
+    pub seed: u64,
+
+    /// Distortion measure for the assignment of training vectors to cells.
+    pub distortion: &'static dyn Distortion,
+}
+
+/// Statistics of the codebook obtained for a particular size.
+struct LbgStep {
+    codebook_size: usize,
+    passes: usize,
+    avg_distortion: f64,
+    sigma: f64,
+    inertia: f64,
+    empty_cells: usize,
+}
+
+/// Rust implementation of the LBG codebook training.
+///
+/// Starting from the centroid of all training vectors (or from the given base
+/// codebook), each codebook is split and refined with the generalized Lloyd
+/// iteration until the relative decrease of the total distortion is less than
+/// `epsilon`. Codebooks of sizes 2, 4, .., `MAX_CODEBOOK_SIZE` are saved under
+/// `data/codebooks/<class_name>/`, along with an `eps_<epsilon>.rpt` report of
+/// the average distortion, sigma and inertia for each size.
+///
+/// Centroids are obtained in the domain of the distortion measure when it
+/// provides one (cepstral, euclidean), and otherwise as under the likelihood
+/// ratio, that is, from the average autocorrelation in each cell (see `centroids`).
+pub fn learn(
+    base_codebook: Option<Codebook>,
+    prediction_order: usize,
+    codebook_class_name: String,
+    predictor_filenames: Vec<PathBuf>,
+    params: &LbgParams,
+    exp_key: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let epsilon = params.epsilon;
+
+    let vectors = load_training_vectors(prediction_order, &predictor_filenames)?;
+    if vectors.is_empty() {
+        return Err("No training vectors".into());
+    }
+
+    let codebook_dir = format!("data/codebooks/{}", codebook_class_name);
+    std::fs::create_dir_all(&codebook_dir)?;
+
+    println!("Codebook generation:\n");
+    println!("{} training vectors (ε={})", vectors.len(), epsilon);
+    println!(
+        "distortion={} init={:?} restarts={} repair_empty={} seed={}",
+        params.distortion.name(),
+        params.init,
+        params.restarts,
+        params.repair_empty,
+        params.seed
+    );
+
+    let report_filename = format!("{}/eps_{}.rpt", codebook_dir, epsilon);
+    let mut report = BufWriter::new(File::create(&report_filename)?);
+    println!("Report: {}", report_filename);
+    writeln!(report, "# class_name='{}'", codebook_class_name)?;
+    writeln!(
+        report,
+        "# P={} T={} epsilon={}",
+        prediction_order,
+        vectors.len(),
+        epsilon
+    )?;
+    writeln!(
+        report,
+        "# distortion={} init={:?} restarts={} repair_empty={} seed={}",
+        params.distortion.name(),
+        params.init,
+        params.restarts,
+        params.repair_empty,
+        params.seed
+    )?;
+    writeln!(report, "M,passes,avg_distortion,sigma,inertia,empty_cells")?;
+
+    let comet_client = CometClient::new(exp_key);
+    comet_client.log_parameter("P", &prediction_order);
+    comet_client.log_parameter("epsilon", &epsilon);
+    comet_client.log_parameter("distortion", &params.distortion.name());
+
+    let mut rng = StdRng::seed_from_u64(params.seed);
+
+    let before = Instant::now();
+
+    let measure = params.distortion;
+    let prepared: Vec<Vec<f64>> = vectors
+        .iter()
+        .map(|v| measure.prepare_vector(prediction_order, v))
+        .collect();
+
+    let mut codebook = match base_codebook {
+        Some(base_codebook) => base_codebook,
+        None => {
+            let all_cells = vec![0; vectors.len()];
+            let (reflections, _) = centroids(
+                prediction_order,
+                measure,
+                &vectors,
+                &prepared,
+                &all_cells,
+                1,
+            );
+            Codebook::new(codebook_class_name, prediction_order, reflections, measure)
+        }
+    };
+
+    while codebook.size() < MAX_CODEBOOK_SIZE {
+        let m = 2 * codebook.size();
+
+        let filename = format!("{}/eps_{}_M_{:04}.cbook", codebook_dir, epsilon, m);
+        println!("{}", filename);
+
+        let (refined, step) = train_size(&codebook, m, &vectors, &prepared, params, &mut rng);
+        codebook = refined;
+
+        codebook.save(&filename)?;
+
+        writeln!(
+            report,
+            "{},{},{},{},{},{}",
+            step.codebook_size,
+            step.passes,
+            step.avg_distortion,
+            step.sigma,
+            step.inertia,
+            step.empty_cells
+        )?;
+        report.flush()?;
+
+        comet_client.log_vq_learn(
+            step.codebook_size as i32,
+            step.avg_distortion,
+            step.sigma,
+            step.inertia,
+        );
+    }
+
+    println!("codebook training took: {:.2?}", before.elapsed());
+    Ok(())
+}
+
+/// Codebook of size `m` (twice the size of the given one), as the best of
+/// the `params.restarts` trainings.
+fn train_size(
+    codebook: &Codebook,
+    m: usize,
+    vectors: &[Vec<f64>],
+    prepared: &[Vec<f64>],
+    params: &LbgParams,
+    rng: &mut StdRng,
+) -> (Codebook, LbgStep) {
+    let mut best: Option<(Codebook, LbgStep)> = None;
+    for restart in 0..params.restarts.max(1) {
+        let initial = match params.init {
+            Init::Split if restart == 0 => split(codebook, None),
+            Init::Split => split(codebook, Some(rng)),
+            Init::KMeansPP => kmeans_pp(codebook, m, vectors, prepared, rng),
+        };
+
+        let (refined, step) = lloyd(
+            initial,
+            vectors,
+            prepared,
+            params.epsilon,
+            params.repair_empty,
+        );
+
+        if params.restarts > 1 {
+            println!(
+                "  restart {}: avg_distortion={} empty_cells={}",
+                restart, step.avg_distortion, step.empty_cells
+            );
+        }
+        if best
+            .as_ref()
+            .is_none_or(|(_, b)| step.avg_distortion < b.avg_distortion)
+        {
+            best = Some((refined, step));
+        }
+    }
+    best.unwrap()
+}
+
+/// Loads the vectors from the given predictor files.
+fn load_training_vectors(
+    prediction_order: usize,
+    predictor_filenames: &[PathBuf],
+) -> Result<Vec<Vec<f64>>, Box<dyn Error>> {
+    let mut vectors = Vec::new();
+    for predictor_filename in predictor_filenames {
+        let filename = predictor_filename.to_str().unwrap();
+        let predictor = prd::load(filename)?;
+        if predictor.prediction_order != prediction_order {
+            return Err(format!(
+                "conformity error: {}: prediction order: {} != {}",
+                filename, predictor.prediction_order, prediction_order
+            )
+            .into());
+        }
+        vectors.extend(predictor.vectors);
+    }
+    Ok(vectors)
+}
+
+/// Splits each codeword into two by perturbing its reflection coefficients.
+/// With a random generator, the sign of the perturbation of each coefficient
+/// is chosen at random; otherwise, all coefficients are scaled up for one
+/// of the new codewords and scaled down for the other.
+fn split(codebook: &Codebook, mut rng: Option<&mut StdRng>) -> Codebook {
+    let mut reflections = Vec::with_capacity(2 * codebook.size());
+    for reflection in &codebook.reflections {
+        let signs: Vec<f64> = match rng.as_mut() {
+            Some(rng) => reflection
+                .iter()
+                .map(|_| if rng.random_bool(0.5) { 1.0 } else { -1.0 })
+                .collect(),
+            None => vec![1.0; reflection.len()],
+        };
+        for direction in [1.0, -1.0] {
+            let perturbed = reflection
+                .iter()
+                .zip(&signs)
+                .map(|(k, sign)| {
+                    let factor = 1.0 + direction * sign * SPLIT_PERTURBATION;
+                    (k * factor).clamp(-MAX_REFLECTION, MAX_REFLECTION)
+                })
+                .collect();
+            reflections.push(perturbed);
+        }
+    }
+    Codebook::new(
+        codebook.class_name.clone(),
+        codebook.prediction_order,
+        reflections,
+        codebook.distortion,
+    )
+}
+
+/// k-means++ seeding: the first codeword is the model of a training vector
+/// chosen uniformly at random; each subsequent one is the model of a training
+/// vector chosen with probability proportional to its distortion with respect
+/// to the codewords chosen so far.
+fn kmeans_pp(
+    codebook: &Codebook,
+    m: usize,
+    vectors: &[Vec<f64>],
+    prepared: &[Vec<f64>],
+    rng: &mut StdRng,
+) -> Codebook {
+    let p = codebook.prediction_order;
+    let measure = codebook.distortion;
+
+    let model = |vector: &[f64]| {
+        let mut reflection = vec![0f64; p + 1];
+        let mut pred = vec![0f64; p + 1];
+        lpca_r(p, vector, &mut reflection, &mut pred);
+        reflection
+    };
+
+    // distortion of each vector to its nearest seed so far
+    let mut min_distortions = vec![f64::INFINITY; vectors.len()];
+    let mut reflections: Vec<Vec<f64>> = Vec::with_capacity(m);
+    let mut add_seed = |chosen: usize, min_distortions: &mut Vec<f64>| {
+        let reflection = model(&vectors[chosen]);
+        let codeword = measure.prepare_codeword(p, &reflection);
+        reflections.push(reflection);
+        for (d, v) in min_distortions.iter_mut().zip(prepared) {
+            *d = d.min(measure.distortion(v, &codeword));
+        }
+    };
+
+    add_seed(rng.random_range(0..vectors.len()), &mut min_distortions);
+    for _ in 1..m {
+        let total: f64 = min_distortions.iter().sum();
+        let chosen = if total > 0.0 {
+            let mut target = rng.random::<f64>() * total;
+            min_distortions
+                .iter()
+                .position(|&d| {
+                    target -= d;
+                    target <= 0.0
+                })
+                .unwrap_or(vectors.len() - 1)
+        } else {
+            // all vectors already perfectly represented
+            rng.random_range(0..vectors.len())
+        };
+        add_seed(chosen, &mut min_distortions);
+    }
+    Codebook::new(codebook.class_name.clone(), p, reflections, measure)
+}
+
+/// Generalized Lloyd iteration on the given initial codebook.
+/// `prepared` are the `vectors` as prepared for the codebook distortion measure.
+fn lloyd(
+    mut codebook: Codebook,
+    vectors: &[Vec<f64>],
+    prepared: &[Vec<f64>],
+    epsilon: f64,
+    repair_empty: bool,
+) -> (Codebook, LbgStep) {
+    let p = codebook.prediction_order;
+    let m = codebook.size();
+    let num_vectors = vectors.len() as f64;
+
+    let mut dd_prv = f64::INFINITY;
+    let mut passes = 0;
+    let mut repair_passes = 0;
+    loop {
+        let assignments = codebook.quantize(prepared);
+        let dd: f64 = assignments.iter().map(|(_, d)| d).sum();
+
+        let ratio = (dd_prv - dd) / dd;
+        println!(
+            "({})\tDP={}\tDDprv={}\tDD={}\t{}",
+            passes,
+            dd / num_vectors,
+            dd_prv,
+            dd,
+            ratio
+        );
+
+        let cells: Vec<usize> = assignments.iter().map(|(i, _)| *i).collect();
+
+        if ratio < epsilon || dd == 0.0 {
+            let step = statistics(vectors, &assignments, m, passes);
+            if step.empty_cells == 0 || !repair_empty || repair_passes >= MAX_REPAIR_PASSES {
+                if step.empty_cells > 0 {
+                    println!(
+                        "WARN: review_cells: {} empty cell(s) for codebook size {}",
+                        step.empty_cells, m
+                    );
+                }
+                return (codebook, step);
+            }
+            repair_passes += 1;
+        }
+
+        let (reflections, counts) = centroids(p, codebook.distortion, vectors, prepared, &cells, m);
+        let mut reflections: Vec<Vec<f64>> = reflections
+            .into_iter()
+            .zip(&counts)
+            .zip(&codebook.reflections)
+            .map(|((new, &count), old)| if count > 0 { new } else { old.clone() })
+            .collect();
+
+        if repair_empty {
+            repair_empty_cells(p, vectors, &assignments, &counts, &mut reflections);
+        }
+
+        codebook = Codebook::new(codebook.class_name, p, reflections, codebook.distortion);
+
+        dd_prv = dd;
+        passes += 1;
+    }
+}
+
+/// Replaces the codeword of each empty cell with the model of one of the
+/// training vectors with highest distortion (each used at most once).
+fn repair_empty_cells(
+    p: usize,
+    vectors: &[Vec<f64>],
+    assignments: &[(usize, f64)],
+    counts: &[usize],
+    reflections: &mut [Vec<f64>],
+) {
+    let empty_cells: Vec<usize> = (0..counts.len()).filter(|&i| counts[i] == 0).collect();
+    if empty_cells.is_empty() {
+        return;
+    }
+
+    let mut by_distortion: Vec<usize> = (0..vectors.len()).collect();
+    by_distortion.sort_by(|&a, &b| assignments[b].1.total_cmp(&assignments[a].1));
+
+    let mut pred = vec![0f64; p + 1];
+    for (cell, &v) in empty_cells.iter().zip(&by_distortion) {
+        lpca_r(p, &vectors[v], &mut reflections[*cell], &mut pred);
+    }
+}
+
+/// Centroid (as reflection coefficients) and number of vectors for each cell.
+///
+/// Under the likelihood ratio distortion, the centroid of a cell is the LPC
+/// model of the average of the (gain normalized) autocorrelations in the cell.
+/// This is also used for other measures unless they provide their own centroid
+/// from the average of the prepared vectors.
+fn centroids(
+    p: usize,
+    measure: &dyn Distortion,
+    vectors: &[Vec<f64>],
+    prepared: &[Vec<f64>],
+    cells: &[usize],
+    m: usize,
+) -> (Vec<Vec<f64>>, Vec<usize>) {
+    let mut sums = vec![vec![0f64; p + 1]; m];
+    let mut prepared_sums = vec![vec![0f64; prepared[0].len()]; m];
+    let mut counts = vec![0usize; m];
+    for ((vector, prepared), &cell) in vectors.iter().zip(prepared).zip(cells) {
+        counts[cell] += 1;
+        for (s, v) in sums[cell].iter_mut().zip(vector) {
+            *s += v;
+        }
+        for (s, v) in prepared_sums[cell].iter_mut().zip(prepared) {
+            *s += v;
+        }
+    }
+
+    let mut pred = vec![0f64; p + 1];
+    let reflections = sums
+        .iter()
+        .zip(&prepared_sums)
+        .zip(&counts)
+        .map(|((sum, prepared_sum), &count)| {
+            let mut reflection = vec![0f64; p + 1];
+            if count > 0 {
+                let prepared_mean: Vec<f64> =
+                    prepared_sum.iter().map(|s| s / count as f64).collect();
+                if let Some(centroid) = measure.centroid(p, &prepared_mean) {
+                    return centroid;
+                }
+                let mean: Vec<f64> = sum.iter().map(|s| s / count as f64).collect();
+                let (res_lpca, err_pred) = lpca_r(p, &mean, &mut reflection, &mut pred);
+                if res_lpca != 0 {
+                    eprintln!(
+                        "WARNING: lpca_r: res_lpca = {}, err_pred = {}",
+                        res_lpca, err_pred
+                    );
+                }
+            }
+            reflection
+        })
+        .collect();
+
+    (reflections, counts)
+}
+
+/// Average distortion, sigma (standard deviation of the distortions),
+/// inertia (average squared Euclidean distance of the vectors to their
+/// cell mean), and number of empty cells.
+fn statistics(
+    vectors: &[Vec<f64>],
+    assignments: &[(usize, f64)],
+    m: usize,
+    passes: usize,
+) -> LbgStep {
+    let num_vectors = vectors.len() as f64;
+    let p1 = vectors[0].len();
+
+    let avg_distortion = assignments.iter().map(|(_, d)| d).sum::<f64>() / num_vectors;
+    let sigma = (assignments
+        .iter()
+        .map(|(_, d)| (d - avg_distortion).powi(2))
+        .sum::<f64>()
+        / num_vectors)
+        .sqrt();
+
+    let mut means = vec![vec![0f64; p1]; m];
+    let mut counts = vec![0usize; m];
+    for (vector, (cell, _)) in vectors.iter().zip(assignments) {
+        counts[*cell] += 1;
+        for (s, v) in means[*cell].iter_mut().zip(vector) {
+            *s += v;
+        }
+    }
+    for (mean, &count) in means.iter_mut().zip(&counts) {
+        if count > 0 {
+            mean.iter_mut().for_each(|s| *s /= count as f64);
+        }
+    }
+    let inertia = vectors
+        .iter()
+        .zip(assignments)
+        .map(|(vector, (cell, _))| {
+            vector
+                .iter()
+                .zip(&means[*cell])
+                .map(|(v, c)| (v - c).powi(2))
+                .sum::<f64>()
+        })
+        .sum::<f64>()
+        / num_vectors;
+
+    let empty_cells = counts.iter().filter(|&&c| c == 0).count();
+
+    LbgStep {
+        codebook_size: m,
+        passes,
+        avg_distortion,
+        sigma,
+        inertia,
+        empty_cells,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vq::distortion::{self, autocorrelation};
+
+    /// Training vectors around a few random models.
+    fn training_vectors(p: usize, rng: &mut StdRng) -> Vec<Vec<f64>> {
+        let models: Vec<Vec<f64>> = (0..6)
+            .map(|_| (0..p).map(|_| rng.random_range(-0.8..0.8)).collect())
+            .collect();
+        (0..300)
+            .map(|i| {
+                let mut reflection = vec![0.0];
+                reflection.extend(
+                    models[i % models.len()]
+                        .iter()
+                        .map(|k| k + rng.random_range(-0.1..0.1)),
+                );
+                autocorrelation(p, &reflection)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_train_size() {
+        let p = 4;
+        let measure = distortion::by_name("lr").unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+        let vectors = training_vectors(p, &mut rng);
+        let prepared: Vec<Vec<f64>> = vectors
+            .iter()
+            .map(|v| measure.prepare_vector(p, v))
+            .collect();
+        let all_cells = vec![0; vectors.len()];
+        let (reflections, _) = centroids(p, measure, &vectors, &prepared, &all_cells, 1);
+        let params = |init: Init, restarts: usize, repair_empty: bool| LbgParams {
+            epsilon: 0.001,
+            init,
+            restarts,
+            repair_empty,
+            seed: 0,
+            distortion: measure,
+        };
+
+        for init in [Init::Split, Init::KMeansPP] {
+            let single = params(init, 1, true);
+            let restarted = params(init, 3, true);
+            let mut codebook = Codebook::new("c".to_string(), p, reflections.clone(), measure);
+            while codebook.size() < 16 {
+                let m = 2 * codebook.size();
+                // with the same random sequence, the first restart is the single run
+                let mut rng = StdRng::seed_from_u64(1);
+                let (_, single_step) =
+                    train_size(&codebook, m, &vectors, &prepared, &single, &mut rng);
+                let mut rng = StdRng::seed_from_u64(1);
+                let (refined, step) =
+                    train_size(&codebook, m, &vectors, &prepared, &restarted, &mut rng);
+                assert_eq!(refined.size(), m);
+                assert!(step.avg_distortion <= single_step.avg_distortion);
+                assert_eq!(step.empty_cells, 0);
+                codebook = refined;
+            }
+        }
+    }
+}
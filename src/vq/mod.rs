@@ -5,13 +5,27 @@ use std::path::PathBuf;
 
 use clap::StructOpt;
 
+use crate::ecoz2_lib::set_random_seed;
 use crate::ecoz2_lib::vq_classify;
 use crate::ecoz2_lib::vq_learn;
 use crate::ecoz2_lib::vq_quantize;
 use crate::ecoz2_lib::vq_show;
 use crate::utl;
 
-use self::EcozVqCommand::{Classify, Learn, Quantize, Show};
+use self::EcozVqCommand::{Classify, Learn, Quantize, Report, Show};
+
+mod classify;
+mod codebook;
+mod distortion;
+mod lbg;
+mod quantize;
+mod report;
+mod tsvq;
+
+use self::distortion::Distortion;
+use self::lbg::{Init, LbgParams};
+use self::quantize::Search;
+use self::tsvq::Tsvq;
 
 #[derive(StructOpt, Debug)]
 pub struct VqMainOpts {
@@ -33,6 +47,9 @@ enum EcozVqCommand {
 
     #[structopt(about = "Show codebook")]
     Show(VqShowOpts),
+
+    #[structopt(about = "Codebook usage report")]
+    Report(VqReportOpts),
 }
 
 #[derive(StructOpt, Debug)]
@@ -66,6 +83,41 @@ pub struct VqLearnOpts {
     /// Only has effect if the COMET_API_KEY env var is defined.
     #[structopt(long)]
     exp_key: Option<String>,
+
+    /// Use Rust implementation.
+    /// Predictor files are expected as generated by `lpc --zrs`.
+    #[structopt(long)]
+    zrs: bool,
+
+    /// Initialization for each codebook size (with --zrs):
+    ///    split:    split each codeword of the previous codebook
+    ///    kmeans++: k-means++ seeding from the training vectors
+    #[structopt(long, default_value = "split", name = "init")]
+    init: Init,
+
+    /// Number of trainings for each codebook size, keeping the one
+    /// with the lowest distortion (with --zrs).
+    #[structopt(long, default_value = "1", name = "K")]
+    restarts: usize,
+
+    /// Reassign empty cells to the training vectors with highest distortion
+    /// (with --zrs).
+    #[structopt(long)]
+    repair_empty: bool,
+
+    /// Seed for random numbers. Negative means random seed.
+    /// Otherwise, the given seed is used, which will allow for reproducibility.
+    #[structopt(short = 's', long, default_value = "-1")]
+    seed: i64,
+
+    /// Distortion measure (with --zrs); with -B, the one of the base codebook is used:
+    ///    lr:         likelihood ratio, rxg·raas - 1 (as in the C implementation)
+    ///    log-lr:     log likelihood ratio, ln(rxg·raas)
+    ///    sym-log-lr: average of the log likelihood ratios in both directions
+    ///    cepstral:   truncated cepstral distance
+    ///    euclidean:  Euclidean distance on the reflection coefficients
+    #[structopt(long, default_value = "lr", name = "distortion")]
+    distortion: String,
 }
 
 #[derive(StructOpt, Debug)]
@@ -93,6 +145,37 @@ pub struct VqQuantizeOpts {
     /// Show file names as they are processed.
     #[structopt(short, long)]
     show_filenames: bool,
+
+    /// Use Rust implementation.
+    /// Predictor files can be in CBOR (`lpc --zrs`) or legacy format.
+    #[structopt(long)]
+    zrs: bool,
+
+    /// Base directory for the generated sequences (with --zrs),
+    /// which are saved under `<dir>/M<M>/<class>/`.
+    #[structopt(long, default_value = "data/sequences", name = "dir")]
+    sequences_dir: String,
+
+    /// Also save a soft sequence (`.sseq`) with the k nearest symbols
+    /// and their distortions for each frame (with --zrs).
+    #[structopt(long, default_value = "1", name = "k")]
+    top_k: usize,
+
+    /// Use a tree-structured search (with --zrs) based on the codebooks of
+    /// smaller sizes (`<prefix>_M_0002.cbook`, ..) in the same directory.
+    #[structopt(long)]
+    tsvq: bool,
+
+    /// Number of nearest nodes kept at each level of the tree search
+    /// (1 means plain tree search).
+    #[structopt(long, default_value = "1", name = "beam")]
+    tsvq_beam: usize,
+
+    /// Number of levels above the leaves where the tree search stops pruning
+    /// and does an exhaustive search among all the leaves under the kept nodes.
+    /// 1 prunes at every level; log2(M) - 1 with a beam of at least 2 is exact.
+    #[structopt(long, default_value = "1", name = "depth")]
+    tsvq_refine_depth: usize,
 }
 
 #[derive(StructOpt, Debug)]
@@ -115,6 +198,11 @@ pub struct VqClassifyOpts {
     /// Otherwise, if directories are included, then all `.prd` under them will be used.
     #[structopt(long, required = true, min_values = 1, parse(from_os_str))]
     predictors: Vec<PathBuf>,
+
+    /// Use Rust implementation, which also saves the
+    /// `vq_<M>_classification.json` and `vq_<M>_y_true_pred.json` files.
+    #[structopt(long)]
+    zrs: bool,
 }
 
 #[derive(StructOpt, Debug)]
@@ -130,6 +218,64 @@ pub struct VqShowOpts {
     /// Codebook.
     #[structopt(parse(from_os_str))]
     codebook: PathBuf,
+
+    /// Export the codebook to the given file in JSON format
+    /// (use `-` for standard output).
+    #[structopt(long, name = "json-file", parse(from_os_str))]
+    json: Option<PathBuf>,
+
+    /// Export the codebook to the given file in CSV format.
+    #[structopt(long, name = "csv-file", parse(from_os_str))]
+    csv: Option<PathBuf>,
+
+    /// Export the codebook to the given file in NPY format.
+    #[structopt(long, name = "npy-file", parse(from_os_str))]
+    npy: Option<PathBuf>,
+
+    /// Export the raas instead of the reflection coefficients
+    /// (for `--csv` and `--npy`).
+    #[structopt(long)]
+    raas: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct VqReportOpts {
+    /// Codebook to report on.
+    #[structopt(long = "codebook", parse(from_os_str))]
+    codebook: PathBuf,
+
+    /// Predictor files to be quantized.
+    /// The class of each file is the one in the predictor or, if not set,
+    /// the name of its parent directory.
+    #[structopt(long, required = true, parse(from_os_str), name = "files")]
+    predictors: Vec<PathBuf>,
+
+    #[structopt(long, default_value = "data/predictors")]
+    predictors_dir_template: String,
+
+    /// Optional selection of TRAIN or TEST instances
+    /// when `.csv` is given to `--predictors`.
+    #[structopt(long)]
+    tt: Option<String>,
+
+    /// Only this class when `.csv` is given to `--predictors`.
+    #[structopt(long, name = "class")]
+    class_name: Option<String>,
+
+    /// Cells with fewer vectors than this fraction of the
+    /// expected occupancy under uniform usage are reported as rare.
+    #[structopt(long, default_value = "0.1")]
+    rare_factor: f64,
+
+    /// Save the full report to the given file in JSON format
+    /// (use `-` for standard output).
+    #[structopt(long, name = "json-file", parse(from_os_str))]
+    json: Option<PathBuf>,
+
+    /// Save `<prefix>_cells.csv` with the occupancy of each cell
+    /// and `<prefix>_class_usage.csv` with the symbol usage per class.
+    #[structopt(long, name = "prefix")]
+    csv: Option<String>,
 }
 
 pub fn main(opts: VqMainOpts) {
@@ -141,6 +287,8 @@ pub fn main(opts: VqMainOpts) {
         Classify(opts) => main_vq_classify(opts),
 
         Show(opts) => main_vq_show(opts),
+
+        Report(opts) => main_vq_report(opts),
     };
 
     if let Err(err) = res {
@@ -156,6 +304,12 @@ pub fn main_vq_learn(opts: VqLearnOpts) -> Result<(), Box<dyn Error>> {
         class_name,
         predictors,
         exp_key,
+        zrs,
+        init,
+        restarts,
+        repair_empty,
+        seed,
+        distortion,
     } = opts;
 
     if let (Some(_), Some(_)) = (&base_codebook, prediction_order) {
@@ -175,6 +329,51 @@ pub fn main_vq_learn(opts: VqLearnOpts) -> Result<(), Box<dyn Error>> {
         ".prd",
     )?;
 
+    if zrs {
+        let (base_codebook, prediction_order, codebook_class_name, distortion) =
+            match (base_codebook, prediction_order) {
+                (Some(base_codebook), None) => {
+                    if init != Init::Split {
+                        return Err("Base codebook only supported with split init".into());
+                    }
+                    let base_codebook = codebook::load(&base_codebook)?;
+                    let p = base_codebook.prediction_order;
+                    let class_name = base_codebook.class_name.clone();
+                    let measure = base_codebook.distortion;
+                    (Some(base_codebook), p, class_name, measure)
+                }
+                (None, Some(p)) => (
+                    None,
+                    p,
+                    codebook_class_name,
+                    distortion::by_name(&distortion)?,
+                ),
+                _ => return Err("One of base codebook or prediction order expected".into()),
+            };
+
+        let params = LbgParams {
+            epsilon,
+            init,
+            restarts,
+            repair_empty,
+            seed: set_random_seed(seed),
+            distortion,
+        };
+
+        return lbg::learn(
+            base_codebook,
+            prediction_order,
+            codebook_class_name,
+            prd_filenames,
+            &params,
+            exp_key,
+        );
+    }
+
+    if distortion != distortion::LIKELIHOOD_RATIO.name() {
+        return Err("--distortion only supported with --zrs".into());
+    }
+
     vq_learn(
         base_codebook,
         prediction_order,
@@ -195,6 +394,12 @@ pub fn main_vq_quantize(opts: VqQuantizeOpts) -> Result<(), Box<dyn Error>> {
         tt,
         class_name,
         show_filenames,
+        zrs,
+        sequences_dir,
+        top_k,
+        tsvq,
+        tsvq_beam,
+        tsvq_refine_depth,
     } = opts;
 
     let tt = tt.unwrap_or_default();
@@ -210,6 +415,32 @@ pub fn main_vq_quantize(opts: VqQuantizeOpts) -> Result<(), Box<dyn Error>> {
 
     println!("number of predictor files: {}", prd_filenames.len());
 
+    if zrs {
+        let codebook_filename = codebook.to_str().unwrap();
+        let codebook = codebook::load(codebook_filename)?;
+        let search = if tsvq {
+            if top_k > 1 {
+                return Err("--top-k not supported with --tsvq".into());
+            }
+            Search::Tree {
+                tsvq: Tsvq::load(codebook_filename)?,
+                beam: tsvq_beam,
+                refine_depth: tsvq_refine_depth,
+            }
+        } else {
+            Search::Full
+        };
+        return quantize::quantize(
+            &codebook,
+            &search,
+            &prd_filenames,
+            &sequences_dir,
+            top_k,
+            show_filenames,
+        );
+    }
+
+    check_c_distortion(std::slice::from_ref(&codebook))?;
     vq_quantize(codebook, prd_filenames, show_filenames);
 
     Ok(())
@@ -221,6 +452,7 @@ pub fn main_vq_classify(opts: VqClassifyOpts) -> Result<(), Box<dyn Error>> {
         codebooks,
         tt,
         predictors,
+        zrs,
     } = opts;
 
     let cb_filenames = utl::resolve_filenames(codebooks, ".cbook", "codebooks")?;
@@ -240,15 +472,86 @@ pub fn main_vq_classify(opts: VqClassifyOpts) -> Result<(), Box<dyn Error>> {
     );
     println!("show_ranked = {}", show_ranked);
 
+    if zrs {
+        return classify::classify(cb_filenames, prd_filenames, show_ranked);
+    }
+
+    check_c_distortion(&cb_filenames)?;
     vq_classify(cb_filenames, prd_filenames, show_ranked);
 
     Ok(())
 }
 
+/// The C implementation only supports the likelihood ratio distortion.
+fn check_c_distortion(cb_filenames: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    for cb_filename in cb_filenames {
+        let filename = cb_filename.to_str().unwrap();
+        let measure = codebook::load(filename)?.distortion;
+        if measure.name() != distortion::LIKELIHOOD_RATIO.name() {
+            return Err(format!(
+                "{}: distortion '{}' not supported by the C implementation",
+                filename,
+                measure.name()
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
 pub fn main_vq_show(opts: VqShowOpts) -> Result<(), Box<dyn Error>> {
-    let VqShowOpts { from, to, codebook } = opts;
+    let VqShowOpts {
+        from,
+        to,
+        codebook,
+        json,
+        csv,
+        npy,
+        raas,
+    } = opts;
+
+    if json.is_some() || csv.is_some() || npy.is_some() {
+        let codebook = codebook::load(codebook.to_str().unwrap())?;
+        return codebook.export(from, to, raas, json, csv, npy);
+    }
 
     vq_show(codebook, from, to);
 
     Ok(())
 }
+
+pub fn main_vq_report(opts: VqReportOpts) -> Result<(), Box<dyn Error>> {
+    let VqReportOpts {
+        codebook,
+        predictors,
+        predictors_dir_template,
+        tt,
+        class_name,
+        rare_factor,
+        json,
+        csv,
+    } = opts;
+
+    let tt = tt.unwrap_or_default();
+
+    let prd_filenames = utl::resolve_files3(
+        &predictors,
+        tt.as_str(),
+        &class_name,
+        "".to_string(),
+        predictors_dir_template,
+        ".prd",
+    )?;
+
+    let codebook_filename = codebook.to_str().unwrap();
+    let codebook = codebook::load(codebook_filename)?;
+
+    report::report(
+        &codebook,
+        codebook_filename,
+        &prd_filenames,
+        rare_factor,
+        json,
+        csv,
+    )
+}
@@ -0,0 +1,376 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::prd;
+use crate::sequence::{Sequence, SoftSequence};
+
+use super::codebook::Codebook;
+use super::tsvq::Tsvq;
+
+/// Every this number of vectors, a tree search is checked against the
+/// exhaustive search to measure the speedup and distortion penalty.
+const TREE_CHECK_STRIDE: usize = 10;
+
+/// Codebook search for the quantization.
+pub enum Search {
+    /// Exhaustive search.
+    Full,
+
+    /// Tree-structured search (see `Tsvq::nearest`).
+    Tree {
+        tsvq: Tsvq,
+        beam: usize,
+        refine_depth: usize,
+    },
+}
+
+/// Result of quantizing a predictor file.
+struct Quantized {
+    seq_filename: String,
+    num_vectors: usize,
+    avg_distortion: f64,
+    tree: TreeStats,
+}
+
+/// Measurements of the tree search against the exhaustive search.
+#[derive(Default)]
+struct TreeStats {
+    /// Distortion evaluations in the tree search (all vectors).
+    evaluations: usize,
+    /// Number of vectors checked against the exhaustive search, and
+    /// for those, time, total distortion and number of equal symbols.
+    checked: usize,
+    tree_time: Duration,
+    full_time: Duration,
+    tree_distortion: f64,
+    full_distortion: f64,
+    agreements: usize,
+}
+
+impl TreeStats {
+    fn add(&mut self, other: &TreeStats) {
+        self.evaluations += other.evaluations;
+        self.checked += other.checked;
+        self.tree_time += other.tree_time;
+        self.full_time += other.full_time;
+        self.tree_distortion += other.tree_distortion;
+        self.full_distortion += other.full_distortion;
+        self.agreements += other.agreements;
+    }
+
+    fn show(&self, codebook_size: usize, num_vectors: usize, beam: usize, refine_depth: usize) {
+        if self.checked == 0 {
+            return;
+        }
+        let evaluations = self.evaluations as f64 / num_vectors as f64;
+        let speedup = self.full_time.as_secs_f64() / self.tree_time.as_secs_f64();
+        let penalty = 100.0 * (self.tree_distortion - self.full_distortion) / self.full_distortion;
+        println!(
+            "\nTree search (beam={}, refine depth={}) vs. exhaustive search, checked on {} vectors:",
+            beam, refine_depth, self.checked
+        );
+        println!(
+            "  distortion evaluations per vector: {:.1} vs. {}  ({:.1}x fewer)",
+            evaluations,
+            codebook_size,
+            codebook_size as f64 / evaluations
+        );
+        println!(
+            "  search time: {:.2?} vs. {:.2?}  (speedup: {:.2}x)",
+            self.tree_time, self.full_time, speedup
+        );
+        println!(
+            "  avg distortion: {} vs. {}  (penalty: {:.2}%)",
+            self.tree_distortion / self.checked as f64,
+            self.full_distortion / self.checked as f64,
+            penalty
+        );
+        println!(
+            "  same symbol: {:.2}%",
+            100.0 * self.agreements as f64 / self.checked as f64
+        );
+    }
+}
+
+/// Rust implementation of the vector quantization of predictor files.
+///
+/// Each predictor file (CBOR or legacy format) is quantized with the given
+/// codebook and saved as `<sequences_dir>/M<M>/<class>/<name>.seq`, where
+/// `<class>` is that of the predictor or, if not set, the name of the
+/// directory containing the predictor file.
+/// Files are processed in parallel. The average distortion is reported for
+/// each file and for all of them.
+///
+/// With `top_k > 1`, a soft sequence with the `top_k` nearest symbols for each
+/// frame is also saved as `<name>.sseq` next to the sequence.
+///
+/// With a tree search, its speedup and distortion penalty with respect to the
+/// exhaustive search are measured on every `TREE_CHECK_STRIDE`-th vector.
+pub fn quantize(
+    codebook: &Codebook,
+    search: &Search,
+    prd_filenames: &[PathBuf],
+    sequences_dir: &str,
+    top_k: usize,
+    show_filenames: bool,
+) -> Result<(), Box<dyn Error>> {
+    let before = Instant::now();
+
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Quantized>>> =
+        Mutex::new(prd_filenames.iter().map(|_| None).collect());
+
+    let cores = num_cpus::get().min(prd_filenames.len()).max(1);
+    thread::scope(|s| -> Result<(), Box<dyn Error>> {
+        let handles: Vec<_> = (0..cores)
+            .map(|_| {
+                s.spawn(|| -> Result<(), String> {
+                    loop {
+                        let i = next.fetch_add(1, Ordering::SeqCst);
+                        if i >= prd_filenames.len() {
+                            return Ok(());
+                        }
+                        let quantized = quantize_file(
+                            codebook,
+                            search,
+                            &prd_filenames[i],
+                            sequences_dir,
+                            top_k,
+                        )
+                        .map_err(|e| format!("{}: {}", prd_filenames[i].display(), e))?;
+                        if show_filenames {
+                            println!(
+                                "{} -> {}",
+                                prd_filenames[i].display(),
+                                quantized.seq_filename
+                            );
+                        }
+                        results.lock().unwrap()[i] = Some(quantized);
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        Ok(())
+    })?;
+
+    let results: Vec<Quantized> = results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect();
+
+    println!("\n{:>10} {:>14}  sequence", "T", "avg_distortion");
+    for quantized in &results {
+        println!(
+            "{:>10} {:>14.6}  {}",
+            quantized.num_vectors, quantized.avg_distortion, quantized.seq_filename
+        );
+    }
+
+    let total_vectors: usize = results.iter().map(|q| q.num_vectors).sum();
+    let total_distortion: f64 = results
+        .iter()
+        .map(|q| q.avg_distortion * q.num_vectors as f64)
+        .sum();
+    let avg_distortion = if total_vectors > 0 {
+        total_distortion / total_vectors as f64
+    } else {
+        0.0
+    };
+    println!(
+        "\n{} sequence(s), {} vectors: avg_distortion = {}",
+        results.len(),
+        total_vectors,
+        avg_distortion
+    );
+
+    if let Search::Tree {
+        beam, refine_depth, ..
+    } = search
+    {
+        let mut tree = TreeStats::default();
+        results.iter().for_each(|q| tree.add(&q.tree));
+        tree.show(codebook.size(), total_vectors, *beam, *refine_depth);
+    }
+
+    println!("quantization took: {:.2?}", before.elapsed());
+    Ok(())
+}
+
+fn quantize_file(
+    codebook: &Codebook,
+    search: &Search,
+    prd_filename: &Path,
+    sequences_dir: &str,
+    top_k: usize,
+) -> Result<Quantized, Box<dyn Error>> {
+    let predictor = prd::load(prd_filename.to_str().unwrap())?;
+    if predictor.prediction_order != codebook.prediction_order {
+        return Err(format!(
+            "conformity error: prediction order: {} != {}",
+            predictor.prediction_order, codebook.prediction_order
+        )
+        .into());
+    }
+
+    let p = codebook.prediction_order;
+    let mut symbols = Vec::with_capacity(predictor.vectors.len());
+    let mut frames = Vec::new();
+    let mut total_distortion = 0f64;
+    let mut tree = TreeStats::default();
+    for (t, vector) in predictor.vectors.iter().enumerate() {
+        let prepared = codebook.distortion.prepare_vector(p, vector);
+        let (symbol, d) = if top_k > 1 {
+            let nearest = codebook.nearest_k(&prepared, top_k);
+            let first = nearest[0];
+            frames.push(nearest.into_iter().map(|(s, d)| (s as u16, d)).collect());
+            first
+        } else if let Search::Tree {
+            tsvq,
+            beam,
+            refine_depth,
+        } = search
+        {
+            let before = Instant::now();
+            let (symbol, d, evaluations) = tsvq.nearest(&prepared, *beam, *refine_depth);
+            let tree_time = before.elapsed();
+            tree.evaluations += evaluations;
+
+            if t % TREE_CHECK_STRIDE == 0 {
+                let before = Instant::now();
+                let (full_symbol, full_d) = codebook.nearest(&prepared);
+                tree.full_time += before.elapsed();
+                tree.tree_time += tree_time;
+                tree.checked += 1;
+                tree.tree_distortion += d;
+                tree.full_distortion += full_d;
+                if symbol == full_symbol {
+                    tree.agreements += 1;
+                }
+            }
+            (symbol, d)
+        } else {
+            codebook.nearest(&prepared)
+        };
+        symbols.push(symbol as u16);
+        total_distortion += d;
+    }
+
+    let class_name = predictor.resolved_class_name(prd_filename);
+    let dir = format!("{}/M{}/{}", sequences_dir, codebook.size(), class_name);
+    std::fs::create_dir_all(&dir)?;
+    let stem = prd_filename.file_stem().unwrap().to_str().unwrap();
+    let seq_filename = format!("{}/{}.seq", dir, stem);
+
+    if top_k > 1 {
+        let soft_sequence = SoftSequence {
+            class_name: class_name.clone(),
+            codebook_size: codebook.size() as u32,
+            k: top_k.min(codebook.size()),
+            frames,
+        };
+        soft_sequence.save(&format!("{}/{}.sseq", dir, stem))?;
+    }
+
+    let num_vectors = symbols.len();
+    let sequence = Sequence {
+        class_name,
+        codebook_size: codebook.size() as u32,
+        symbols,
+    };
+    sequence.save(&seq_filename)?;
+
+    Ok(Quantized {
+        seq_filename,
+        num_vectors,
+        avg_distortion: if num_vectors > 0 {
+            total_distortion / num_vectors as f64
+        } else {
+            0.0
+        },
+        tree,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vq::distortion::{self, autocorrelation};
+    use rand::rngs::StdRng;
+    use rand::{RngExt, SeedableRng};
+
+    fn random_reflection(rng: &mut StdRng, p: usize) -> Vec<f64> {
+        let mut reflection = vec![0.0];
+        reflection.extend((0..p).map(|_| rng.random_range(-0.8..0.8)));
+        reflection
+    }
+
+    #[test]
+    fn test_parallel_as_serial() {
+        let p = 3;
+        let mut rng = StdRng::seed_from_u64(5);
+        let measure = distortion::by_name("lr").unwrap();
+        let reflections = (0..8).map(|_| random_reflection(&mut rng, p)).collect();
+        let codebook = Codebook::new("cb".to_string(), p, reflections, measure);
+
+        let base = std::env::temp_dir().join("ecoz2_test_quantize");
+        let _ = std::fs::remove_dir_all(&base);
+        let prd_dir = base.join("prd");
+        std::fs::create_dir_all(&prd_dir).unwrap();
+        let prd_filenames: Vec<PathBuf> = (0..12)
+            .map(|i| {
+                let predictor = prd::Predictor {
+                    class_name: format!("c{}", i % 3),
+                    prediction_order: p,
+                    vectors: (0..20 + i)
+                        .map(|_| autocorrelation(p, &random_reflection(&mut rng, p)))
+                        .collect(),
+                };
+                let filename = prd_dir.join(format!("f{}.prd", i));
+                let f = std::fs::File::create(&filename).unwrap();
+                serde_cbor::to_writer(f, &predictor).unwrap();
+                filename
+            })
+            .collect();
+
+        let top_k = 3;
+        let parallel_dir = base.join("parallel").to_str().unwrap().to_string();
+        quantize(
+            &codebook,
+            &Search::Full,
+            &prd_filenames,
+            &parallel_dir,
+            top_k,
+            false,
+        )
+        .unwrap();
+        let serial_dir = base.join("serial").to_str().unwrap().to_string();
+        let serial: Vec<Quantized> = prd_filenames
+            .iter()
+            .map(|f| quantize_file(&codebook, &Search::Full, f, &serial_dir, top_k).unwrap())
+            .collect();
+
+        for quantized in &serial {
+            for ext in [".seq", ".sseq"] {
+                let serial_filename = quantized.seq_filename.replace(".seq", ext);
+                let parallel_filename = serial_filename.replacen(&serial_dir, &parallel_dir, 1);
+                assert_eq!(
+                    std::fs::read(&parallel_filename).unwrap(),
+                    std::fs::read(&serial_filename).unwrap(),
+                    "{}",
+                    parallel_filename
+                );
+            }
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::prd;
+use crate::utl;
+
+use super::codebook::Codebook;
+
+/// Usage of the codewords by a set of vectors.
+#[derive(serde::Serialize)]
+struct Usage {
+    num_vectors: usize,
+    avg_distortion: f64,
+    /// Number of vectors assigned to each cell.
+    counts: Vec<usize>,
+    /// Entropy (in bits) of the codeword usage distribution.
+    entropy: f64,
+}
+
+impl Usage {
+    fn new(codebook_size: usize) -> Self {
+        Usage {
+            num_vectors: 0,
+            avg_distortion: 0.0,
+            counts: vec![0; codebook_size],
+            entropy: 0.0,
+        }
+    }
+
+    fn add(&mut self, assignments: &[(usize, f64)]) {
+        for (cell, d) in assignments {
+            self.counts[*cell] += 1;
+            // accumulated here; averaged in `finish`:
+            self.avg_distortion += d;
+        }
+        self.num_vectors += assignments.len();
+    }
+
+    fn finish(&mut self) {
+        if self.num_vectors > 0 {
+            let n = self.num_vectors as f64;
+            self.avg_distortion /= n;
+            self.entropy = -self
+                .counts
+                .iter()
+                .filter(|&&c| c > 0)
+                .map(|&c| {
+                    let p = c as f64 / n;
+                    p * p.log2()
+                })
+                .sum::<f64>();
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Report<'a> {
+    codebook: &'a str,
+    class_name: &'a str,
+    prediction_order: usize,
+    codebook_size: usize,
+    distortion: &'a str,
+    num_files: usize,
+    /// Cells with fewer vectors than this are reported as rare.
+    rare_threshold: f64,
+    empty_cells: Vec<usize>,
+    rare_cells: Vec<usize>,
+    /// Entropy normalized by `log2(M)`.
+    normalized_entropy: f64,
+    /// `2^entropy`, the effective number of codewords in use.
+    perplexity: f64,
+    overall: Usage,
+    classes: BTreeMap<String, Usage>,
+}
+
+/// Quantizes the given predictor files and reports the usage of the codewords:
+/// occupancy of each cell, empty and rarely used cells, and usage entropy,
+/// overall and per class.
+///
+/// A cell is rare if it has fewer than `rare_factor * N / M` vectors, that is,
+/// the given fraction of the expected occupancy under uniform usage.
+///
+/// * `json` - full report (`-` for standard output).
+/// * `csv`  - prefix for `<prefix>_cells.csv` (count, fraction and average
+///   distortion per cell) and `<prefix>_class_usage.csv` (symbol usage matrix,
+///   one row per class).
+pub fn report(
+    codebook: &Codebook,
+    codebook_filename: &str,
+    prd_filenames: &[PathBuf],
+    rare_factor: f64,
+    json: Option<PathBuf>,
+    csv: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let m = codebook.size();
+    let mut overall = Usage::new(m);
+    let mut cell_distortions = vec![0f64; m];
+    let mut classes: BTreeMap<String, Usage> = BTreeMap::new();
+
+    for prd_filename in prd_filenames {
+        let filename = prd_filename.to_str().unwrap();
+        let predictor = prd::load(filename)?;
+        if predictor.prediction_order != codebook.prediction_order {
+            return Err(format!(
+                "conformity error: {}: prediction order: {} != {}",
+                filename, predictor.prediction_order, codebook.prediction_order
+            )
+            .into());
+        }
+
+        let p = codebook.prediction_order;
+        let assignments: Vec<(usize, f64)> = predictor
+            .vectors
+            .iter()
+            .map(|v| codebook.nearest(&codebook.distortion.prepare_vector(p, v)))
+            .collect();
+        for (cell, d) in &assignments {
+            cell_distortions[*cell] += d;
+        }
+        overall.add(&assignments);
+        classes
+            .entry(predictor.resolved_class_name(prd_filename))
+            .or_insert_with(|| Usage::new(m))
+            .add(&assignments);
+    }
+
+    if overall.num_vectors == 0 {
+        return Err("No vectors to quantize".into());
+    }
+
+    overall.finish();
+    classes.values_mut().for_each(Usage::finish);
+
+    let rare_threshold = rare_factor * overall.num_vectors as f64 / m as f64;
+    let empty_cells: Vec<usize> = (0..m).filter(|&i| overall.counts[i] == 0).collect();
+    let rare_cells: Vec<usize> = (0..m)
+        .filter(|&i| overall.counts[i] > 0 && (overall.counts[i] as f64) < rare_threshold)
+        .collect();
+
+    let max_entropy = (m as f64).log2();
+    let normalized_entropy = if max_entropy > 0.0 {
+        overall.entropy / max_entropy
+    } else {
+        0.0
+    };
+    let perplexity = overall.entropy.exp2();
+
+    println!(
+        "codebook: {}  M={}  P={}  distortion={}",
+        codebook_filename,
+        m,
+        codebook.prediction_order,
+        codebook.distortion.name()
+    );
+    println!(
+        "{} vectors from {} file(s)  avg_distortion={}",
+        overall.num_vectors,
+        prd_filenames.len(),
+        overall.avg_distortion
+    );
+    println!(
+        "entropy={:.4} bits (max {:.4})  normalized={:.4}  perplexity={:.1}",
+        overall.entropy, max_entropy, normalized_entropy, perplexity
+    );
+    println!(
+        "empty cells: {}  rare cells (< {:.2} vectors): {}",
+        empty_cells.len(),
+        rare_threshold,
+        rare_cells.len()
+    );
+
+    println!("\nCell occupancy histogram:");
+    show_occupancy_histogram(&overall.counts);
+
+    println!("\nPer class:");
+    println!(
+        "  {:>20} {:>10} {:>12} {:>10} {:>8}",
+        "class", "vectors", "avg_dist", "entropy", "used"
+    );
+    for (class_name, usage) in &classes {
+        println!(
+            "  {:>20} {:>10} {:>12.6} {:>10.4} {:>8}",
+            class_name,
+            usage.num_vectors,
+            usage.avg_distortion,
+            usage.entropy,
+            usage.counts.iter().filter(|&&c| c > 0).count()
+        );
+    }
+
+    if let Some(prefix) = csv {
+        let n = overall.num_vectors as f64;
+        let header: Vec<String> = ["cell", "count", "fraction", "avg_distortion"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let rows: Vec<Vec<f64>> = (0..m)
+            .map(|i| {
+                let count = overall.counts[i] as f64;
+                let avg_distortion = if count > 0.0 {
+                    cell_distortions[i] / count
+                } else {
+                    0.0
+                };
+                vec![i as f64, count, count / n, avg_distortion]
+            })
+            .collect();
+        let cells_filename = PathBuf::from(format!("{}_cells.csv", prefix));
+        utl::save_csv(&header, &rows, &cells_filename)?;
+        println!("\ncell occupancy saved to {:?}", cells_filename);
+
+        let usage_filename = format!("{}_class_usage.csv", prefix);
+        let mut wrt = csv::Writer::from_path(&usage_filename)?;
+        let mut header = vec!["class".to_string()];
+        header.extend((0..m).map(|i| format!("s{}", i)));
+        wrt.write_record(&header)?;
+        for (class_name, usage) in &classes {
+            let mut record = vec![class_name.clone()];
+            record.extend(usage.counts.iter().map(|c| c.to_string()));
+            wrt.write_record(&record)?;
+        }
+        wrt.flush()?;
+        println!("class symbol usage saved to {:?}", usage_filename);
+    }
+
+    if let Some(json_filename) = json {
+        let report = Report {
+            codebook: codebook_filename,
+            class_name: &codebook.class_name,
+            prediction_order: codebook.prediction_order,
+            codebook_size: m,
+            distortion: codebook.distortion.name(),
+            num_files: prd_filenames.len(),
+            rare_threshold,
+            empty_cells,
+            rare_cells,
+            normalized_entropy,
+            perplexity,
+            overall,
+            classes,
+        };
+        if json_filename.to_str() == Some("-") {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            utl::save_json(&report, json_filename.to_str().unwrap())?;
+            println!("report saved to {:?}", json_filename);
+        }
+    }
+
+    Ok(())
+}
+
+/// Shows the number of cells by occupancy, in powers of 2.
+fn show_occupancy_histogram(counts: &[usize]) {
+    let max_count = counts.iter().copied().max().unwrap_or(0);
+    // bins: 0, 1, 2..3, 4..7, ...
+    let num_bins = 2 + (usize::BITS - max_count.leading_zeros()) as usize;
+    let mut bins = vec![0usize; num_bins];
+    for &count in counts {
+        let bin = if count == 0 {
+            0
+        } else {
+            1 + (usize::BITS - 1 - count.leading_zeros()) as usize
+        };
+        bins[bin] += 1;
+    }
+    while bins.len() > 1 && *bins.last().unwrap() == 0 {
+        bins.pop();
+    }
+
+    let max_bin = bins.iter().copied().max().unwrap_or(1).max(1);
+    for (bin, &num_cells) in bins.iter().enumerate() {
+        let range = match bin {
+            0 => "0".to_string(),
+            1 => "1".to_string(),
+            _ => format!("{}..{}", 1 << (bin - 1), (1 << bin) - 1),
+        };
+        let bar = "*".repeat((50 * num_cells).div_ceil(max_bin));
+        println!("  {:>12} {:>6} {}", range, num_cells, bar);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vq::distortion::{self, autocorrelation};
+
+    #[test]
+    fn test_report() {
+        let p = 2;
+        let reflections = vec![
+            vec![0.0, 0.5, -0.25],
+            vec![0.0, -0.6, 0.3],
+            vec![0.0, 0.1, 0.8],
+        ];
+        let measure = distortion::by_name("lr").unwrap();
+        let codebook = Codebook::new("cb".to_string(), p, reflections.clone(), measure);
+
+        // vectors given by the models of the codewords: X uses 0, 0, 0, 1; Y uses 1, 1, 1, 1
+        let dir = std::env::temp_dir();
+        let prd_filenames: Vec<PathBuf> = [("X", [0, 0, 0, 1]), ("Y", [1, 1, 1, 1])]
+            .iter()
+            .map(|(class_name, cells)| {
+                let predictor = prd::Predictor {
+                    class_name: class_name.to_string(),
+                    prediction_order: p,
+                    vectors: cells
+                        .iter()
+                        .map(|&i| autocorrelation(p, &reflections[i]))
+                        .collect(),
+                };
+                let filename = dir.join(format!("ecoz2_test_report_{}.prd", class_name));
+                let f = std::fs::File::create(&filename).unwrap();
+                serde_cbor::to_writer(f, &predictor).unwrap();
+                filename
+            })
+            .collect();
+
+        let json_filename = dir.join("ecoz2_test_report.json");
+        let prefix = dir.join("ecoz2_test_report").to_str().unwrap().to_string();
+        report(
+            &codebook,
+            "cb.cbook",
+            &prd_filenames,
+            1.2,
+            Some(json_filename.clone()),
+            Some(prefix.clone()),
+        )
+        .unwrap();
+
+        let json = std::fs::read_to_string(&json_filename).unwrap();
+        let cells = std::fs::read_to_string(format!("{}_cells.csv", prefix)).unwrap();
+        let class_usage = std::fs::read_to_string(format!("{}_class_usage.csv", prefix)).unwrap();
+        for filename in prd_filenames.iter().chain([&json_filename]) {
+            std::fs::remove_file(filename).unwrap();
+        }
+        std::fs::remove_file(format!("{}_cells.csv", prefix)).unwrap();
+        std::fs::remove_file(format!("{}_class_usage.csv", prefix)).unwrap();
+
+        let report: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(report["num_files"], 2);
+        assert_eq!(report["overall"]["num_vectors"], 8);
+        assert_eq!(report["overall"]["counts"], serde_json::json!([3, 5, 0]));
+        assert_eq!(report["empty_cells"], serde_json::json!([2]));
+        // threshold: 1.2 * 8 / 3 = 3.2 vectors
+        assert_eq!(report["rare_cells"], serde_json::json!([0]));
+        let entropy = -(0.375f64 * 0.375f64.log2() + 0.625 * 0.625f64.log2());
+        let overall_entropy = report["overall"]["entropy"].as_f64().unwrap();
+        assert!((overall_entropy - entropy).abs() < 1e-12);
+        let normalized = report["normalized_entropy"].as_f64().unwrap();
+        assert!((normalized - entropy / 3f64.log2()).abs() < 1e-12);
+        let x_entropy = report["classes"]["X"]["entropy"].as_f64().unwrap();
+        assert!((x_entropy - -(0.75f64 * 0.75f64.log2() + 0.25 * 0.25f64.log2())).abs() < 1e-12);
+        assert_eq!(report["classes"]["Y"]["entropy"].as_f64().unwrap(), 0.0);
+
+        let cell_lines: Vec<&str> = cells.lines().collect();
+        assert_eq!(cell_lines[0], "cell,count,fraction,avg_distortion");
+        assert!(cell_lines[1].starts_with("0,3,0.375,"));
+        assert_eq!(cell_lines[3], "2,0,0,0");
+        assert_eq!(class_usage, "class,s0,s1,s2\nX,3,1,0\nY,0,4,0\n");
+    }
+}
@@ -0,0 +1,217 @@
+use std::error::Error;
+
+use regex::Regex;
+
+use super::codebook::{self, Codebook};
+use super::distortion::autocorrelation;
+
+/// Tree-structured VQ index built from the hierarchy of codebooks
+/// generated by the LBG training (sizes 2, 4, .., M).
+///
+/// Each codeword at a level is a child of its nearest codeword at the
+/// previous level, which, with the splitting initialization, corresponds
+/// to the codeword it was split from.
+pub struct Tsvq {
+    /// Codebooks of sizes 2, 4, .., M (the last one being the target codebook).
+    levels: Vec<Codebook>,
+
+    /// `children[l][j]`: codewords at level `l + 1` whose parent is
+    /// codeword `j` at level `l`.
+    children: Vec<Vec<Vec<usize>>>,
+}
+
+impl Tsvq {
+    /// Builds the index for the given codebook, which is expected to be
+    /// named `<prefix>_M_<MMMM>.cbook`, with the codebooks of smaller sizes
+    /// `<prefix>_M_0002.cbook`, .. in the same directory.
+    pub fn load(codebook_filename: &str) -> Result<Tsvq, Box<dyn Error>> {
+        let re = Regex::new(r"^(.*_M_)(\d+)\.cbook$").unwrap();
+        let caps = re.captures(codebook_filename).ok_or_else(|| {
+            format!(
+                "{}: expecting a codebook named <prefix>_M_<size>.cbook",
+                codebook_filename
+            )
+        })?;
+        let prefix = &caps[1];
+        let codebook_size: usize = caps[2].parse()?;
+        if codebook_size < 2 || !codebook_size.is_power_of_two() {
+            return Err(format!("{}: size must be a power of two", codebook_filename).into());
+        }
+
+        let mut levels: Vec<Codebook> = Vec::new();
+        let mut m = 2;
+        while m <= codebook_size {
+            let filename = format!("{}{:04}.cbook", prefix, m);
+            let level = codebook::load(&filename)?;
+            if level.size() != m {
+                return Err(format!("{}: unexpected size: {}", filename, level.size()).into());
+            }
+            if let Some(first) = levels.first() {
+                if level.prediction_order != first.prediction_order
+                    || level.distortion.name() != first.distortion.name()
+                {
+                    return Err(format!("{}: not conforming to the hierarchy", filename).into());
+                }
+            }
+            levels.push(level);
+            m *= 2;
+        }
+
+        Ok(Tsvq::new(levels))
+    }
+
+    /// Index for the given codebooks of sizes 2, 4, .., M.
+    fn new(levels: Vec<Codebook>) -> Tsvq {
+        let children = levels
+            .windows(2)
+            .map(|pair| {
+                let (parents, level) = (&pair[0], &pair[1]);
+                let p = parents.prediction_order;
+                let mut children = vec![Vec::new(); parents.size()];
+                for (i, reflection) in level.reflections.iter().enumerate() {
+                    let rxg = autocorrelation(p, reflection);
+                    let prepared = parents.distortion.prepare_vector(p, &rxg);
+                    children[parents.nearest(&prepared).0].push(i);
+                }
+                children
+            })
+            .collect();
+
+        Tsvq { levels, children }
+    }
+
+    /// Searches the tree for the codeword nearest to the given prepared vector.
+    ///
+    /// The search descends the tree keeping the `beam` nearest nodes at each
+    /// level (1 for the plain tree search) down to `refine_depth` levels above
+    /// the leaves, and then does an exhaustive search among all the leaves
+    /// under the kept nodes. So, `refine_depth = 1` prunes at every level,
+    /// while a larger depth trades more evaluations for a more accurate result
+    /// (with the depth of the whole tree and `beam` >= 2, the search is exact).
+    /// Returns the index of the codeword, the corresponding distortion, and
+    /// the number of distortion evaluations.
+    pub fn nearest(&self, vector: &[f64], beam: usize, refine_depth: usize) -> (usize, f64, usize) {
+        let last = self.levels.len() - 1;
+        let refine_level = last.saturating_sub(refine_depth.max(1));
+        let keep = |mut nodes: Vec<(usize, f64)>| {
+            nodes.sort_by(|a, b| a.1.total_cmp(&b.1));
+            nodes.truncate(beam.max(1));
+            nodes
+        };
+        let mut evaluations = 0;
+
+        let mut nodes = keep(self.evaluate(0, vector, 0..2, &mut evaluations));
+        for level in 1..=refine_level {
+            let candidates = self.under(&nodes, level - 1, level);
+            nodes = keep(self.evaluate(level, vector, candidates.into_iter(), &mut evaluations));
+        }
+        if refine_level < last {
+            let leaves = self.under(&nodes, refine_level, last);
+            nodes = self.evaluate(last, vector, leaves.into_iter(), &mut evaluations);
+        }
+
+        let best = nodes
+            .into_iter()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        (best.0, best.1, evaluations)
+    }
+
+    /// The nodes at level `to` in the subtrees of the given nodes at level `from`,
+    /// or the whole level if there are none.
+    fn under(&self, nodes: &[(usize, f64)], from: usize, to: usize) -> Vec<usize> {
+        let mut descendants: Vec<usize> = nodes.iter().map(|(j, _)| *j).collect();
+        for level in from..to {
+            descendants = descendants
+                .iter()
+                .flat_map(|&j| self.children[level][j].iter().copied())
+                .collect();
+        }
+        if descendants.is_empty() {
+            // kept nodes without descendants: consider the whole level
+            descendants = (0..self.levels[to].size()).collect();
+        }
+        descendants
+    }
+
+    fn evaluate(
+        &self,
+        level: usize,
+        vector: &[f64],
+        candidates: impl Iterator<Item = usize>,
+        evaluations: &mut usize,
+    ) -> Vec<(usize, f64)> {
+        let codebook = &self.levels[level];
+        candidates
+            .map(|i| {
+                *evaluations += 1;
+                (i, codebook.distortion_to(vector, i))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vq::distortion;
+    use rand::rngs::StdRng;
+    use rand::{RngExt, SeedableRng};
+
+    fn random_reflection(rng: &mut StdRng, p: usize) -> Vec<f64> {
+        let mut reflection = vec![0.0];
+        reflection.extend((0..p).map(|_| rng.random_range(-0.8..0.8)));
+        reflection
+    }
+
+    #[test]
+    fn test_nearest() {
+        let p = 4;
+        let measure = distortion::by_name("lr").unwrap();
+        let mut rng = StdRng::seed_from_u64(3);
+        // each codeword split into two perturbed ones at the next level
+        let mut reflections = vec![random_reflection(&mut rng, p)];
+        let mut levels: Vec<Codebook> = Vec::new();
+        for l in 1..=5 {
+            let scale = 0.5f64.powi(l);
+            reflections = reflections
+                .iter()
+                .flat_map(|r| [r.clone(), r.clone()])
+                .map(|r| {
+                    let delta = random_reflection(&mut rng, p);
+                    r.iter()
+                        .zip(&delta)
+                        .map(|(k, d)| (k + scale * d).clamp(-0.95, 0.95))
+                        .collect()
+                })
+                .collect();
+            levels.push(Codebook::new(
+                "c".to_string(),
+                p,
+                reflections.clone(),
+                measure,
+            ));
+        }
+        let tsvq = Tsvq::new(levels);
+        let codebook = tsvq.levels.last().unwrap();
+
+        for _ in 0..50 {
+            let rxg = autocorrelation(p, &random_reflection(&mut rng, p));
+            let vector = measure.prepare_vector(p, &rxg);
+            let (_, brute_d) = codebook.nearest(&vector);
+
+            let (_, d1, evaluations1) = tsvq.nearest(&vector, 1, 1);
+            assert!(d1 >= brute_d);
+            assert!(evaluations1 < codebook.size());
+
+            let (_, d4, _) = tsvq.nearest(&vector, 4, 2);
+            assert!(d4 >= brute_d);
+
+            // exact: all nodes kept above the leaves, or refine over the whole tree
+            let (_, d16, _) = tsvq.nearest(&vector, 16, 1);
+            assert_eq!(d16, brute_d);
+            let (_, d_all, _) = tsvq.nearest(&vector, 2, 4);
+            assert_eq!(d_all, brute_d);
+        }
+    }
+}